@@ -1,251 +1,8746 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::{Deref, DerefMut, Range};
+use std::path::Path;
 use std::str::Chars;
+use std::str::FromStr;
 
-#[derive(Copy, Clone)]
-enum Escape {
-    Foreground(u8),
-    Background(u8),
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Color of a style, as written in the template.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Color {
+    /// Default color of the terminal.
+    #[default]
+    Default,
+    /// Color of the 256-color palette.
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Attribute {
+    Bold,
+    Dim,
+    Italic,
+    Underline,
+    Blink,
+    Reverse,
+    Hidden,
+    Strikethrough,
+}
+
+/// Set of text attributes, one bit per `Attribute` variant. With the `serde` feature it's
+/// serialized as the list of names, like `["bold", "italic"]`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Attributes(u8);
+
+/// Change of the style that a `ColorEncoder` writes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Escape {
+    Foreground(Color),
+    Background(Color),
+    AttributeOn(Attribute),
+    AttributeOff(Attribute),
     Reset,
 }
 
-#[derive(Copy, Clone)]
-struct Style {
-    fg: u8,
-    bg: u8,
-}
+/// Shell the output is for, with options of how escape codes are written.
+#[derive(Copy, Clone)]
+struct Output<'a> {
+    shell: Shell,
+    /// Writes colors and attributes in the codes of the shell or the tool.
+    encoder: &'a dyn ColorEncoder,
+    /// The output is HTML, text is escaped and sequences that don't style it are left out.
+    html: bool,
+    /// Text is passed to the shell as is, so that shell escapes in it are expanded.
+    shell_escapes: bool,
+    /// Escape codes are left out, and separators too unless they're replaced with ASCII.
+    plain: bool,
+    /// Powerline glyphs of separators are replaced with ASCII characters.
+    ascii: bool,
+    colors: ColorMode,
+    /// Caps at the terminal background are left out at the ends of sections pointing left, as the
+    /// prompt is on the right side of the terminal.
+    right: bool,
+    /// Mapping of the 16 basic colors to the 8 colors of `ColorMode::Basic8`.
+    color_table: [u8; 16],
+}
+
+/// Glyphs drawn when a section opens and closes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Separator {
+    open: Option<char>,
+    close: Option<char>,
+    /// Divider between adjacent sections with the same background.
+    thin: Option<char>,
+}
+
+/// Colors, attributes and separator of a section.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Style {
+    pub fg: Color,
+    pub bg: Color,
+    pub attrs: Attributes,
+    separator: Separator,
+    /// Overrides the direction of the whole prompt.
+    direction: Option<Direction>,
+}
+
+/// Style of a section along with options that affect its layout.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Section {
+    style: Style,
+    /// Number of spaces around the content.
+    padding: usize,
+    /// Maximum width of the content in columns, longer content is truncated.
+    max_width: Option<usize>,
+    /// Replaces the end of truncated content, `…` by default.
+    ellipsis: Option<String>,
+    /// Minimum width of the content in columns, shorter content is filled with spaces.
+    min_width: usize,
+    align: Align,
+    /// Colors the section's own text character by character.
+    coloring: Option<Coloring>,
+    /// Name that allows replacing the content when generating.
+    name: Option<String>,
+    /// Section produces no output if it has no text.
+    optional: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum Coloring {
+    Gradient(Rgb, Rgb),
+    Rainbow,
+}
+
+type Rgb = (u8, u8, u8);
+
+/// Coloring of the section that is being rendered, with the number of colored characters so far.
+#[derive(Copy, Clone)]
+struct ColoringState {
+    coloring: Coloring,
+    index: usize,
+    total: usize,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum Align {
+    #[default]
+    Left,
+    Right,
+    Center,
+}
+
+/// Declarations from the template header.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Definitions {
+    colors: HashMap<String, Color>,
+    styles: HashMap<String, Section>,
+    /// Style of the text outside of sections.
+    default: Option<Section>,
+    /// Glyphs at the edges of bubbles against the terminal background.
+    caps: Option<Separator>,
+    /// Syntax version from `{pragma:vN}`, templates without it are version 1.
+    version: Option<u32>,
+    /// Values given when generating, `$NAME` of `hash()` is looked up in them before the
+    /// environment. It's `None` when the template is only parsed.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    values: Option<HashMap<String, String>>,
+}
+
+/// Direction the caps point to, prompts on the right side of the terminal usually point left.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Direction {
+    #[default]
+    Right,
+    Left,
+}
+
+#[derive(Copy, Clone)]
+pub enum Shell {
+    None,
+    Zsh,
+    Bash,
+    /// The output is a command that prints the prompt, for the body of `fish_prompt`.
+    Fish,
+    /// The output is a double-quoted string, for the body of the `prompt` function.
+    PowerShell,
+    /// The output is an interpolated string, for the closure of `$env.PROMPT_COMMAND`.
+    Nu,
+    /// Tcsh can't compute fills or check the exit status in the prompt, they're resolved during
+    /// generation like without a shell.
+    Tcsh,
+    /// Ksh93 and mksh, non-printing sequences are delimited with the character declared at the
+    /// start of the prompt.
+    Ksh,
+    /// The output is a command that puts the prompt, for the function of `edit:prompt`. Fills and
+    /// the exit status are resolved during generation.
+    Elvish,
+    /// The output is a format string for `$PROMPT`, where braces are reserved for fields. Fills,
+    /// time and the exit status are resolved during generation.
+    Xonsh,
+    /// OSH and YSH from the Oils project, which follow Bash conventions for `PS1`.
+    Oils,
+    /// The output is a format string for tmux `status-left` and `status-right`.
+    Tmux,
+    /// The output is a GNU screen string for `hardstatus` and `caption`, colors are converted to
+    /// the closest basic ones.
+    Screen,
+    /// The output is a Vim script with `:highlight` commands for the styles and the `statusline`
+    /// that uses them.
+    Vim,
+    /// The output is a Lua prompt filter for Clink in Windows cmd. Fills and the exit status are
+    /// resolved during generation.
+    Clink,
+    /// The output is a starship `format` string with `[text](style)` groups for the styles. Raw
+    /// text is passed as is, so that it can use starship variables like `$directory`. Fills, time
+    /// and the exit status are resolved during generation.
+    Starship,
+    /// Non-printing sequences are delimited with the `\x01` and `\x02` markers of readline, for
+    /// prompts passed to readline directly, like in Python REPL. Fills, time and the exit status
+    /// are resolved during generation.
+    Readline,
+    /// A shell or tool with conventions defined by the dialect. Fills, time and the exit status are
+    /// resolved during generation.
+    Custom(&'static dyn ShellDialect),
+}
+
+/// Conventions of a shell or tool that `Shell` doesn't cover, for `Shell::Custom`.
+pub trait ShellDialect {
+    /// Marks the start of escape codes that don't move the cursor, like `\[` in Bash.
+    fn non_printing_start(&self) -> &str {
+        ""
+    }
+
+    /// Marks the end of escape codes that don't move the cursor, like `\]` in Bash.
+    fn non_printing_end(&self) -> &str {
+        ""
+    }
+
+    /// Escapes characters of the text that are special for the shell.
+    fn escape_text(&self, text: &str) -> String {
+        text.to_string()
+    }
+
+    /// Writes colors and attributes, in escape codes of the terminal if there's no encoder.
+    fn color_encoder(&self) -> Option<&dyn ColorEncoder> {
+        None
+    }
+
+    /// Name that `Shell` displays for the dialect. It's not parsed back, as `FromStr` only knows
+    /// the built-in shells.
+    fn name(&self) -> &str {
+        "custom"
+    }
+}
+
+/// Parsed part of a template. Escapes are resolved in the text, and includes and macros are
+/// expanded.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Node {
+    Text(String),
+    /// Text that is passed to the output exactly as written.
+    Raw(String),
+    /// Value of `{{name}}` given when generating, left out if there's none.
+    Placeholder(String),
+    /// Spaces that fill the rest of the terminal line.
+    Fill(char),
+    /// Section with its content.
+    Section(Section, Vec<Node>),
+    /// Hyperlink to the URL with the label.
+    Link(String, Vec<Node>),
+    /// Current time in the strftime format.
+    Time(String),
+    /// Nodes to render when the condition holds and when it doesn't.
+    Conditional(Condition, Vec<Node>, Vec<Node>),
+    /// Title of the terminal window, styles of sections in it are ignored.
+    Title(Vec<Node>),
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Condition {
+    /// Environment variable is set and is not empty, checked during generation.
+    Variable(String),
+    /// Last command has succeeded, checked by the shell.
+    Status,
+}
+
+/// Kind of the generated prompt.
+#[derive(Copy, Clone, PartialEq)]
+enum Prompt {
+    Main,
+    /// Prompt for continuation lines of multi-line commands.
+    Continuation,
+    /// Prompt on the right side of the terminal.
+    Right,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Block {
+    Template,
+    Section,
+    Conditional,
+    Else,
+    TernaryThen,
+    TernaryElse,
+}
+
+struct Parser<'a> {
+    /// Whole template, used to report positions.
+    source: &'a str,
+    chars: Chars<'a>,
+    definitions: &'a Definitions,
+    emoji: bool,
+    /// Number of blocks the parser is in, not counting the template itself.
+    depth: usize,
+    max_depth: usize,
+    /// Errors collected when the template is checked, parsing stops at the first one otherwise.
+    errors: Option<&'a mut Vec<Error>>,
+}
+
+/// Destination of the output, which takes it as soon as nothing later can change it.
+enum Sink<'a, 'n> {
+    Writer(&'a mut dyn fmt::Write),
+    Chunks(&'a mut Vec<Chunk<'n>>),
+}
+
+/// Output that is not written yet, with the spans of text that is printed on the line.
+#[derive(Default)]
+struct Buffer<'n> {
+    output: String,
+    /// Spans of text, with the text of the template they show as is.
+    texts: Vec<(Range<usize>, Option<&'n str>)>,
+    /// Text and escapes are not told apart, since the fills of the line were resolved into it.
+    mixed: bool,
+}
+
+struct Renderer<'a, 'n> {
+    sink: Sink<'a, 'n>,
+    /// Result of writing into the sink, the rest of the output is dropped after an error.
+    written: fmt::Result,
+    /// Output is written before the prompt is complete, the shell doesn't wrap it as a whole.
+    streaming: bool,
+    /// Number of branches that are rendered separately from the output.
+    captures: usize,
+    buffer: Buffer<'n>,
+    /// Text is pushed into an escape code, like the title, and is not printed on the line.
+    in_escape: bool,
+    output: Output<'a>,
+    /// Content of placeholders and named sections, filled in as they're rendered.
+    content: &'n HashMap<&'n str, String>,
+    styles: Vec<Style>,
+    /// Brace of a transition that is not emitted yet, with the style active before it.
+    pending: Option<(char, Option<Style>)>,
+    /// Columns left for text in the truncated section.
+    budget: Option<usize>,
+    /// Number of columns taken by the output so far, without the raw text.
+    columns: usize,
+    /// Characters of the fills on the current line.
+    fills: Vec<char>,
+    coloring: Option<ColoringState>,
+    /// Foreground was changed by per-character coloring and doesn't match the active style.
+    stale: bool,
+    /// Time is formatted during generation instead of by the shell.
+    static_time: bool,
+    caps: Option<Separator>,
+    direction: Direction,
+    /// Style of the section that was closed right before the pending opening.
+    closed: Option<Style>,
+    /// The output checks the exit status of the last command.
+    status: bool,
+    /// Branches of a status conditional are being rendered.
+    in_branch: bool,
+    /// Control characters are written as `$([char]27)` in PowerShell output.
+    legacy_escapes: bool,
+    /// The prompt is wrapped into OSC 133 marks.
+    semantic_marks: bool,
+    /// The prompt reports the working directory with OSC 7.
+    report_cwd: bool,
+}
+
+/// Reasons a template can't be turned into a prompt. The messages match the ones printed by the
+/// command line tool.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// A `{` or `}` has no counterpart.
+    UnbalancedBraces,
+    /// A section has fewer than the two `fg,bg` fields.
+    MissingMeta,
+    /// The fg or bg of a section isn't a valid color.
+    InvalidColor {
+        field: &'static str,
+        reason: String,
+    },
+    /// A `@name=color` variable isn't a valid color.
+    InvalidVariable {
+        name: String,
+        reason: String,
+    },
+    /// A numeric section option can't be parsed.
+    InvalidNumber {
+        option: String,
+        reason: String,
+    },
+    InvalidAlign(String),
+    InvalidDirection(String),
+    InvalidSeparator(String),
+    InvalidHr,
+    InvalidConditional,
+    /// A conditional with `?` has no `:` before the else branch.
+    MissingColon,
+    UnknownAttribute(String),
+    UnknownOption(String),
+    UnknownStyle(String),
+    UnknownIcon(String),
+    UnknownPartial(String),
+    UnknownMacro(String),
+    UnknownShell(String),
+    UnterminatedComment,
+    UnterminatedConditional,
+    UnterminatedRaw,
+    UnterminatedMacro(String),
+    /// A closing block like `{/}` or `{|}` without an opening one.
+    Unexpected(String),
+    RecursiveInclude(String),
+    RecursiveMacro(String),
+    NestingTooDeep {
+        limit: usize,
+        position: usize,
+    },
+    IncludesTooDeep(usize),
+    MalformedHexEscape(usize),
+    MalformedUnicodeEscape(usize),
+    UnsupportedVersion(String),
+    /// A right prompt has a line break or a fill.
+    MultilineRightPrompt,
+    /// The writer given to `Generator::generate_to` failed.
+    Write,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UnbalancedBraces => write!(f, "Error: unbalanced braces."),
+            ErrorKind::MissingMeta => write!(f, "Error: invalid style, should be 'fg,bg'."),
+            ErrorKind::InvalidColor { field, reason } => {
+                write!(f, "Error: invalid {}, {}.", field, reason)
+            }
+            ErrorKind::InvalidVariable { name, reason } => {
+                write!(f, "Error: invalid variable '@{}', {}.", name, reason)
+            }
+            ErrorKind::InvalidNumber { option, reason } => {
+                write!(f, "Error: invalid {}, {}.", option, reason)
+            }
+            ErrorKind::InvalidAlign(value) => write!(
+                f,
+                "Error: invalid align '{}', should be 'left', 'right' or 'center'.",
+                value
+            ),
+            ErrorKind::InvalidDirection(value) => write!(
+                f,
+                "Error: invalid dir '{}', should be 'right' or 'left'.",
+                value
+            ),
+            ErrorKind::InvalidSeparator(value) => write!(
+                f,
+                "Error: invalid separator '{}', should be a name or two or three glyphs.",
+                value
+            ),
+            ErrorKind::InvalidHr => write!(f, "Error: invalid hr, should be a single character."),
+            ErrorKind::InvalidConditional => write!(
+                f,
+                "Error: invalid conditional, should be '{{?NAME}}' or '{{?NAME ? then : else}}'."
+            ),
+            ErrorKind::MissingColon => write!(f, "Error: missing ':' in conditional."),
+            ErrorKind::UnknownAttribute(name) => write!(f, "Error: unknown attribute '{}'.", name),
+            ErrorKind::UnknownOption(name) => write!(f, "Error: unknown option '{}'.", name),
+            ErrorKind::UnknownStyle(name) => write!(f, "Error: unknown style '%{}'.", name),
+            ErrorKind::UnknownIcon(name) => write!(f, "Error: unknown icon '{}'.", name),
+            ErrorKind::UnknownPartial(name) => write!(f, "Error: unknown partial '{}'.", name),
+            ErrorKind::UnknownMacro(name) => write!(f, "Error: unknown macro '{}'.", name),
+            ErrorKind::UnknownShell(name) => write!(f, "Error: unknown shell '{}'.", name),
+            ErrorKind::UnterminatedComment => write!(f, "Error: unterminated comment."),
+            ErrorKind::UnterminatedConditional => write!(f, "Error: unterminated conditional."),
+            ErrorKind::UnterminatedRaw => write!(f, "Error: unterminated raw text."),
+            ErrorKind::UnterminatedMacro(name) => {
+                write!(f, "Error: unterminated macro '{}'.", name)
+            }
+            ErrorKind::Unexpected(block) => write!(f, "Error: unexpected {}.", block),
+            ErrorKind::RecursiveInclude(name) => {
+                write!(f, "Error: recursive include of '{}'.", name)
+            }
+            ErrorKind::RecursiveMacro(name) => write!(f, "Error: recursive use of '{}'.", name),
+            ErrorKind::NestingTooDeep { limit, position } => {
+                write!(
+                    f,
+                    "Error: nesting is deeper than {} at {}.",
+                    limit, position
+                )
+            }
+            ErrorKind::IncludesTooDeep(limit) => {
+                write!(f, "Error: includes are nested deeper than {}.", limit)
+            }
+            ErrorKind::MalformedHexEscape(position) => {
+                write!(f, "Error: malformed hex escape at {}.", position)
+            }
+            ErrorKind::MalformedUnicodeEscape(position) => {
+                write!(f, "Error: malformed unicode escape at {}.", position)
+            }
+            ErrorKind::UnsupportedVersion(value) => {
+                write!(f, "Error: unsupported template version '{}'.", value)
+            }
+            ErrorKind::MultilineRightPrompt => {
+                write!(f, "Error: right prompt can't have line breaks or fills.")
+            }
+            ErrorKind::Write => write!(f, "Error: failed to write the prompt."),
+        }
+    }
+}
+
+/// Error with the kind of the failure and, when known, where it is in the template.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+    span: Option<Span>,
+}
+
+/// Location of an error in the template, as byte offsets of the start and the end, and the line
+/// and column of the start, both counted from 1 and the column in characters.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Location in the template. Errors in templates with includes or macros don't have one, as
+    /// they're found in the expanded template.
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    /// Renders the error like rustc, with the line of the template and carets under the span.
+    /// Errors without a span are rendered as just the message.
+    pub fn diagnostic(&self, template: &str) -> String {
+        let span = match self.span {
+            Some(span) if template.get(span.start..span.end).is_some() => span,
+            _ => return self.to_string(),
+        };
+
+        let line_start = template[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = template[span.start..]
+            .find('\n')
+            .map_or(template.len(), |i| span.start + i);
+
+        let number = span.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let indent = template[line_start..span.start].width();
+        let carets = template[span.start..span.end.min(line_end)].width().max(1);
+
+        format!(
+            "{}\n{}--> {}:{}\n{} |\n{} | {}\n{} | {}{}",
+            self,
+            gutter,
+            span.line,
+            span.column,
+            gutter,
+            number,
+            &template[line_start..line_end],
+            gutter,
+            " ".repeat(indent),
+            "^".repeat(carets)
+        )
+    }
+
+    /// Sets the span unless the error already has a more precise one.
+    fn at(self, span: Span) -> Error {
+        Error {
+            span: self.span.or(Some(span)),
+            ..self
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error { kind, span: None }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Span {
+    fn new(source: &str, start: usize, end: usize) -> Span {
+        let before = &source[..start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+        Span {
+            start,
+            end,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    /// Span of `part`, which is a slice of `source`.
+    fn of(source: &str, part: &str) -> Span {
+        let start = part.as_ptr() as usize - source.as_ptr() as usize;
+        Span::new(source, start, start + part.len())
+    }
+}
+
+/// Parsed template, see `Generator::parse`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Ast {
+    /// Nodes of the template body, without the default style around them.
+    pub nodes: Vec<Node>,
+    definitions: Definitions,
+}
+
+impl Ast {
+    /// Template of the nodes without header declarations.
+    pub fn new(nodes: Vec<Node>) -> Ast {
+        Ast {
+            nodes,
+            definitions: Definitions::default(),
+        }
+    }
+
+    /// Syntax version declared with `{pragma:vN}`, or 1.
+    pub fn version(&self) -> u32 {
+        self.definitions.version.unwrap_or(1)
+    }
+
+    /// Section declared with `{default:...}` for the text outside of sections.
+    pub fn default_section(&self) -> Option<&Section> {
+        self.definitions.default.as_ref()
+    }
+
+    /// Calls the function for each node, parents before their children and branches of
+    /// conditionals in order.
+    pub fn walk(&self, mut f: impl FnMut(&Node)) {
+        walk_nodes(&self.nodes, &mut f);
+    }
+
+    /// Calls the function for each node that can be changed in place, parents before children.
+    pub fn walk_mut(&mut self, mut f: impl FnMut(&mut Node)) {
+        walk_nodes_mut(&mut self.nodes, &mut f);
+    }
+
+    /// Changes styles of all sections and the default one, like to shift the colors of a theme
+    /// or to strip attributes before rendering.
+    pub fn map_styles(&mut self, mut f: impl FnMut(&mut Style)) {
+        if let Some(default) = &mut self.definitions.default {
+            f(&mut default.style);
+        }
+
+        self.walk_mut(|node| {
+            if let Node::Section(section, _) = node {
+                f(&mut section.style);
+            }
+        });
+    }
+}
+
+/// Template parsed once with `Generator::compile`, for rendering it on each prompt draw.
+#[derive(Clone)]
+pub struct Template {
+    generator: Generator,
+    ast: Ast,
+    /// Expanded template, kept when colors of `hash($NAME)` depend on the values of each render.
+    source: Option<String>,
+}
+
+impl Template {
+    /// Renders the prompt, with the content of named sections and placeholders replaced like
+    /// `Generator::generate_with` does. Templates with `hash($NAME)` colors are parsed again, and
+    /// fail if the variable is neither in the content nor in the environment.
+    pub fn render(
+        &self,
+        options: impl Into<Options>,
+        content: &HashMap<&str, String>,
+    ) -> Result<String, Error> {
+        let options = options.into();
+
+        let source = match &self.source {
+            Some(source) => source,
+            None => {
+                return Ok(self.generator.render_main(
+                    &self.ast.definitions,
+                    &self.ast.nodes,
+                    options,
+                    content,
+                ))
+            }
+        };
+
+        let mut output = String::new();
+        self.generator.generate_expanded(
+            source,
+            options,
+            content,
+            Prompt::Main,
+            None,
+            &mut output,
+        )?;
+
+        Ok(output)
+    }
+
+    /// Renders the prompt like `render`, into chunks of text and escapes that the renderer splits
+    /// while it writes the output, for callers that write or measure them separately. Text that
+    /// needs no escaping is borrowed from the template and the content, unless the template has
+    /// `hash($NAME)` colors and is parsed again.
+    pub fn chunks<'a>(
+        &'a self,
+        options: impl Into<Options>,
+        content: &'a HashMap<&'a str, String>,
+    ) -> Result<Chunks<'a>, Error> {
+        let options = options.into();
+
+        let chunks = match &self.source {
+            Some(source) => {
+                let (definitions, nodes) =
+                    self.generator.parse_template(source, Some(content), None)?;
+
+                self.generator
+                    .render_chunks(&definitions, &nodes, options, content)
+                    .into_iter()
+                    .map(Chunk::into_owned)
+                    .collect()
+            }
+            None => self.generator.render_chunks(
+                &self.ast.definitions,
+                &self.ast.nodes,
+                options,
+                content,
+            ),
+        };
+
+        Ok(Chunks {
+            chunks: chunks.into_iter(),
+        })
+    }
+
+    /// Parsed template that is rendered.
+    pub fn ast(&self) -> &Ast {
+        &self.ast
+    }
+}
+
+/// Prompt built from styled segments in code instead of a template, for content that comes from
+/// another program. Segments are sections like `{fg,bg:text}`, their text is taken as is.
+#[derive(Clone, Debug, Default)]
+pub struct PromptBuilder {
+    nodes: Vec<Node>,
+}
+
+impl PromptBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a section with the style and the text.
+    pub fn segment(&mut self, style: Style, text: &str) -> &mut Self {
+        let children = vec![Node::Text(text.to_string())];
+        self.nodes
+            .push(Node::Section(Section::new(style), children));
+        self
+    }
+
+    /// Appends text outside of sections.
+    pub fn text(&mut self, text: &str) -> &mut Self {
+        self.nodes.push(Node::Text(text.to_string()));
+        self
+    }
+
+    /// Template of the segments, for rendering them with the settings of a `Generator`.
+    pub fn ast(&self) -> Ast {
+        Ast::new(self.nodes.clone())
+    }
+
+    /// Renders the prompt for the shell, or with `Options` that set the color mode too.
+    pub fn render(&self, options: impl Into<Options>) -> String {
+        let definitions = Definitions::default();
+        Generator::new().render_main(&definitions, &self.nodes, options.into(), &HashMap::new())
+    }
+}
+
+fn walk_nodes(nodes: &[Node], f: &mut dyn FnMut(&Node)) {
+    for node in nodes {
+        f(node);
+
+        match node {
+            Node::Section(_, children) | Node::Link(_, children) | Node::Title(children) => {
+                walk_nodes(children, f)
+            }
+            Node::Conditional(_, then, otherwise) => {
+                walk_nodes(then, f);
+                walk_nodes(otherwise, f);
+            }
+            // The rest have no children
+            _ => (),
+        }
+    }
+}
+
+fn walk_nodes_mut(nodes: &mut [Node], f: &mut dyn FnMut(&mut Node)) {
+    for node in nodes {
+        f(node);
+
+        match node {
+            Node::Section(_, children) | Node::Link(_, children) | Node::Title(children) => {
+                walk_nodes_mut(children, f)
+            }
+            Node::Conditional(_, then, otherwise) => {
+                walk_nodes_mut(then, f);
+                walk_nodes_mut(otherwise, f);
+            }
+            // The rest have no children
+            _ => (),
+        }
+    }
+}
+
+/// Token of a template, see `tokens`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Token<'a> {
+    /// Declarations at the start of the template, like `@fg=33` and `{default:7,0}`.
+    Header(&'a str),
+    /// Opening of a section with its meta, like `33,236` of `{33,236:`.
+    OpenSection(&'a str),
+    CloseSection,
+    /// Opening of another block with content as written, like `{title:`, `{link:URL|` or
+    /// `{?NAME ?`.
+    OpenBlock(&'a str),
+    CloseBlock,
+    /// Part in braces without content as written, like `{fill}`, `{?NAME}`, `{/}` or `{>name}`.
+    Tag(&'a str),
+    /// Escape sequence like `\{` or `\u{E0B0}`.
+    Escape(&'a str),
+    Comment(&'a str),
+    Text(&'a str),
+}
+
+/// Iterator over tokens of a template with their spans, see `tokens`.
+pub struct Tokens<'a> {
+    source: &'a str,
+    rest: &'a str,
+    /// Line and column where the rest starts.
+    line: usize,
+    column: usize,
+    /// Length of the header that is not taken yet.
+    header: Option<usize>,
+    /// Blocks that are open, true for sections.
+    blocks: Vec<bool>,
+}
+
+/// Part of a prompt rendered by `Template::chunks`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Chunk<'a> {
+    /// Text that is printed on the line, escaped for the shell. Text of the template and the
+    /// content is borrowed if it needs no escaping.
+    Text(Cow<'a, str>),
+    /// Escape codes with the markers of the shell around them, and code that the shell expands
+    /// itself, like raw text, times and status conditionals.
+    Escape(Cow<'a, str>),
+    /// Text and escapes together: a line with fills from the first fill on, which are resolved at
+    /// the end of the line, and the prompt of shells that quote or convert it as a whole, like Fish
+    /// and Vim.
+    Mixed(Cow<'a, str>),
+}
+
+impl Chunk<'_> {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Chunk::Text(text) | Chunk::Escape(text) | Chunk::Mixed(text) => text,
+        }
+    }
+
+    /// Copies borrowed text, so that the chunk outlives the template.
+    pub fn into_owned(self) -> Chunk<'static> {
+        match self {
+            Chunk::Text(text) => Chunk::Text(Cow::Owned(text.into_owned())),
+            Chunk::Escape(text) => Chunk::Escape(Cow::Owned(text.into_owned())),
+            Chunk::Mixed(text) => Chunk::Mixed(Cow::Owned(text.into_owned())),
+        }
+    }
+}
+
+/// Iterator over chunks of a prompt, see `Template::chunks`.
+pub struct Chunks<'a> {
+    chunks: std::vec::IntoIter<Chunk<'a>>,
+}
+
+/// Statistics of a valid template.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TemplateInfo {
+    /// Syntax version declared with `{pragma:vN}`, or 1.
+    pub version: u32,
+    /// Number of sections, nested ones included.
+    pub sections: usize,
+    /// Colors of sections in the order they're first used, palette indices as numbers like `33`
+    /// and RGB colors as `#rrggbb`.
+    pub colors: Vec<String>,
+    /// How deep sections are nested, 1 for sections at the top level.
+    pub depth: usize,
+}
+
+impl TemplateInfo {
+    fn add_nodes(&mut self, nodes: &[Node], depth: usize) {
+        for node in nodes {
+            match node {
+                Node::Section(section, children) => {
+                    self.sections += 1;
+                    self.depth = self.depth.max(depth + 1);
+                    self.add_colors(&section.style);
+                    self.add_nodes(children, depth + 1);
+                }
+                Node::Link(_, children) | Node::Title(children) => self.add_nodes(children, depth),
+                Node::Conditional(_, then, otherwise) => {
+                    self.add_nodes(then, depth);
+                    self.add_nodes(otherwise, depth);
+                }
+                Node::Text(_)
+                | Node::Raw(_)
+                | Node::Fill(_)
+                | Node::Time(_)
+                | Node::Placeholder(_) => (),
+            }
+        }
+    }
+
+    fn add_colors(&mut self, style: &Style) {
+        for color in [style.fg, style.bg]
+            .iter()
+            .filter_map(|color| color.to_template())
+        {
+            if !self.colors.contains(&color) {
+                self.colors.push(color);
+            }
+        }
+    }
+}
+
+/// Prompt generator with a registry of partial templates that can be included with `{>name}`.
+/// Settings that don't change between prompts are set on the generator, the shell and the color
+/// mode of each prompt are given with `Options`, along with caps and escaping that override the
+/// generator's.
+#[derive(Clone, Default)]
+pub struct Generator {
+    partials: HashMap<String, String>,
+    emoji: bool,
+    static_time: bool,
+    direction: Direction,
+    max_depth: Option<usize>,
+    escaping: Escaping,
+    plain: bool,
+    ascii: bool,
+    color_table: Option<[u8; 16]>,
+    semantic_marks: bool,
+    report_cwd: bool,
+    caps: Option<Separator>,
+    /// Prompts are rendered as HTML, for `render_html`.
+    html: bool,
+}
+
+/// Colors supported by the terminal. Colors are converted to the closest ones of the mode,
+/// however they're written in the template.
+#[derive(Copy, Clone, Default, PartialEq)]
+pub enum ColorMode {
+    /// Colors are written as in the template, RGB colors with `38;2` codes.
+    #[default]
+    TrueColor,
+    /// RGB colors are converted to the 256-color palette.
+    Palette256,
+    /// Colors are converted to the 16 basic ones, written with `3x`, `4x`, `9x` and `10x` codes.
+    Basic16,
+    /// Colors are converted to the basic ones and mapped to 8 colors with the color table, written
+    /// with `3x` and `4x` codes. Bold is the only attribute that's kept.
+    Basic8,
+    /// Colors are left out, attributes are kept.
+    None,
+    /// The mode is detected from `$COLORTERM`, `$TERM` and its terminfo entry when the prompt is
+    /// generated, see `ColorMode::detect`.
+    Auto,
+}
+
+/// How text and escape codes are written for the shell. Everything is off by default, so that
+/// text is escaped and escape codes are written raw.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Escaping {
+    /// Text is passed to Bash, Oils and Zsh as is, see `Generator::shell_escapes`.
+    pub shell_escapes: bool,
+    /// Control characters are written for PowerShell 5, see `Generator::legacy_escapes`.
+    pub legacy_escapes: bool,
+    /// Zsh colors are written with its own escapes, see `Generator::native_colors`.
+    pub native_colors: bool,
+}
+
+/// Options of a single generation, see `Generator::generate`. The caps and the escaping override
+/// the ones set on the generator.
+#[derive(Copy, Clone)]
+pub struct Options {
+    pub shell: Shell,
+    pub color_mode: ColorMode,
+    /// Colors are left out if `$NO_COLOR` is set, unless `$CLICOLOR_FORCE` is set to something
+    /// else than `0`, which keeps them even if the terminal doesn't support them.
+    pub color_env: bool,
+    pub escaping: Option<Escaping>,
+    caps: Option<Separator>,
+}
+
+impl Options {
+    pub fn new(shell: Shell) -> Self {
+        Self {
+            shell,
+            color_mode: ColorMode::default(),
+            color_env: false,
+            escaping: None,
+            caps: None,
+        }
+    }
+
+    pub fn escaping(mut self, escaping: Escaping) -> Self {
+        self.escaping = Some(escaping);
+        self
+    }
+
+    /// Sets the caps of templates that don't declare them, like `Generator::caps`.
+    pub fn caps(mut self, glyphs: &str) -> Result<Self, Error> {
+        self.caps = Some(parse_separator(glyphs.trim())?);
+        Ok(self)
+    }
+
+    pub fn color_mode(mut self, color_mode: ColorMode) -> Self {
+        self.color_mode = color_mode;
+        self
+    }
+
+    /// Honors the `NO_COLOR` and `CLICOLOR_FORCE` conventions, see `color_env`.
+    pub fn color_env(mut self, enabled: bool) -> Self {
+        self.color_env = enabled;
+        self
+    }
+
+    /// Color mode to render with, detected and adjusted by the environment.
+    fn resolve_color_mode(&self) -> ColorMode {
+        let color_mode = match self.color_mode {
+            ColorMode::Auto => ColorMode::detect(),
+            color_mode => color_mode,
+        };
+
+        if !self.color_env {
+            return color_mode;
+        }
+
+        let force = env::var("CLICOLOR_FORCE").is_ok_and(|value| !value.is_empty() && value != "0");
+        env_color_mode(color_mode, is_set("NO_COLOR"), force)
+    }
+}
+
+fn env_color_mode(color_mode: ColorMode, no_color: bool, force: bool) -> ColorMode {
+    match color_mode {
+        ColorMode::None if force => ColorMode::Basic16,
+        _ if force => color_mode,
+        _ if no_color => ColorMode::None,
+        _ => color_mode,
+    }
+}
+
+impl From<Shell> for Options {
+    fn from(shell: Shell) -> Self {
+        Self::new(shell)
+    }
+}
+
+impl Default for Separator {
+    fn default() -> Self {
+        SEPARATORS[0].1
+    }
+}
+
+impl Color {
+    fn is_default(self) -> bool {
+        matches!(self, Color::Default)
+    }
+
+    /// Converts the color to RGB using the standard xterm palette for indexed colors.
+    fn to_rgb(self) -> Option<Rgb> {
+        match self {
+            Color::Default => None,
+            Color::Indexed(index @ 0..=15) => Some(BASIC_COLORS[index as usize]),
+            Color::Indexed(index @ 16..=231) => {
+                let index = index - 16;
+                let level = |i: u8| CUBE_LEVELS[i as usize];
+
+                Some((level(index / 36), level(index / 6 % 6), level(index % 6)))
+            }
+            Color::Indexed(index) => {
+                let gray = 8 + 10 * (index - 232);
+                Some((gray, gray, gray))
+            }
+            Color::Rgb(r, g, b) => Some((r, g, b)),
+        }
+    }
+
+    /// Color as it's written in templates, the default color has no value.
+    fn to_template(self) -> Option<String> {
+        match self {
+            Color::Default => None,
+            Color::Indexed(index) => Some(index.to_string()),
+            Color::Rgb(r, g, b) => Some(format!("#{:02x}{:02x}{:02x}", r, g, b)),
+        }
+    }
+
+    fn to_hex(self) -> Option<String> {
+        self.to_rgb()
+            .map(|(r, g, b)| format!("#{:02x}{:02x}{:02x}", r, g, b))
+    }
+
+    /// Index of the closest basic color, basic colors are returned as is.
+    fn to_basic(self) -> Option<u8> {
+        if let Color::Indexed(index @ 0..=15) = self {
+            return Some(index);
+        }
+
+        let rgb = self.to_rgb()?;
+        (0..16u8).min_by_key(|i| rgb_distance(rgb, BASIC_COLORS[*i as usize]))
+    }
+
+    /// Index of the closest color of the 256-color palette, indexed colors are returned as is.
+    /// Basic colors are skipped, since terminals often change them.
+    fn to_indexed(self) -> Option<u8> {
+        if let Color::Indexed(index) = self {
+            return Some(index);
+        }
+
+        let rgb = self.to_rgb()?;
+        (16..=255u8).min_by_key(|i| rgb_distance(rgb, Color::Indexed(*i).to_rgb().unwrap()))
+    }
+}
+
+impl Shell {
+    fn is_status_line(self) -> bool {
+        matches!(self, Shell::Tmux | Shell::Screen | Shell::Vim)
+    }
+
+    /// Detects the shell that runs the program, from the name of the parent process where it's
+    /// available and then from `$SHELL`. On Windows it falls back to PowerShell, elsewhere to no
+    /// shell.
+    pub fn detect() -> Self {
+        let shell = env::var("SHELL").ok();
+        let shell = shell
+            .as_deref()
+            .map(|shell| shell.rsplit('/').next().unwrap_or(shell));
+
+        parent_process_name()
+            .and_then(|name| shell_by_name(&name))
+            .or_else(|| shell.and_then(shell_by_name))
+            .unwrap_or(if cfg!(windows) {
+                Shell::PowerShell
+            } else {
+                Shell::None
+            })
+    }
+}
+
+/// Parses the shell by its name as in `Display` or by the name of its executable, ignoring case.
+/// `none` and `any` give `Shell::None`. Names of custom dialects are unknown shells, there's no
+/// dialect to parse them into.
+impl FromStr for Shell {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_ref() {
+            "none" | "any" => Ok(Shell::None),
+            "oils" => Ok(Shell::Oils),
+            "tmux" => Ok(Shell::Tmux),
+            "screen" => Ok(Shell::Screen),
+            "vim" | "nvim" => Ok(Shell::Vim),
+            "clink" => Ok(Shell::Clink),
+            "starship" => Ok(Shell::Starship),
+            "readline" => Ok(Shell::Readline),
+            lowercase => shell_by_name(lowercase)
+                .ok_or_else(|| ErrorKind::UnknownShell(name.to_string()).into()),
+        }
+    }
+}
+
+/// Writes the name that `FromStr` parses, or the name of the dialect of `Shell::Custom`.
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Shell::None => "none",
+            Shell::Zsh => "zsh",
+            Shell::Bash => "bash",
+            Shell::Fish => "fish",
+            Shell::PowerShell => "powershell",
+            Shell::Nu => "nu",
+            Shell::Tcsh => "tcsh",
+            Shell::Ksh => "ksh",
+            Shell::Elvish => "elvish",
+            Shell::Xonsh => "xonsh",
+            Shell::Oils => "oils",
+            Shell::Tmux => "tmux",
+            Shell::Screen => "screen",
+            Shell::Vim => "vim",
+            Shell::Clink => "clink",
+            Shell::Starship => "starship",
+            Shell::Readline => "readline",
+            Shell::Custom(dialect) => dialect.name(),
+        })
+    }
+}
+
+/// Name of the parent process, from procfs on Linux.
+#[cfg(target_os = "linux")]
+fn parent_process_name() -> Option<String> {
+    let path = format!("/proc/{}/comm", std::os::unix::process::parent_id());
+    fs::read_to_string(path)
+        .ok()
+        .map(|name| name.trim_end().to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn parent_process_name() -> Option<String> {
+    None
+}
+
+/// Shell by the name of its executable. Login shells are named with a leading dash.
+fn shell_by_name(name: &str) -> Option<Shell> {
+    let name = name.trim_start_matches('-');
+    let name = name.strip_suffix(".exe").unwrap_or(name);
+
+    match name {
+        "zsh" => Some(Shell::Zsh),
+        "bash" => Some(Shell::Bash),
+        "fish" => Some(Shell::Fish),
+        "pwsh" | "powershell" => Some(Shell::PowerShell),
+        "nu" => Some(Shell::Nu),
+        "tcsh" | "csh" => Some(Shell::Tcsh),
+        "ksh" | "ksh93" | "mksh" => Some(Shell::Ksh),
+        "elvish" => Some(Shell::Elvish),
+        "xonsh" => Some(Shell::Xonsh),
+        "osh" | "ysh" | "oils-for-unix" => Some(Shell::Oils),
+        _ => None,
+    }
+}
+
+impl ColorMode {
+    /// Detects the colors supported by the terminal. `$COLORTERM` set to `truecolor` or `24bit`
+    /// gives true color, otherwise the number of colors is read from the terminfo entry of `$TERM`,
+    /// or guessed from the name of the terminal if there's no entry. An unset or `dumb` terminal
+    /// gives no colors.
+    pub fn detect() -> Self {
+        let term = env::var("TERM").ok();
+        let colors = term.as_deref().and_then(terminfo_colors);
+
+        detect_color_mode(
+            term.as_deref(),
+            env::var("COLORTERM").ok().as_deref(),
+            colors,
+        )
+    }
+}
+
+fn detect_color_mode(
+    term: Option<&str>,
+    colorterm: Option<&str>,
+    colors: Option<i32>,
+) -> ColorMode {
+    if let Some("truecolor") | Some("24bit") = colorterm {
+        return ColorMode::TrueColor;
+    }
+
+    let term = match term {
+        None | Some("") | Some("dumb") => return ColorMode::None,
+        Some(term) => term,
+    };
+
+    match colors {
+        Some(0x1000000..=i32::MAX) => ColorMode::TrueColor,
+        Some(256..=0xffffff) => ColorMode::Palette256,
+        Some(16..=255) => ColorMode::Basic16,
+        Some(8..=15) => ColorMode::Basic8,
+        Some(_) => ColorMode::None,
+        None if term.ends_with("-direct") => ColorMode::TrueColor,
+        None if term.contains("256color") => ColorMode::Palette256,
+        None => ColorMode::Basic16,
+    }
+}
+
+/// Reads the number of colors from the compiled terminfo entry of the terminal.
+fn terminfo_colors(term: &str) -> Option<i32> {
+    let first = term.chars().next()?;
+    let mut dirs: Vec<String> = env::var("TERMINFO").into_iter().collect();
+
+    if let Ok(home) = env::var("HOME") {
+        dirs.push(format!("{}/.terminfo", home));
+    }
+
+    let system = env::var("TERMINFO_DIRS").unwrap_or_default();
+    let system = system.split(':').filter(|dir| !dir.is_empty());
+    dirs.extend(system.map(str::to_string));
+    dirs.extend(
+        ["/etc/terminfo", "/lib/terminfo", "/usr/share/terminfo"]
+            .iter()
+            .map(|dir| dir.to_string()),
+    );
+
+    // Entries are in directories named by the first character, or its hex code on macOS.
+    let mut paths = dirs.iter().flat_map(|dir| {
+        vec![
+            format!("{}/{}/{}", dir, first, term),
+            format!("{}/{:x}/{}", dir, first as u32, term),
+        ]
+    });
+
+    let data = paths.find_map(|path| fs::read(path).ok())?;
+    parse_terminfo_colors(&data)
+}
+
+/// Parses the `colors` number capability of a compiled terminfo entry, in the legacy format with
+/// 16-bit numbers or the extended one with 32-bit numbers.
+fn parse_terminfo_colors(data: &[u8]) -> Option<i32> {
+    /// Index of `colors` among the number capabilities.
+    const COLORS: usize = 13;
+
+    let short = |offset: usize| -> Option<usize> {
+        let bytes = data.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+    };
+
+    let size = match short(0)? {
+        0o432 => 2,
+        0o1036 => 4,
+        _ => return None,
+    };
+
+    let (names, booleans, numbers) = (short(2)?, short(4)?, short(6)?);
+    if numbers <= COLORS {
+        return None;
+    }
+
+    let start = 12 + names + booleans;
+    let offset = start + start % 2 + COLORS * size;
+    let bytes = data.get(offset..offset + size)?;
+
+    let colors = match size {
+        2 => i32::from(i16::from_le_bytes([bytes[0], bytes[1]])),
+        _ => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    };
+
+    Some(colors).filter(|colors| *colors >= 0)
+}
+
+impl ColoringState {
+    /// Color of the next character.
+    fn next_color(&mut self) -> Color {
+        let index = self.index;
+        self.index += 1;
+
+        let (from, to) = match self.coloring {
+            Coloring::Gradient(from, to) => (from, to),
+            Coloring::Rainbow => return Color::Indexed(RAINBOW[index % RAINBOW.len()]),
+        };
+
+        let t = match self.total {
+            0 | 1 => 0.0,
+            total => index as f64 / (total - 1) as f64,
+        };
+
+        let (r, g, b) = blend(from, to, t);
+        Color::Rgb(r, g, b)
+    }
+}
+
+const OPEN_BRACE: char = '{';
+const CLOSE_BRACE: char = '}';
+const ESCAPE: char = '\\';
+const COMMENT: char = '#';
+const VARIABLE: char = '@';
+const NAMED_STYLE: char = '%';
+const INCLUDE: char = '>';
+const CONDITIONAL: char = '?';
+const RAW: char = '!';
+/// Quotes a meta value, so that commas and colons in it are taken literally.
+const QUOTE: char = '"';
+/// Size of a terminal cell in SVG images, in pixels.
+const SVG_CELL_WIDTH: usize = 9;
+const SVG_CELL_HEIGHT: usize = 18;
+const SVG_FONT_SIZE: usize = 15;
+/// Offset of the text baseline from the top of the cell.
+const SVG_BASELINE: usize = 14;
+/// Colors of the terminal in SVG images.
+const SVG_FOREGROUND: &str = "#d0d0d0";
+const SVG_BACKGROUND: &str = "#1c1c1c";
+/// Delimiter of non-printing sequences in Ksh prompts.
+const NON_PRINTING: char = '\x01';
+/// Default limit of nesting.
+const MAX_DEPTH: usize = 64;
+const ELLIPSIS: &str = "\u{2026}";
+/// Foreground that contrasts with the background, white or black unless other colors are given.
+const AUTO: &str = "auto";
+const AUTO_COLORS: (Color, Color) = (Color::Indexed(15), Color::Indexed(0));
+
+/// Colors that `hash()` chooses from, distinct enough to tell apart.
+const HASH_COLORS: [u8; 16] = [
+    33, 37, 39, 43, 69, 75, 106, 112, 128, 134, 136, 142, 166, 172, 197, 203,
+];
+const FILL: &str = "{fill}";
+const HR: &str = "{hr:";
+/// Stands for fills in the output until the width of the whole prompt is known.
+const FILL_MARKER: char = '\0';
+const ICON: &str = "{icon:";
+const TIME: &str = "{time:";
+const PLACEHOLDER: &str = "{{";
+const END_PLACEHOLDER: &str = "}}";
+const LINK: &str = "{link:";
+const TITLE: &str = "{title:";
+/// Separates the URL from the label in a link.
+const LINK_LABEL: char = '|';
+/// Starts a macro definition, the name should be followed by a space and the closing brace.
+const DEFINE: &str = "def ";
+const END_DEFINE: &str = "{/def}";
+const USE: &str = "use ";
+/// Declares the style of the text outside of sections, only in the header.
+const DEFAULT_STYLE: &str = "{default:";
+/// Declares glyphs at the edges of bubbles, only in the header.
+const CAPS: &str = "{caps:";
+/// Declares the syntax version, only at the very start of the template.
+const PRAGMA: &str = "{pragma:";
+/// Latest syntax version, it doesn't change the output yet.
+pub const LATEST_VERSION: u32 = 2;
+const ELSE: &str = "{|}";
+const END_CONDITIONAL: &str = "{/}";
+
+/// Nerd Font glyphs by name, sorted for the binary search.
+const ICONS: [(&str, char); 26] = [
+    ("apple", '\u{F179}'),
+    ("arch", '\u{F303}'),
+    ("branch", '\u{E0A0}'),
+    ("check", '\u{F00C}'),
+    ("clock", '\u{F017}'),
+    ("cross", '\u{F00D}'),
+    ("debian", '\u{F306}'),
+    ("docker", '\u{F308}'),
+    ("fedora", '\u{F30A}'),
+    ("folder", '\u{F07B}'),
+    ("folder_open", '\u{F07C}'),
+    ("git", '\u{E702}'),
+    ("github", '\u{F09B}'),
+    ("gitlab", '\u{F296}'),
+    ("home", '\u{F015}'),
+    ("linux", '\u{F17C}'),
+    ("lock", '\u{F023}'),
+    ("node", '\u{E718}'),
+    ("python", '\u{E73C}'),
+    ("rust", '\u{E7A8}'),
+    ("terminal", '\u{F120}'),
+    ("ubuntu", '\u{F31B}'),
+    ("user", '\u{F007}'),
+    ("warning", '\u{F071}'),
+    ("windows", '\u{F17A}'),
+    ("zap", '\u{F0E7}'),
+];
+
+/// Emoji by their shortcodes, sorted for the binary search.
+const EMOJI: [(&str, &str); 32] = [
+    ("+1", "👍"),
+    ("bug", "🐛"),
+    ("coffee", "☕"),
+    ("computer", "💻"),
+    ("construction", "🚧"),
+    ("crab", "🦀"),
+    ("file_folder", "📁"),
+    ("fire", "🔥"),
+    ("gear", "⚙️"),
+    ("ghost", "👻"),
+    ("hammer", "🔨"),
+    ("heart", "❤️"),
+    ("hourglass", "⌛"),
+    ("house", "🏠"),
+    ("key", "🔑"),
+    ("lock", "🔒"),
+    ("memo", "📝"),
+    ("open_file_folder", "📂"),
+    ("package", "📦"),
+    ("penguin", "🐧"),
+    ("rocket", "🚀"),
+    ("skull", "💀"),
+    ("snake", "🐍"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("tada", "🎉"),
+    ("warning", "⚠️"),
+    ("whale", "🐳"),
+    ("white_check_mark", "✅"),
+    ("wrench", "🔧"),
+    ("x", "❌"),
+    ("zap", "⚡"),
+];
+
+/// Semantic roles with their default styles, a style declared with the same name overrides them.
+const ROLES: [(&str, &str); 5] = [
+    ("error", "15,1"),
+    ("warning", "0,3"),
+    ("ok", "0,2"),
+    ("info", "15,4"),
+    ("muted", "250,238"),
+];
+
+/// Names of the 16 basic ANSI colors, indexed by their 256-color palette number.
+const COLOR_NAMES: [&str; 16] = [
+    "black",
+    "red",
+    "green",
+    "yellow",
+    "blue",
+    "magenta",
+    "cyan",
+    "white",
+    "bright-black",
+    "bright-red",
+    "bright-green",
+    "bright-yellow",
+    "bright-blue",
+    "bright-magenta",
+    "bright-cyan",
+    "bright-white",
+];
+
+const SEPARATORS: [(&str, Separator); 4] = [
+    (
+        "round",
+        Separator::new('\u{E0B6}', '\u{E0B4}').with_thin('\u{E0B5}'),
+    ),
+    (
+        "arrow",
+        Separator::new('\u{E0B2}', '\u{E0B0}').with_thin('\u{E0B1}'),
+    ),
+    (
+        "slant",
+        Separator::new('\u{E0BA}', '\u{E0BC}').with_thin('\u{E0BD}'),
+    ),
+    ("none", NO_SEPARATOR),
+];
+
+const NO_SEPARATOR: Separator = Separator {
+    open: None,
+    close: None,
+    thin: None,
+};
+
+/// Default mapping of the 16 basic colors to the 8 colors, bright colors become the normal ones.
+pub const EIGHT_COLOR_TABLE: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 0, 1, 2, 3, 4, 5, 6, 7];
+
+/// Standard xterm RGB values of the 16 basic colors.
+const BASIC_COLORS: [Rgb; 16] = [
+    (0, 0, 0),
+    (128, 0, 0),
+    (0, 128, 0),
+    (128, 128, 0),
+    (0, 0, 128),
+    (128, 0, 128),
+    (0, 128, 128),
+    (192, 192, 192),
+    (128, 128, 128),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (0, 0, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+/// Levels of the RGB components in the 6x6x6 color cube of the 256-color palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// Colors that the `rainbow` flag cycles through, from the 256-color palette.
+const RAINBOW: [u8; 7] = [196, 208, 226, 46, 21, 93, 201];
+
+const ATTRIBUTES: [(&str, Attribute); 8] = [
+    ("bold", Attribute::Bold),
+    ("dim", Attribute::Dim),
+    ("italic", Attribute::Italic),
+    ("underline", Attribute::Underline),
+    ("blink", Attribute::Blink),
+    ("reverse", Attribute::Reverse),
+    ("hidden", Attribute::Hidden),
+    ("strikethrough", Attribute::Strikethrough),
+];
+
+impl Attribute {
+    fn on_code(self) -> u8 {
+        match self {
+            Attribute::Bold => 1,
+            Attribute::Dim => 2,
+            Attribute::Italic => 3,
+            Attribute::Underline => 4,
+            Attribute::Blink => 5,
+            Attribute::Reverse => 7,
+            Attribute::Hidden => 8,
+            Attribute::Strikethrough => 9,
+        }
+    }
+
+    fn tmux_name(self) -> &'static str {
+        match self {
+            Attribute::Bold => "bold",
+            Attribute::Dim => "dim",
+            Attribute::Italic => "italics",
+            Attribute::Underline => "underscore",
+            Attribute::Blink => "blink",
+            Attribute::Reverse => "reverse",
+            Attribute::Hidden => "hidden",
+            Attribute::Strikethrough => "strikethrough",
+        }
+    }
+
+    /// Name of the attribute in Vim highlight groups, not all attributes are supported.
+    fn vim_name(self) -> Option<&'static str> {
+        match self {
+            Attribute::Bold => Some("bold"),
+            Attribute::Italic => Some("italic"),
+            Attribute::Underline => Some("underline"),
+            Attribute::Reverse => Some("reverse"),
+            Attribute::Strikethrough => Some("strikethrough"),
+            _ => None,
+        }
+    }
+
+    /// Name of the attribute in starship styles.
+    fn starship_name(self) -> &'static str {
+        match self {
+            Attribute::Bold => "bold",
+            Attribute::Dim => "dimmed",
+            Attribute::Italic => "italic",
+            Attribute::Underline => "underline",
+            Attribute::Blink => "blink",
+            Attribute::Reverse => "inverted",
+            Attribute::Hidden => "hidden",
+            Attribute::Strikethrough => "strikethrough",
+        }
+    }
+
+    /// CSS declaration of the attribute, except for decorations and reverse that are combined with
+    /// other styles.
+    fn css(self) -> Option<&'static str> {
+        match self {
+            Attribute::Bold => Some("font-weight:bold"),
+            Attribute::Dim => Some("opacity:0.5"),
+            Attribute::Italic => Some("font-style:italic"),
+            Attribute::Hidden => Some("visibility:hidden"),
+            _ => None,
+        }
+    }
+
+    /// Letter of the attribute in screen strings, not all attributes are supported.
+    fn screen_letter(self) -> Option<char> {
+        match self {
+            Attribute::Bold => Some('b'),
+            Attribute::Dim => Some('d'),
+            Attribute::Underline => Some('u'),
+            Attribute::Blink => Some('B'),
+            Attribute::Reverse => Some('r'),
+            _ => None,
+        }
+    }
+
+    fn off_code(self) -> u8 {
+        match self {
+            Attribute::Bold | Attribute::Dim => 22,
+            Attribute::Italic => 23,
+            Attribute::Underline => 24,
+            Attribute::Blink => 25,
+            Attribute::Reverse => 27,
+            Attribute::Hidden => 28,
+            Attribute::Strikethrough => 29,
+        }
+    }
+}
+
+impl Separator {
+    const fn new(open: char, close: char) -> Self {
+        Separator {
+            open: Some(open),
+            close: Some(close),
+            thin: None,
+        }
+    }
+
+    const fn with_thin(self, thin: char) -> Self {
+        Separator {
+            thin: Some(thin),
+            ..self
+        }
+    }
+}
+
+impl Style {
+    /// Style with the default colors of the terminal, no attributes and the default separator,
+    /// to be built like `Style::new().fg(33).bg(236).bold()`.
+    pub fn new() -> Style {
+        Style::default()
+    }
+
+    /// Sets the foreground, from a color, a palette index or an RGB triple.
+    pub fn fg(mut self, fg: impl Into<Color>) -> Style {
+        self.fg = fg.into();
+        self
+    }
+
+    /// Sets the background, from a color, a palette index or an RGB triple.
+    pub fn bg(mut self, bg: impl Into<Color>) -> Style {
+        self.bg = bg.into();
+        self
+    }
+
+    /// Adds the attribute to the ones that are already set.
+    pub fn attr(mut self, attr: Attribute) -> Style {
+        self.attrs.insert(attr);
+        self
+    }
+
+    /// Makes the text bold.
+    pub fn bold(self) -> Style {
+        self.attr(Attribute::Bold)
+    }
+
+    /// Makes the text dim.
+    pub fn dim(self) -> Style {
+        self.attr(Attribute::Dim)
+    }
+
+    /// Makes the text italic.
+    pub fn italic(self) -> Style {
+        self.attr(Attribute::Italic)
+    }
+
+    /// Underlines the text.
+    pub fn underline(self) -> Style {
+        self.attr(Attribute::Underline)
+    }
+
+    /// Makes the text blink.
+    pub fn blink(self) -> Style {
+        self.attr(Attribute::Blink)
+    }
+
+    /// Swaps the foreground and the background.
+    pub fn reverse(self) -> Style {
+        self.attr(Attribute::Reverse)
+    }
+
+    /// Hides the text, it still takes its columns.
+    pub fn hidden(self) -> Style {
+        self.attr(Attribute::Hidden)
+    }
+
+    /// Strikes the text through.
+    pub fn strikethrough(self) -> Style {
+        self.attr(Attribute::Strikethrough)
+    }
+}
+
+impl From<u8> for Color {
+    fn from(index: u8) -> Color {
+        Color::Indexed(index)
+    }
+}
+
+impl From<(u8, u8, u8)> for Color {
+    fn from((r, g, b): (u8, u8, u8)) -> Color {
+        Color::Rgb(r, g, b)
+    }
+}
+
+impl Section {
+    /// Section with the style and the default layout, like the one of `{fg,bg:...}`.
+    pub fn new(style: Style) -> Section {
+        Section {
+            style,
+            ..Section::default()
+        }
+    }
+
+    pub fn style(&self) -> &Style {
+        &self.style
+    }
+
+    pub fn style_mut(&mut self) -> &mut Style {
+        &mut self.style
+    }
+
+    /// Name given with `@name`, for replacing the content when generating.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Attributes {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let attrs = ATTRIBUTES.iter().map(|(_, attr)| *attr);
+        serializer.collect_seq(attrs.filter(|attr| self.contains(*attr)))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Attributes {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut attrs = Attributes::default();
+
+        for attr in <Vec<Attribute> as serde::Deserialize>::deserialize(deserializer)? {
+            attrs.insert(attr);
+        }
+
+        Ok(attrs)
+    }
+}
+
+impl Attributes {
+    pub fn contains(self, attr: Attribute) -> bool {
+        self.0 & (1 << attr as u8) != 0
+    }
+
+    pub fn insert(&mut self, attr: Attribute) {
+        self.0 |= 1 << attr as u8;
+    }
+
+    pub fn remove(&mut self, attr: Attribute) {
+        self.0 &= !(1 << attr as u8);
+    }
+}
+
+impl Generator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a partial template under the given name.
+    pub fn partial(&mut self, name: &str, template: &str) -> &mut Self {
+        self.partials.insert(name.to_string(), template.to_string());
+        self
+    }
+
+    /// Enables expansion of `:shortcode:` emoji in the template text.
+    pub fn emoji(&mut self, enabled: bool) -> &mut Self {
+        self.emoji = enabled;
+        self
+    }
+
+    /// Formats `{time:...}` during generation, instead of leaving it to the shell so that it shows
+    /// the time when the prompt is drawn. Without a shell the time is always formatted right away.
+    pub fn static_time(&mut self, enabled: bool) -> &mut Self {
+        self.static_time = enabled;
+        self
+    }
+
+    /// Wraps the prompt into OSC 133 `A` and `B` marks of the prompt start and end, so that
+    /// terminals like WezTerm, kitty and iTerm2 can jump between prompts and select command output.
+    /// The marks are left out for tmux, screen and Vim, and for right and continuation prompts.
+    pub fn semantic_marks(&mut self, enabled: bool) -> &mut Self {
+        self.semantic_marks = enabled;
+        self
+    }
+
+    /// Appends OSC 7 report of the working directory to the prompt, so that terminals open new tabs
+    /// in it. The path is expanded by the shell when the prompt is drawn, shells that can't expand
+    /// it get the directory of the generation. The report is left out for tmux, screen and Vim,
+    /// and for right and continuation prompts.
+    pub fn report_cwd(&mut self, enabled: bool) -> &mut Self {
+        self.report_cwd = enabled;
+        self
+    }
+
+    /// Writes control characters in PowerShell output as `$([char]27)` instead of `` `e ``, which
+    /// is supported only since PowerShell 6.
+    pub fn legacy_escapes(&mut self, enabled: bool) -> &mut Self {
+        self.escaping.legacy_escapes = enabled;
+        self
+    }
+
+    /// Writes Zsh colors, bold and underline as `%F{n}`, `%K{n}`, `%B` and `%U`, so that Zsh
+    /// knows about them. Other attributes are still written as raw escape codes.
+    pub fn native_colors(&mut self, enabled: bool) -> &mut Self {
+        self.escaping.native_colors = enabled;
+        self
+    }
+
+    /// Passes the text to Bash, Oils and Zsh as is, so that shell escapes like `\w` or `%~` can be
+    /// written in it. By default special characters in the text are escaped, such as `$` for Bash
+    /// and `%` for Zsh, and shell escapes should be raw text.
+    pub fn shell_escapes(&mut self, enabled: bool) -> &mut Self {
+        self.escaping.shell_escapes = enabled;
+        self
+    }
+
+    /// Sets how text and escape codes are written, for generations whose `Options` don't set it.
+    pub fn escaping(&mut self, escaping: Escaping) -> &mut Self {
+        self.escaping = escaping;
+        self
+    }
+
+    /// Leaves out colors, attributes and separators, for a fallback prompt on dumb terminals.
+    /// Separators are kept if they're replaced with ASCII with `ascii`.
+    pub fn plain(&mut self, enabled: bool) -> &mut Self {
+        self.plain = enabled;
+        self
+    }
+
+    /// Sets the table that maps each of the 16 basic colors by its index to one of the 8 colors of
+    /// `ColorMode::Basic8`, for embedded and legacy terminals. See `EIGHT_COLOR_TABLE` for the
+    /// default one.
+    pub fn color_table(&mut self, table: [u8; 16]) -> &mut Self {
+        self.color_table = Some(table);
+        self
+    }
+
+    /// Replaces Powerline glyphs of the standard separators with ASCII characters.
+    pub fn ascii(&mut self, enabled: bool) -> &mut Self {
+        self.ascii = enabled;
+        self
+    }
+
+    /// Sets the direction of caps for sections that don't set it with the `dir` option.
+    pub fn direction(&mut self, direction: Direction) -> &mut Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Sets the caps of templates that don't declare them with `{caps:glyphs}`, unless `Options`
+    /// set them. It takes the same values as `sep`, like `round` or the glyphs.
+    pub fn caps(&mut self, glyphs: &str) -> Result<&mut Self, Error> {
+        self.caps = Some(parse_separator(glyphs.trim())?);
+        Ok(self)
+    }
+
+    /// Limits how deep sections, conditionals, includes and macros can be nested, 64 by default.
+    pub fn max_depth(&mut self, depth: usize) -> &mut Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Registers a partial template read from a file, the trailing newline is not included.
+    pub fn partial_file<P: AsRef<Path>>(&mut self, name: &str, path: P) -> io::Result<&mut Self> {
+        let template = fs::read_to_string(path)?;
+        let template = template.strip_suffix('\n').unwrap_or(&template);
+
+        Ok(self.partial(name, template))
+    }
+
+    /// Generates the prompt for the shell, or with `Options` that set the color mode too.
+    pub fn generate(&self, template: &str, options: impl Into<Options>) -> Result<String, Error> {
+        self.generate_with(template, options, &HashMap::new())
+    }
+
+    /// Generates the prompt into the writer, like an existing buffer or a formatter. It's written
+    /// in parts while it's rendered, except for lines with fills, which are held until their end,
+    /// and for Fish, PowerShell, Nu, Elvish, Clink, Vim and Starship, where the whole prompt is
+    /// quoted or converted at once. Nothing is written if the template is invalid.
+    pub fn generate_to<W: fmt::Write>(
+        &self,
+        template: &str,
+        options: impl Into<Options>,
+        out: &mut W,
+    ) -> Result<(), Error> {
+        let content = HashMap::new();
+        self.generate_prompt_to(template, options.into(), &content, Prompt::Main, out)
+    }
+
+    /// Generates the prompt replacing the content of named sections, like `{33,236@cwd:}`, with
+    /// the given text. Sections without the content keep what's written in the template.
+    /// Placeholders like `{{branch}}` are replaced with the values too, or removed if there's none.
+    pub fn generate_with(
+        &self,
+        template: &str,
+        options: impl Into<Options>,
+        content: &HashMap<&str, String>,
+    ) -> Result<String, Error> {
+        self.generate_prompt(template, options.into(), content, Prompt::Main)
+    }
+
+    /// Generates a continuation prompt that matches the template, like `PS2` of Bash. It's a single
+    /// section with `…` styled like the last top-level section of the template, followed by the
+    /// whitespace that the template ends with.
+    pub fn generate_continuation(
+        &self,
+        template: &str,
+        options: impl Into<Options>,
+    ) -> Result<String, Error> {
+        self.generate_prompt(
+            template,
+            options.into(),
+            &HashMap::new(),
+            Prompt::Continuation,
+        )
+    }
+
+    /// Generates a prompt for the right side of the terminal, like `RPROMPT` of Zsh. Caps point
+    /// left unless sections set the direction, and there are no caps at the ends of sections, so
+    /// that each section starts with a cap and the prompt ends flat at the edge. Trailing
+    /// whitespace is dropped, and line breaks and fills are not allowed.
+    pub fn generate_right(
+        &self,
+        template: &str,
+        options: impl Into<Options>,
+    ) -> Result<String, Error> {
+        self.generate_prompt(template, options.into(), &HashMap::new(), Prompt::Right)
+    }
+
+    /// Renders the prompt as HTML with `<span>` elements for styles, for previews and docs. It's
+    /// generated without a shell, so shell-side parts are resolved right away.
+    pub fn render_html(&self, template: &str) -> Result<String, Error> {
+        let mut generator = self.clone();
+        generator.html = true;
+        generator.generate(template, Shell::None)
+    }
+
+    /// Renders the prompt as SVG image of a terminal, with Powerline glyphs drawn as shapes. It's
+    /// generated without a shell, like with `render_html`.
+    pub fn render_svg(&self, template: &str) -> Result<String, Error> {
+        self.generate(template, Shell::None)
+            .map(|output| svg_image(&output))
+    }
+
+    /// Generates the prompt without failing on errors, so that a typo doesn't break the shell.
+    /// Malformed parts are rendered as plain text, and sections with invalid meta as their content
+    /// in the style around them. Errors that can't be recovered from, like a missing partial,
+    /// leave the whole template as plain text. The errors are returned as warnings either way.
+    pub fn generate_lenient(
+        &self,
+        template: &str,
+        options: impl Into<Options>,
+    ) -> (String, Vec<Error>) {
+        let options = options.into();
+        let mut warnings = Vec::new();
+
+        let mut output = String::new();
+
+        let result = self
+            .expand_includes(template, &mut Vec::new(), &mut HashMap::new())
+            .map_err(Error::from)
+            .and_then(|expanded| {
+                let result = self.generate_expanded(
+                    &expanded,
+                    options,
+                    &HashMap::new(),
+                    Prompt::Main,
+                    Some(&mut warnings),
+                    &mut output,
+                );
+
+                if expanded != template {
+                    for warning in &mut warnings {
+                        warning.span = None;
+                    }
+                }
+
+                result
+            });
+
+        if let Err(error) = result {
+            warnings.push(error);
+
+            let nodes = vec![Node::Text(template.to_string())];
+            output = self.render_main(&Definitions::default(), &nodes, options, &HashMap::new());
+        }
+
+        (output, warnings)
+    }
+
+    /// Renders a parsed or built template as the prompt, like `generate` does with the template.
+    pub fn render(&self, ast: &Ast, options: impl Into<Options>) -> Result<String, Error> {
+        Ok(self.render_main(
+            &ast.definitions,
+            &ast.nodes,
+            options.into(),
+            &HashMap::new(),
+        ))
+    }
+
+    /// Parses the template into nodes for tools that work on its structure, like linters and
+    /// converters. Includes and macros are expanded, placeholders are kept as nodes.
+    pub fn parse(&self, template: &str) -> Result<Ast, Error> {
+        let expanded = self.expand_includes(template, &mut Vec::new(), &mut HashMap::new())?;
+
+        match self.parse_template(&expanded, None, None) {
+            Ok((definitions, nodes)) => Ok(Ast { nodes, definitions }),
+            Err(error) if expanded == template => Err(error),
+            Err(error) => Err(Error {
+                span: None,
+                ..error
+            }),
+        }
+    }
+
+    /// Parses the template once for rendering it many times, like a long-lived process does on
+    /// each command. The template keeps the settings of the generator.
+    pub fn compile(&self, template: &str) -> Result<Template, Error> {
+        let ast = self.parse(template)?;
+        let expanded = self.expand_includes(template, &mut Vec::new(), &mut HashMap::new())?;
+
+        Ok(Template {
+            generator: self.clone(),
+            ast,
+            source: has_variable_hash(&expanded).then_some(expanded),
+        })
+    }
+
+    /// Checks the template without generating a prompt. Parsing continues after errors, so all of
+    /// them are returned at once, in the order they're found in the template.
+    pub fn check(&self, template: &str) -> Result<(), Vec<Error>> {
+        self.validate(template).map(|_| ())
+    }
+
+    /// Checks the template like `check`, and returns statistics of it when it's valid, for
+    /// editors and CI checks of dotfiles.
+    pub fn validate(&self, template: &str) -> Result<TemplateInfo, Vec<Error>> {
+        let expanded = self
+            .expand_includes(template, &mut Vec::new(), &mut HashMap::new())
+            .map_err(|error| vec![Error::from(error)])?;
+
+        let mut errors = Vec::new();
+        let parsed = self.parse_template(&expanded, None, Some(&mut errors));
+
+        if let Err(error) = &parsed {
+            errors.push(error.clone());
+        }
+
+        if expanded != template {
+            for error in &mut errors {
+                error.span = None;
+            }
+        }
+
+        match parsed {
+            Ok((definitions, nodes)) if errors.is_empty() => {
+                let mut info = TemplateInfo {
+                    version: definitions.version.unwrap_or(1),
+                    ..TemplateInfo::default()
+                };
+
+                if let Some(default) = &definitions.default {
+                    info.add_colors(&default.style);
+                }
+
+                info.add_nodes(&nodes, 0);
+                Ok(info)
+            }
+            _ => Err(errors),
+        }
+    }
+
+    /// Replaces includes with the contents of partials and uses of macros with their bodies, both
+    /// are expanded recursively. Macros are collected as they are defined, a partial can define
+    /// macros for the rest of the template.
+    fn expand_includes(
+        &self,
+        template: &str,
+        stack: &mut Vec<String>,
+        macros: &mut HashMap<String, String>,
+    ) -> Result<String, ErrorKind> {
+        let mut buffer = String::new();
+        let mut chars = template.chars();
+
+        while let Some(next) = chars.next() {
+            buffer.push(next);
+
+            match next {
+                ESCAPE => buffer.extend(chars.next()),
+                OPEN_BRACE if chars.as_str().starts_with(INCLUDE) => {
+                    buffer.pop();
+                    chars.next();
+
+                    let name: String = chars.by_ref().take_while(|c| *c != CLOSE_BRACE).collect();
+                    let name = name.trim();
+
+                    let partial = match self.partials.get(name) {
+                        Some(partial) => partial,
+                        None => return Err(ErrorKind::UnknownPartial(name.to_string())),
+                    };
+
+                    let key = format!("{}{}", INCLUDE, name);
+
+                    if stack.contains(&key) {
+                        return Err(ErrorKind::RecursiveInclude(name.to_string()));
+                    }
+
+                    self.check_depth(stack)?;
+                    stack.push(key);
+                    buffer.push_str(&self.expand_includes(partial, stack, macros)?);
+                    stack.pop();
+                }
+                OPEN_BRACE if chars.as_str().starts_with(DEFINE) => {
+                    buffer.pop();
+                    chars.nth(DEFINE.len() - 1);
+
+                    let name: String = chars.by_ref().take_while(|c| *c != CLOSE_BRACE).collect();
+                    let name = name.trim();
+
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find(END_DEFINE)
+                        .ok_or_else(|| ErrorKind::UnterminatedMacro(name.to_string()))?;
+
+                    macros.insert(name.to_string(), rest[..end].to_string());
+                    chars = rest[end + END_DEFINE.len()..].chars();
+                }
+                OPEN_BRACE if chars.as_str().starts_with(USE) => {
+                    buffer.pop();
+                    chars.nth(USE.len() - 1);
+
+                    let name: String = chars.by_ref().take_while(|c| *c != CLOSE_BRACE).collect();
+                    let name = name.trim();
+
+                    let body = match macros.get(name) {
+                        Some(body) => body.clone(),
+                        None => return Err(ErrorKind::UnknownMacro(name.to_string())),
+                    };
+
+                    if stack.iter().any(|key| key == name) {
+                        return Err(ErrorKind::RecursiveMacro(name.to_string()));
+                    }
+
+                    self.check_depth(stack)?;
+                    stack.push(name.to_string());
+                    buffer.push_str(&self.expand_includes(&body, stack, macros)?);
+                    stack.pop();
+                }
+                OPEN_BRACE if is_comment(chars.as_str()) => {
+                    let comment = chars.as_str();
+                    skip_comment(&mut chars)?;
+                    buffer.push_str(&comment[..comment.len() - chars.as_str().len()]);
+                }
+                _ => (),
+            }
+        }
+
+        Ok(buffer)
+    }
+
+    /// Expands the template and generates the prompt. Spans of errors are dropped when the
+    /// expansion changed the template, they wouldn't point into the one that was given.
+    fn generate_prompt(
+        &self,
+        template: &str,
+        options: Options,
+        content: &HashMap<&str, String>,
+        prompt: Prompt,
+    ) -> Result<String, Error> {
+        let mut output = String::new();
+        self.generate_prompt_to(template, options, content, prompt, &mut output)?;
+
+        Ok(output)
+    }
+
+    fn generate_prompt_to(
+        &self,
+        template: &str,
+        options: Options,
+        content: &HashMap<&str, String>,
+        prompt: Prompt,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), Error> {
+        let expanded = self.expand_includes(template, &mut Vec::new(), &mut HashMap::new())?;
+
+        self.generate_expanded(&expanded, options, content, prompt, None, out)
+            .map_err(|error| match expanded == template {
+                true => error,
+                false => Error {
+                    span: None,
+                    ..error
+                },
+            })
+    }
+
+    fn generate_expanded(
+        &self,
+        template: &str,
+        options: Options,
+        content: &HashMap<&str, String>,
+        prompt: Prompt,
+        errors: Option<&mut Vec<Error>>,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), Error> {
+        let (definitions, nodes) = self.parse_template(template, Some(content), errors)?;
+        self.render_prompt(&definitions, nodes, options, content, prompt, out)
+    }
+
+    fn render_prompt(
+        &self,
+        definitions: &Definitions,
+        nodes: Vec<Node>,
+        options: Options,
+        content: &HashMap<&str, String>,
+        prompt: Prompt,
+        out: &mut dyn fmt::Write,
+    ) -> Result<(), Error> {
+        let mut nodes = fill_placeholders(nodes, content);
+
+        match prompt {
+            Prompt::Main => (),
+            Prompt::Continuation => {
+                let ellipsis = if self.ascii { "..." } else { "…" };
+                nodes = continuation_nodes(&nodes, ellipsis);
+            }
+            Prompt::Right => {
+                if !is_single_line(&nodes) {
+                    return Err(ErrorKind::MultilineRightPrompt.into());
+                }
+
+                if let Some(Node::Text(text)) = nodes.last_mut() {
+                    *text = text.trim_end().to_string();
+                }
+            }
+        }
+
+        self.render_filled(
+            definitions,
+            &nodes,
+            options,
+            content,
+            prompt,
+            Sink::Writer(out),
+        )
+        .map_err(|_| ErrorKind::Write.into())
+    }
+
+    /// Renders the main prompt, which can't fail unlike the right one.
+    fn render_main(
+        &self,
+        definitions: &Definitions,
+        nodes: &[Node],
+        options: Options,
+        content: &HashMap<&str, String>,
+    ) -> String {
+        let mut output = String::new();
+
+        // Writing into a string never fails
+        let _ = self.render_filled(
+            definitions,
+            nodes,
+            options,
+            content,
+            Prompt::Main,
+            Sink::Writer(&mut output),
+        );
+
+        output
+    }
+
+    /// Renders the main prompt into chunks, which borrow text of the nodes and the content.
+    fn render_chunks<'n>(
+        &self,
+        definitions: &Definitions,
+        nodes: &'n [Node],
+        options: Options,
+        content: &'n HashMap<&'n str, String>,
+    ) -> Vec<Chunk<'n>> {
+        let mut chunks = Vec::new();
+
+        // Collecting chunks never fails
+        let _ = self.render_filled(
+            definitions,
+            nodes,
+            options,
+            content,
+            Prompt::Main,
+            Sink::Chunks(&mut chunks),
+        );
+
+        chunks
+    }
+
+    /// Renders nodes after they're adjusted for the prompt, filling in the content as it goes.
+    fn render_filled<'n>(
+        &self,
+        definitions: &Definitions,
+        nodes: &'n [Node],
+        options: Options,
+        content: &'n HashMap<&'n str, String>,
+        prompt: Prompt,
+        mut sink: Sink<'_, 'n>,
+    ) -> fmt::Result {
+        let colors = options.resolve_color_mode();
+        let escaping = options.escaping.unwrap_or(self.escaping);
+        let html = HtmlEncoder::default();
+
+        let encoder = match self.html {
+            true => &html,
+            false => color_encoder(options.shell, colors, escaping.native_colors, self.plain),
+        };
+
+        let mut renderer = Renderer {
+            sink: sink.reborrow(),
+            written: Ok(()),
+            // The output of these shells is quoted or converted as a whole when it's complete, and
+            // spans of HTML are merged while they're in the buffer
+            streaming: !self.html
+                && !matches!(
+                    options.shell,
+                    Shell::Fish
+                        | Shell::PowerShell
+                        | Shell::Nu
+                        | Shell::Elvish
+                        | Shell::Clink
+                        | Shell::Vim
+                        | Shell::Starship
+                ),
+            captures: 0,
+            buffer: Buffer::default(),
+            in_escape: false,
+            output: Output {
+                shell: options.shell,
+                encoder,
+                html: self.html,
+                shell_escapes: escaping.shell_escapes,
+                plain: self.plain,
+                ascii: self.ascii,
+                colors,
+                right: prompt == Prompt::Right,
+                color_table: self.color_table.unwrap_or(EIGHT_COLOR_TABLE),
+            },
+            content,
+            styles: Vec::new(),
+            pending: None,
+            budget: None,
+            columns: 0,
+            fills: Vec::new(),
+            coloring: None,
+            stale: false,
+            static_time: self.static_time,
+            caps: definitions.caps.or(options.caps).or(self.caps),
+            direction: match prompt {
+                Prompt::Right => Direction::Left,
+                _ => self.direction,
+            },
+            closed: None,
+            status: false,
+            in_branch: false,
+            legacy_escapes: escaping.legacy_escapes,
+            // Right and continuation prompts are parts of the same prompt for the terminal
+            semantic_marks: self.semantic_marks && prompt == Prompt::Main,
+            report_cwd: self.report_cwd && prompt == Prompt::Main,
+        };
+
+        // The delimiter of non-printing sequences is declared at the start of the prompt
+        if let Shell::Ksh = options.shell {
+            renderer.buffer.push(NON_PRINTING);
+            renderer.buffer.push('\r');
+        }
+
+        if renderer.semantic_marks {
+            push_semantic_mark(&mut renderer.buffer, 'A', renderer.output);
+        }
+
+        match &definitions.default {
+            Some(default) => renderer.render_section(default, nodes),
+            None => renderer.render_nodes(nodes),
+        }
+
+        if self.html {
+            renderer.flush();
+            html.close(&mut renderer.buffer);
+        }
+
+        renderer.finish()
+    }
+}
+
+impl Generator {
+    /// Parses the header and the nodes of the expanded template, with the `values` of a generation
+    /// if it's one. Recoverable errors are collected into `errors` if it's given, parsing
+    /// continues after them.
+    fn parse_template(
+        &self,
+        template: &str,
+        values: Option<&HashMap<&str, String>>,
+        mut errors: Option<&mut Vec<Error>>,
+    ) -> Result<(Definitions, Vec<Node>), Error> {
+        let (definitions, body) = parse_header(template, values, errors.as_deref_mut())?;
+
+        let mut parser = Parser {
+            source: template,
+            chars: body.chars(),
+            definitions: &definitions,
+            emoji: self.emoji,
+            depth: 0,
+            max_depth: self.depth_limit(),
+            errors,
+        };
+
+        let default = definitions.default.as_ref();
+        let nodes = parser.parse_nodes(default.map(|default| &default.style), Block::Template)?;
+
+        Ok((definitions, nodes))
+    }
+
+    fn depth_limit(&self) -> usize {
+        self.max_depth.unwrap_or(MAX_DEPTH)
+    }
+
+    fn check_depth(&self, stack: &[String]) -> Result<(), ErrorKind> {
+        match stack.len() < self.depth_limit() {
+            true => Ok(()),
+            false => Err(ErrorKind::IncludesTooDeep(self.depth_limit())),
+        }
+    }
+}
+
+pub fn generate(template: &str, options: impl Into<Options>) -> Result<String, Error> {
+    Generator::new().generate(template, options)
+}
+
+/// Generates the prompt into the writer, see `Generator::generate_to`.
+pub fn generate_to<W: fmt::Write>(
+    template: &str,
+    options: impl Into<Options>,
+    out: &mut W,
+) -> Result<(), Error> {
+    Generator::new().generate_to(template, options, out)
+}
+
+/// Renders the template as SVG image, see `Generator::render_svg`.
+pub fn render_svg(template: &str) -> Result<String, Error> {
+    Generator::new().render_svg(template)
+}
+
+/// Parses the template, see `Generator::parse`.
+pub fn parse(template: &str) -> Result<Ast, Error> {
+    Generator::new().parse(template)
+}
+
+/// Renders the parsed template, see `Generator::render`.
+pub fn render(ast: &Ast, options: impl Into<Options>) -> Result<String, Error> {
+    Generator::new().render(ast, options)
+}
+
+/// Parses the template for rendering it many times, see `Generator::compile`.
+pub fn compile(template: &str) -> Result<Template, Error> {
+    Generator::new().compile(template)
+}
+
+/// Checks the template and returns all errors in it, see `Generator::check`.
+pub fn check(template: &str) -> Result<(), Vec<Error>> {
+    Generator::new().check(template)
+}
+
+/// Checks the template and returns its statistics, see `Generator::validate`.
+pub fn validate(template: &str) -> Result<TemplateInfo, Vec<Error>> {
+    Generator::new().validate(template)
+}
+
+/// Renders the template as HTML, see `Generator::render_html`.
+pub fn render_html(template: &str) -> Result<String, Error> {
+    Generator::new().render_html(template)
+}
+
+/// Returns the syntax version declared with `{pragma:vN}` at the start of the template, or 1.
+pub fn template_version(template: &str) -> Result<u32, Error> {
+    parse_pragma(template)
+        .map(|(version, _)| version.unwrap_or(1))
+        .map_err(Error::from)
+}
+
+impl<'a> Parser<'a> {
+    /// Parses nodes of a nested block, keeping track of the nesting depth.
+    fn parse_nodes(&mut self, parent: Option<&Style>, block: Block) -> Result<Vec<Node>, Error> {
+        if block == Block::Template {
+            return self.parse_block(parent, block);
+        }
+
+        if self.depth == self.max_depth {
+            let position = self.position(self.chars.as_str());
+
+            return Err(ErrorKind::NestingTooDeep {
+                limit: self.max_depth,
+                position,
+            }
+            .into());
+        }
+
+        self.depth += 1;
+        let nodes = self.parse_block(parent, block);
+        self.depth -= 1;
+
+        nodes
+    }
+
+    /// Parses nodes until the end of the block, styles of sections are resolved against the parent.
+    /// Conditional blocks stop right before `{|}` or `{/}`, leaving them to the caller.
+    fn parse_block(&mut self, parent: Option<&Style>, block: Block) -> Result<Vec<Node>, Error> {
+        let mut nodes = Vec::new();
+        let mut text = String::new();
+
+        loop {
+            let rest = self.chars.as_str();
+
+            let ends_block = match block {
+                Block::Conditional => rest.starts_with(ELSE) || rest.starts_with(END_CONDITIONAL),
+                Block::Else => rest.starts_with(END_CONDITIONAL),
+                Block::TernaryThen => rest.starts_with(':'),
+                _ => false,
+            };
+
+            if ends_block {
+                push_text(&mut nodes, &mut text);
+                return Ok(nodes);
+            }
+
+            let next = match self.chars.next() {
+                Some(next) => next,
+                None => break,
+            };
+
+            match self.parse_part(next, rest, parent, block, &mut nodes, &mut text) {
+                Ok(true) => return Ok(nodes),
+                Ok(false) => (),
+                Err(error) => {
+                    let error = self.locate(error, rest);
+                    self.recover(error)?;
+
+                    // The part is kept as plain text when the prompt is generated anyway.
+                    text.push_str(&rest[..rest.len() - self.chars.as_str().len()]);
+                }
+            }
+        }
+
+        match block {
+            Block::Template => {
+                push_text(&mut nodes, &mut text);
+                Ok(nodes)
+            }
+            Block::Section => Err(ErrorKind::UnbalancedBraces.into()),
+            _ => Err(ErrorKind::UnterminatedConditional.into()),
+        }
+    }
+
+    /// Parses the part of a block that starts with `next`, returns whether it ended the block.
+    fn parse_part(
+        &mut self,
+        next: char,
+        rest: &'a str,
+        parent: Option<&Style>,
+        block: Block,
+        nodes: &mut Vec<Node>,
+        text: &mut String,
+    ) -> Result<bool, Error> {
+        match next {
+            OPEN_BRACE if is_comment(&rest[1..]) => skip_comment(&mut self.chars)?,
+            OPEN_BRACE if rest.starts_with(ELSE) || rest.starts_with(END_CONDITIONAL) => {
+                self.chars.nth(1);
+
+                let error = Error::from(ErrorKind::Unexpected(rest[..3].to_string()));
+                return Err(error.at(Span::of(self.source, &rest[..3])));
+            }
+            OPEN_BRACE if rest.starts_with(FILL) => {
+                self.chars.nth(FILL.len() - 2);
+
+                push_text(nodes, text);
+                nodes.push(Node::Fill(' '));
+            }
+            OPEN_BRACE if rest.starts_with(HR) => {
+                self.chars.nth(HR.len() - 2);
+
+                let fill = self.parse_hr()?;
+
+                push_text(nodes, text);
+                nodes.push(Node::Fill(fill));
+            }
+            OPEN_BRACE if rest.starts_with(PLACEHOLDER) => {
+                self.chars.next();
+
+                let name = self.parse_placeholder()?;
+
+                push_text(nodes, text);
+                nodes.push(Node::Placeholder(name.to_string()));
+            }
+            OPEN_BRACE if rest.starts_with(TIME) => {
+                self.chars.nth(TIME.len() - 2);
+
+                let format: String = self
+                    .chars
+                    .by_ref()
+                    .take_while(|c| *c != CLOSE_BRACE)
+                    .collect();
+
+                push_text(nodes, text);
+                nodes.push(Node::Time(format));
+            }
+            OPEN_BRACE if rest.starts_with(ICON) => {
+                self.chars.nth(ICON.len() - 2);
+                text.push(self.parse_icon()?);
+            }
+            OPEN_BRACE if rest.starts_with(TITLE) => {
+                self.chars.nth(TITLE.len() - 2);
+
+                let title = self.parse_nodes(None, Block::Section)?;
+
+                push_text(nodes, text);
+                nodes.push(Node::Title(title));
+            }
+            OPEN_BRACE if rest.starts_with(LINK) => {
+                self.chars.nth(LINK.len() - 2);
+
+                let link = self.parse_link(parent)?;
+
+                push_text(nodes, text);
+                nodes.push(link);
+            }
+            OPEN_BRACE if rest[1..].starts_with(RAW) => {
+                self.chars.next();
+
+                let raw = self.parse_raw()?;
+
+                push_text(nodes, text);
+                nodes.push(Node::Raw(raw));
+            }
+            OPEN_BRACE if rest[1..].starts_with(CONDITIONAL) => {
+                self.chars.next();
+
+                let condition = self.parse_condition();
+                let (then, otherwise) = self.parse_branches(parent)?;
+
+                push_text(nodes, text);
+                nodes.push(Node::Conditional(condition, then, otherwise));
+            }
+            OPEN_BRACE => {
+                let section = match parse_section(&mut self.chars, parent, self.definitions) {
+                    Ok(section) => section,
+                    Err(error) => {
+                        // The section is checked with the parent style, so that errors in its
+                        // content are found and its closing brace is not unbalanced.
+                        let error = self.locate(error, rest);
+                        self.recover(error)?;
+                        let children = self.parse_nodes(parent, Block::Section)?;
+
+                        push_text(nodes, text);
+                        nodes.extend(children);
+                        return Ok(false);
+                    }
+                };
+                let children = self.parse_nodes(Some(&section.style), Block::Section)?;
+
+                push_text(nodes, text);
+                nodes.push(Node::Section(section, children));
+            }
+            CLOSE_BRACE => match block {
+                Block::Section | Block::TernaryElse => {
+                    push_text(nodes, text);
+                    return Ok(true);
+                }
+                Block::TernaryThen => {
+                    let error = self.locate(ErrorKind::MissingColon, rest);
+                    self.recover(error)?;
+
+                    // The brace closes the conditional, the else branch is left empty.
+                    self.chars = rest.chars();
+                    return Ok(true);
+                }
+                _ => return Err(ErrorKind::UnbalancedBraces.into()),
+            },
+            ESCAPE => match rest[1..].chars().next() {
+                Some(c @ OPEN_BRACE) | Some(c @ CLOSE_BRACE) | Some(c @ ESCAPE) => {
+                    self.chars.next();
+                    text.push(c);
+                }
+                Some('n') => {
+                    self.chars.next();
+                    text.push('\n');
+                }
+                Some('e') => {
+                    self.chars.next();
+                    text.push('\x1b');
+                }
+                Some('a') => {
+                    self.chars.next();
+                    text.push('\x07');
+                }
+                Some('x') => {
+                    let position = self.position(rest);
+                    let c = self.parse_hex_escape(position)?;
+                    text.push(c);
+                }
+                Some(':') if block == Block::TernaryThen => {
+                    self.chars.next();
+                    text.push(':');
+                }
+                Some('u') if rest[2..].starts_with(OPEN_BRACE) => {
+                    let position = self.position(rest);
+                    let c = self.parse_unicode_escape(position)?;
+                    text.push(c);
+                }
+                _ => text.push(ESCAPE),
+            },
+            ':' if self.emoji => match self.parse_emoji() {
+                Some(emoji) => text.push_str(emoji),
+                None => text.push(':'),
+            },
+            _ => text.push(next),
+        }
+
+        Ok(false)
+    }
+
+    fn recover(&mut self, error: Error) -> Result<(), Error> {
+        recover(self.errors.as_deref_mut(), error)
+    }
+
+    /// Sets the span of the part starting at `rest` for errors that don't have one. It ends where
+    /// the parser stopped, or covers just the first character if the template ended before.
+    fn locate(&self, error: impl Into<Error>, rest: &str) -> Error {
+        let start = self.source.len() - rest.len();
+        let end = match self.chars.as_str() {
+            "" => start + rest.chars().next().map_or(0, char::len_utf8),
+            current => self.source.len() - current.len(),
+        };
+
+        error.into().at(Span::new(self.source, start, end))
+    }
+
+    /// Position of the beginning of the `rest` in the template, counted in characters from 1.
+    fn position(&self, rest: &str) -> usize {
+        let offset = self.source.len() - rest.len();
+        self.source[..offset].chars().count() + 1
+    }
+
+    /// Parses `xHH` part of a hex escape with exactly two hex digits. Codes above `7f` are
+    /// rejected, the output is UTF-8 and can't have them as raw bytes.
+    fn parse_hex_escape(&mut self, position: usize) -> Result<char, ErrorKind> {
+        self.chars.next();
+
+        let rest = self.chars.as_str();
+        let code = rest
+            .get(..2)
+            .filter(|code| code.chars().all(|c| c.is_ascii_hexdigit()));
+
+        match code.and_then(|code| u8::from_str_radix(code, 16).ok()) {
+            Some(code) if code.is_ascii() => {
+                self.chars = rest[2..].chars();
+                Ok(code as char)
+            }
+            _ => Err(ErrorKind::MalformedHexEscape(position)),
+        }
+    }
+
+    /// Parses `u{XXXX}` part of a unicode escape, where the hex code can have up to 6 digits.
+    fn parse_unicode_escape(&mut self, position: usize) -> Result<char, ErrorKind> {
+        self.chars.nth(1);
+
+        let rest = self.chars.as_str();
+        let code = rest.find(CLOSE_BRACE).map(|end| {
+            self.chars = rest[end + 1..].chars();
+            &rest[..end]
+        });
+
+        code.filter(|code| (1..=6).contains(&code.len()))
+            .and_then(|code| u32::from_str_radix(code, 16).ok())
+            .and_then(char::from_u32)
+            .ok_or(ErrorKind::MalformedUnicodeEscape(position))
+    }
+
+    /// Parses a single character of a horizontal rule up to the closing brace, it can be escaped.
+    fn parse_hr(&mut self) -> Result<char, ErrorKind> {
+        let position = self.position(self.chars.as_str());
+
+        let fill = match self.chars.next() {
+            Some(ESCAPE) if self.chars.as_str().starts_with("u{") => {
+                self.parse_unicode_escape(position)?
+            }
+            Some(ESCAPE) => self.chars.next().unwrap_or(ESCAPE),
+            Some(fill) if fill != CLOSE_BRACE => fill,
+            _ => return Err(ErrorKind::InvalidHr),
+        };
+
+        match self.chars.next() {
+            // The character stands for fills in the output
+            Some(CLOSE_BRACE) if fill == FILL_MARKER => Err(ErrorKind::InvalidHr),
+            Some(CLOSE_BRACE) => Ok(fill),
+            _ => {
+                self.chars.by_ref().find(|c| *c == CLOSE_BRACE);
+                Err(ErrorKind::InvalidHr)
+            }
+        }
+    }
+
+    /// Parses a placeholder name up to the closing braces and looks up its value.
+    fn parse_placeholder(&mut self) -> Result<&'a str, ErrorKind> {
+        let rest = self.chars.as_str();
+        let end = rest
+            .find(END_PLACEHOLDER)
+            .ok_or(ErrorKind::UnbalancedBraces)?;
+
+        self.chars = rest[end + END_PLACEHOLDER.len()..].chars();
+
+        Ok(rest[..end].trim())
+    }
+
+    /// Parses an icon name up to the closing brace and looks it up in the icon table.
+    fn parse_icon(&mut self) -> Result<char, ErrorKind> {
+        let rest = self.chars.as_str();
+        let end = rest.find(CLOSE_BRACE).ok_or(ErrorKind::UnbalancedBraces)?;
+
+        self.chars = rest[end + 1..].chars();
+
+        let name = rest[..end].trim();
+
+        ICONS
+            .binary_search_by_key(&name, |(name, _)| name)
+            .map(|index| ICONS[index].1)
+            .map_err(|_| ErrorKind::UnknownIcon(name.to_string()))
+    }
+
+    /// Parses the rest of a `:shortcode:`, leaving the text intact if it's not a known emoji.
+    fn parse_emoji(&mut self) -> Option<&'static str> {
+        let rest = self.chars.as_str();
+        let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || "_+-".contains(c)))?;
+
+        if !rest[end..].starts_with(':') {
+            return None;
+        }
+
+        let index = EMOJI
+            .binary_search_by_key(&&rest[..end], |(name, _)| name)
+            .ok()?;
+
+        self.chars = rest[end + 1..].chars();
+        Some(EMOJI[index].1)
+    }
+
+    /// Parses a link URL, optionally followed by the label. Without the label the URL is
+    /// shown instead.
+    fn parse_link(&mut self, parent: Option<&Style>) -> Result<Node, Error> {
+        let rest = self.chars.as_str();
+        let end = rest
+            .find([LINK_LABEL, CLOSE_BRACE])
+            .ok_or(ErrorKind::UnbalancedBraces)?;
+
+        let url = rest[..end].trim().to_string();
+        self.chars = rest[end + 1..].chars();
+
+        let label = match rest[end..].starts_with(LINK_LABEL) {
+            true => self.parse_nodes(parent, Block::Section)?,
+            false => vec![Node::Text(url.clone())],
+        };
+
+        Ok(Node::Link(url, label))
+    }
+
+    /// Reads raw text up to the closing brace, braces inside of it should be balanced.
+    fn parse_raw(&mut self) -> Result<String, ErrorKind> {
+        let mut raw = String::new();
+        let mut depth = 0;
+
+        for c in self.chars.by_ref() {
+            match c {
+                OPEN_BRACE => depth += 1,
+                CLOSE_BRACE if depth == 0 => return Ok(raw),
+                CLOSE_BRACE => depth -= 1,
+                _ => (),
+            }
+
+            raw.push(c);
+        }
+
+        Err(ErrorKind::UnterminatedRaw)
+    }
+
+    /// Parses a condition name terminated by whitespace or the closing brace.
+    fn parse_condition(&mut self) -> Condition {
+        let rest = self.chars.as_str();
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == CLOSE_BRACE)
+            .unwrap_or(rest.len());
+
+        self.chars = rest[end..].chars();
+
+        match &rest[..end] {
+            "?" => Condition::Status,
+            variable => Condition::Variable(variable.to_string()),
+        }
+    }
+
+    /// Parses either a `}then{|}else{/}` block or a compact ` ? then : else}` form.
+    fn parse_branches(&mut self, parent: Option<&Style>) -> Result<(Vec<Node>, Vec<Node>), Error> {
+        self.chars = self.chars.as_str().trim_start().chars();
+
+        if self.eat("?") {
+            let mut then = self.parse_nodes(parent, Block::TernaryThen)?;
+            self.eat(":");
+            let mut otherwise = self.parse_nodes(parent, Block::TernaryElse)?;
+
+            trim_nodes(&mut then);
+            trim_nodes(&mut otherwise);
+
+            return Ok((then, otherwise));
+        }
+
+        if !self.eat("}") {
+            let rest = self.chars.as_str();
+            let end = rest.find(CLOSE_BRACE);
+
+            let error = Error::from(ErrorKind::InvalidConditional);
+            self.recover(error.at(Span::of(self.source, &rest[..end.unwrap_or(rest.len())])))?;
+            self.chars = rest[end.map_or(rest.len(), |end| end + 1)..].chars();
+        }
+
+        let then = self.parse_nodes(parent, Block::Conditional)?;
+        let otherwise = if self.eat(ELSE) {
+            self.parse_nodes(parent, Block::Else)?
+        } else {
+            Vec::new()
+        };
+        self.eat(END_CONDITIONAL);
+
+        Ok((then, otherwise))
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        match self.chars.as_str().strip_prefix(token) {
+            Some(rest) => {
+                self.chars = rest.chars();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Splits the template into tokens with their spans, for syntax highlighters and editor plugins
+/// that don't need the parsed structure. It doesn't fail, the rest of the template after a part
+/// that isn't closed is a single token, and braces that close nothing are `CloseSection`.
+pub fn tokens(template: &str) -> Tokens<'_> {
+    let body = match parse_header(template, None, Some(&mut Vec::new())) {
+        Ok((_, body)) => body,
+        Err(_) => template,
+    };
+
+    let mut tokens = Tokens {
+        source: template,
+        rest: template,
+        line: 1,
+        column: 1,
+        header: None,
+        blocks: Vec::new(),
+    };
+
+    if body.len() < template.len() {
+        tokens.header = Some(template.len() - body.len());
+    }
+
+    tokens
+}
+
+impl<'a> Tokens<'a> {
+    /// Takes the next `len` bytes as the token.
+    fn take(&mut self, len: usize, token: Token<'a>) -> (Token<'a>, Span) {
+        let (part, rest) = self.rest.split_at(len);
+        let start = self.source.len() - self.rest.len();
+
+        let span = Span {
+            start,
+            end: start + len,
+            line: self.line,
+            column: self.column,
+        };
+
+        match part.rfind('\n') {
+            Some(i) => {
+                self.line += part.matches('\n').count();
+                self.column = part[i + 1..].chars().count() + 1;
+            }
+            None => self.column += part.chars().count(),
+        }
+
+        self.rest = rest;
+        (token, span)
+    }
+
+    /// Length of the tag up to and including the closing brace, skipping escaped characters.
+    fn tag_len(&self) -> usize {
+        let mut chars = self.rest.char_indices().skip(1);
+
+        while let Some((i, c)) = chars.next() {
+            match c {
+                ESCAPE => {
+                    chars.next();
+                }
+                CLOSE_BRACE => return i + 1,
+                _ => (),
+            }
+        }
+
+        self.rest.len()
+    }
+
+    /// Length of the raw text up to and including the closing brace, with nested braces.
+    fn raw_len(&self) -> usize {
+        let mut depth = 0;
+
+        for (i, c) in self.rest.char_indices().skip(1) {
+            match c {
+                OPEN_BRACE => depth += 1,
+                CLOSE_BRACE if depth == 0 => return i + 1,
+                CLOSE_BRACE => depth -= 1,
+                _ => (),
+            }
+        }
+
+        self.rest.len()
+    }
+
+    fn next_escape(&mut self) -> (Token<'a>, Span) {
+        let rest = self.rest;
+
+        let len = match rest[1..].chars().next() {
+            Some('u') if rest[2..].starts_with(OPEN_BRACE) => {
+                rest.find(CLOSE_BRACE).map_or(rest.len(), |end| end + 1)
+            }
+            Some('x') => rest.char_indices().nth(4).map_or(rest.len(), |(i, _)| i),
+            Some(c) => 1 + c.len_utf8(),
+            None => 1,
+        };
+
+        self.take(len, Token::Escape(&rest[..len]))
+    }
+
+    fn next_braced(&mut self) -> (Token<'a>, Span) {
+        let rest = self.rest;
+
+        if is_comment(&rest[1..]) {
+            let len = self.tag_len();
+            return self.take(len, Token::Comment(&rest[..len]));
+        }
+
+        if rest.starts_with(PLACEHOLDER) {
+            let len = rest.find(END_PLACEHOLDER).map_or(rest.len(), |end| end + 2);
+            return self.take(len, Token::Tag(&rest[..len]));
+        }
+
+        if rest[1..].starts_with(RAW) {
+            let len = self.raw_len();
+            return self.take(len, Token::Tag(&rest[..len]));
+        }
+
+        if rest.starts_with(TITLE) {
+            self.blocks.push(false);
+            return self.take(TITLE.len(), Token::OpenBlock(TITLE));
+        }
+
+        if rest.starts_with(LINK) {
+            let len = rest
+                .find([LINK_LABEL, CLOSE_BRACE])
+                .map_or(rest.len(), |end| end + 1);
+
+            if rest[..len].ends_with(LINK_LABEL) {
+                self.blocks.push(false);
+                return self.take(len, Token::OpenBlock(&rest[..len]));
+            }
+
+            return self.take(len, Token::Tag(&rest[..len]));
+        }
+
+        if rest[1..].starts_with(CONDITIONAL) {
+            let condition = rest[2..]
+                .find(|c: char| c.is_whitespace() || c == CLOSE_BRACE)
+                .map_or(rest.len(), |end| end + 2);
+            let after = rest[condition..].trim_start();
+
+            if after.starts_with(CONDITIONAL) {
+                let len = rest.len() - after.len() + 1;
+
+                self.blocks.push(false);
+                return self.take(len, Token::OpenBlock(&rest[..len]));
+            }
+        }
+
+        let is_tag = [FILL, HR, ICON, TIME, ELSE]
+            .iter()
+            .any(|tag| rest.starts_with(tag))
+            || rest[1..].starts_with([CONDITIONAL, INCLUDE, '/'])
+            || rest[1..].starts_with(DEFINE)
+            || rest[1..].starts_with(USE);
+
+        if is_tag {
+            let len = self.tag_len();
+            return self.take(len, Token::Tag(&rest[..len]));
+        }
+
+        let mut quoted = false;
+        let mut chars = rest.char_indices().skip(1);
+        let mut end = rest.len();
+
+        while let Some((i, c)) = chars.next() {
+            match c {
+                QUOTE => quoted = !quoted,
+                ESCAPE => {
+                    chars.next();
+                }
+                ':' if !quoted => {
+                    end = i;
+                    break;
+                }
+                _ => (),
+            }
+        }
+
+        self.blocks.push(true);
+        let len = (end + 1).min(rest.len());
+        self.take(len, Token::OpenSection(&rest[1..end]))
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = (Token<'a>, Span);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(len) = self.header.take() {
+            return Some(self.take(len, Token::Header(&self.rest[..len])));
+        }
+
+        let rest = self.rest;
+
+        let token = match rest.chars().next()? {
+            ESCAPE => self.next_escape(),
+            OPEN_BRACE => self.next_braced(),
+            CLOSE_BRACE => match self.blocks.pop() {
+                Some(false) => self.take(1, Token::CloseBlock),
+                _ => self.take(1, Token::CloseSection),
+            },
+            _ => {
+                let len = rest
+                    .find([OPEN_BRACE, CLOSE_BRACE, ESCAPE])
+                    .unwrap_or(rest.len());
+                self.take(len, Token::Text(&rest[..len]))
+            }
+        };
+
+        Some(token)
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Chunk<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chunks.next()
+    }
+}
+
+fn is_set(variable: &str) -> bool {
+    env::var_os(variable).is_some_and(|value| !value.is_empty())
+}
+
+/// Children of a section, or the content given for it by name.
+#[derive(Copy, Clone)]
+enum Children<'a> {
+    Nodes(&'a [Node]),
+    Text(&'a str),
+}
+
+impl Children<'_> {
+    fn width(self, content: &HashMap<&str, String>) -> usize {
+        match self {
+            Children::Nodes(nodes) => width(nodes, content),
+            Children::Text(text) => text.width(),
+        }
+    }
+
+    fn is_blank(self, content: &HashMap<&str, String>) -> bool {
+        match self {
+            Children::Nodes(nodes) => is_blank(nodes, content),
+            Children::Text(text) => text.trim().is_empty(),
+        }
+    }
+
+    fn colored_chars(self, content: &HashMap<&str, String>) -> usize {
+        match self {
+            Children::Nodes(nodes) => colored_chars(nodes, content),
+            Children::Text(text) => text.chars().filter(|c| is_colored(*c)).count(),
+        }
+    }
+}
+
+/// Width of the text in columns, with the `content` of placeholders and named sections. Raw text
+/// is not counted, and the widest branch is taken for status conditionals.
+fn width(nodes: &[Node], content: &HashMap<&str, String>) -> usize {
+    nodes
+        .iter()
+        .map(|node| match node {
+            Node::Text(text) => text.width(),
+            Node::Placeholder(name) => content.get(name.as_str()).map_or(0, |text| text.width()),
+            Node::Raw(_) | Node::Fill(_) | Node::Title(_) => 0,
+            Node::Link(_, label) => width(label, content),
+            Node::Section(section, children) if is_collapsed(section, children, content) => 0,
+            Node::Time(format) => format_time(format).width(),
+            Node::Section(section, children) => {
+                let width = section_children(section, children, content).width(content);
+                let width = section.max_width.map_or(width, |max| width.min(max));
+
+                2 * section.padding + width.max(section.min_width)
+            }
+            Node::Conditional(Condition::Variable(variable), then, otherwise) => {
+                width(if is_set(variable) { then } else { otherwise }, content)
+            }
+            Node::Conditional(Condition::Status, then, otherwise) => {
+                width(then, content).max(width(otherwise, content))
+            }
+        })
+        .sum()
+}
+
+fn section_children<'a>(
+    section: &Section,
+    children: &'a [Node],
+    content: &'a HashMap<&str, String>,
+) -> Children<'a> {
+    match section.name.as_deref().and_then(|name| content.get(name)) {
+        Some(text) => Children::Text(text),
+        None => Children::Nodes(children),
+    }
+}
+
+fn is_collapsed(section: &Section, children: &[Node], content: &HashMap<&str, String>) -> bool {
+    section.optional && section_children(section, children, content).is_blank(content)
+}
+
+/// Nodes are blank if they have no text except whitespace.
+fn is_blank(nodes: &[Node], content: &HashMap<&str, String>) -> bool {
+    nodes.iter().all(|node| match node {
+        Node::Text(text) => text.trim().is_empty(),
+        Node::Placeholder(name) => content
+            .get(name.as_str())
+            .is_none_or(|text| text.trim().is_empty()),
+        Node::Raw(_) | Node::Fill(_) | Node::Time(_) => false,
+        Node::Title(_) => true,
+        Node::Link(_, label) => is_blank(label, content),
+        Node::Section(section, children) => {
+            is_collapsed(section, children, content)
+                || section_children(section, children, content).is_blank(content)
+        }
+        Node::Conditional(Condition::Variable(variable), then, otherwise) => {
+            is_blank(if is_set(variable) { then } else { otherwise }, content)
+        }
+        Node::Conditional(Condition::Status, then, otherwise) => {
+            is_blank(then, content) && is_blank(otherwise, content)
+        }
+    })
+}
+
+/// Number of characters colored by the section's own coloring, nested sections are not counted.
+fn colored_chars(nodes: &[Node], content: &HashMap<&str, String>) -> usize {
+    let count = |text: &str| text.chars().filter(|c| is_colored(*c)).count();
+
+    nodes
+        .iter()
+        .map(|node| match node {
+            Node::Text(text) => count(text),
+            Node::Placeholder(name) => content.get(name.as_str()).map_or(0, |text| count(text)),
+            Node::Link(_, label) => colored_chars(label, content),
+            Node::Conditional(Condition::Variable(variable), then, otherwise) => {
+                colored_chars(if is_set(variable) { then } else { otherwise }, content)
+            }
+            Node::Conditional(Condition::Status, then, otherwise) => {
+                colored_chars(then, content).max(colored_chars(otherwise, content))
+            }
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(unix)]
+extern "C" {
+    fn strftime(
+        s: *mut libc::c_char,
+        max: libc::size_t,
+        format: *const libc::c_char,
+        tm: *const libc::tm,
+    ) -> libc::size_t;
+}
+
+/// Formats the current local time with strftime.
+#[cfg(unix)]
+fn format_time(format: &str) -> String {
+    let format = match std::ffi::CString::new(format) {
+        Ok(format) => format,
+        Err(_) => return String::new(),
+    };
+
+    let mut buffer = [0u8; 256];
+
+    // SAFETY: `tm` is filled by `localtime_r` before use, and `strftime` writes at most
+    // `buffer.len()` bytes, returning the number of bytes written
+    let length = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+
+        strftime(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+            format.as_ptr(),
+            &tm,
+        )
+    };
+
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+/// Time can't be formatted without strftime, the format is shown as is.
+#[cfg(not(unix))]
+fn format_time(format: &str) -> String {
+    format.to_string()
+}
+
+/// Name of the host with gethostname.
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+
+    // SAFETY: `gethostname` writes at most `buffer.len()` bytes, the name is read up to the null
+    // terminator, or to the end of the buffer if it was truncated
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return String::new();
+    }
+
+    let length = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+/// Name of the host from the environment, Windows sets it for every process.
+#[cfg(not(unix))]
+fn hostname() -> String {
+    env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(all(windows, feature = "windows-vt"))]
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetStdHandle(handle: u32) -> *mut std::ffi::c_void;
+    fn GetConsoleMode(console: *mut std::ffi::c_void, mode: *mut u32) -> i32;
+    fn SetConsoleMode(console: *mut std::ffi::c_void, mode: u32) -> i32;
+}
+
+/// Enables processing of escape codes on the console of the standard output, so that printed
+/// prompts are rendered in conhost. Fails if the output isn't a console or Windows is older than
+/// Windows 10.
+#[cfg(all(windows, feature = "windows-vt"))]
+pub fn enable_virtual_terminal() -> io::Result<()> {
+    const STD_OUTPUT_HANDLE: u32 = -11i32 as u32;
+    const ENABLE_VIRTUAL_TERMINAL_PROCESSING: u32 = 0x0004;
+
+    // SAFETY: the handle is checked before use, and the mode is written by `GetConsoleMode`
+    // before it's read
+    unsafe {
+        let console = GetStdHandle(STD_OUTPUT_HANDLE);
+        if console.is_null() || console as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut mode = 0;
+        if GetConsoleMode(console, &mut mode) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if SetConsoleMode(console, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whitespace is left uncolored, and so are zero-width characters, which keeps combining marks
+/// together with the character they belong to.
+fn is_colored(c: char) -> bool {
+    !c.is_whitespace() && c.width().unwrap_or(0) > 0
+}
+
+fn single_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Quotes the text as a double-quoted string for Nushell and Lua.
+fn nu_quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Quotes the text for Fish, where only quotes and backslashes are escaped in single quotes.
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Removes whitespace around the first and the last text nodes.
+fn trim_nodes(nodes: &mut Vec<Node>) {
+    if let Some(Node::Text(text)) = nodes.first_mut() {
+        *text = text.trim_start().to_string();
+    }
+
+    if let Some(Node::Text(text)) = nodes.last_mut() {
+        *text = text.trim_end().to_string();
+    }
+
+    nodes.retain(|node| !matches!(node, Node::Text(text) if text.is_empty()));
+}
+
+/// Nodes have no line breaks and fills, including the nested ones.
+fn is_single_line(nodes: &[Node]) -> bool {
+    nodes.iter().all(|node| match node {
+        Node::Text(text) | Node::Raw(text) => !text.contains('\n'),
+        Node::Fill(_) => false,
+        Node::Time(_) | Node::Title(_) | Node::Placeholder(_) => true,
+        Node::Section(_, children) | Node::Link(_, children) => is_single_line(children),
+        Node::Conditional(_, then, otherwise) => is_single_line(then) && is_single_line(otherwise),
+    })
+}
+
+/// Nodes of the continuation prompt, the ellipsis in the last top-level section and the trailing
+/// whitespace of the template.
+fn continuation_nodes(nodes: &[Node], ellipsis: &str) -> Vec<Node> {
+    let last = nodes.iter().rev().find_map(|node| match node {
+        Node::Section(section, _) => Some(section),
+        _ => None,
+    });
+
+    let mut continuation = vec![match last {
+        Some(last) => {
+            let section = Section {
+                style: last.style,
+                padding: last.padding,
+                coloring: last.coloring,
+                ..Section::default()
+            };
+
+            Node::Section(section, vec![Node::Text(ellipsis.to_string())])
+        }
+        None => Node::Text(ellipsis.to_string()),
+    }];
+
+    if let Some(Node::Text(text)) = nodes.last() {
+        let trimmed = text.trim_end();
+        continuation.push(Node::Text(text[trimmed.len()..].to_string()));
+    }
+
+    continuation
+}
+
+/// Replaces placeholders with their values, or leaves them out, and joins the adjacent text.
+fn fill_placeholders(nodes: Vec<Node>, content: &HashMap<&str, String>) -> Vec<Node> {
+    let mut filled = Vec::new();
+    let mut text = String::new();
+
+    for node in nodes {
+        let node = match node {
+            Node::Text(value) => {
+                text.push_str(&value);
+                continue;
+            }
+            Node::Placeholder(name) => {
+                text.extend(content.get(name.as_str()).map(String::as_str));
+                continue;
+            }
+            Node::Section(section, children) => {
+                Node::Section(section, fill_placeholders(children, content))
+            }
+            Node::Link(url, label) => Node::Link(url, fill_placeholders(label, content)),
+            Node::Title(children) => Node::Title(fill_placeholders(children, content)),
+            Node::Conditional(condition, then, otherwise) => Node::Conditional(
+                condition,
+                fill_placeholders(then, content),
+                fill_placeholders(otherwise, content),
+            ),
+            node @ (Node::Raw(_) | Node::Fill(_) | Node::Time(_)) => node,
+        };
+
+        push_text(&mut filled, &mut text);
+        filled.push(node);
+    }
+
+    push_text(&mut filled, &mut text);
+    filled
+}
+
+/// Removes characters that would be taken for fills, so that a NUL in the template or in the
+/// content doesn't turn into a fill.
+fn without_fill_markers(text: &str) -> Cow<'_, str> {
+    match text.contains(FILL_MARKER) {
+        true => Cow::Owned(text.replace(FILL_MARKER, "")),
+        false => Cow::Borrowed(text),
+    }
+}
+
+fn push_text(nodes: &mut Vec<Node>, text: &mut String) {
+    if !text.is_empty() {
+        nodes.push(Node::Text(std::mem::take(text)));
+    }
+}
+
+impl Deref for Buffer<'_> {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.output
+    }
+}
+
+impl DerefMut for Buffer<'_> {
+    fn deref_mut(&mut self) -> &mut String {
+        &mut self.output
+    }
+}
+
+impl<'n> Buffer<'n> {
+    /// Marks the output pushed since `start` as text.
+    fn mark_text(&mut self, start: usize) {
+        let end = self.output.len();
+
+        match self.texts.last_mut() {
+            _ if start == end => (),
+            Some((last, None)) if last.end == start => last.end = end,
+            _ => self.texts.push((start..end, None)),
+        }
+    }
+
+    /// Marks the output pushed since `start` as the text of the template if it's the same.
+    fn mark_template_text(&mut self, start: usize, text: &'n str) {
+        if text.is_empty() || self.output.get(start..) != Some(text) {
+            return;
+        }
+
+        while let Some((last, _)) = self.texts.last_mut() {
+            if last.start < start {
+                last.end = last.end.min(start);
+                break;
+            }
+
+            self.texts.pop();
+        }
+
+        self.texts.push((start..self.output.len(), Some(text)));
+    }
+
+    /// Appends a branch that was rendered separately.
+    fn append(&mut self, branch: Buffer<'n>) {
+        let offset = self.output.len();
+
+        self.output.push_str(&branch.output);
+        self.texts.extend(
+            branch
+                .texts
+                .into_iter()
+                .map(|(span, text)| (span.start + offset..span.end + offset, text)),
+        );
+        self.mixed |= branch.mixed;
+    }
+
+    fn clear(&mut self) {
+        self.output.clear();
+        self.texts.clear();
+        self.mixed = false;
+    }
+}
+
+impl<'n> Sink<'_, 'n> {
+    /// Borrows the sink for a shorter time, like the lifetime of the encoder of the renderer.
+    fn reborrow(&mut self) -> Sink<'_, 'n> {
+        match self {
+            Sink::Writer(out) => Sink::Writer(&mut **out),
+            Sink::Chunks(chunks) => Sink::Chunks(chunks),
+        }
+    }
+
+    fn write(&mut self, buffer: &Buffer<'n>) -> fmt::Result {
+        let chunks = match self {
+            Sink::Writer(out) => return out.write_str(buffer),
+            Sink::Chunks(chunks) => chunks,
+        };
+
+        if buffer.mixed {
+            chunks.push(Chunk::Mixed(Cow::Owned(buffer.output.clone())));
+            return Ok(());
+        }
+
+        let mut end = 0;
+
+        for (span, text) in &buffer.texts {
+            push_copied(chunks, &buffer[end..span.start], false);
+
+            match text {
+                Some(text) => chunks.push(Chunk::Text(Cow::Borrowed(text))),
+                None => push_copied(chunks, &buffer[span.clone()], true),
+            }
+
+            end = span.end;
+        }
+
+        push_copied(chunks, &buffer[end..], false);
+        Ok(())
+    }
+}
+
+/// Pushes a copy of the text or escapes, joined with the previous chunk if it's of the same kind
+/// and copied too.
+fn push_copied(chunks: &mut Vec<Chunk>, output: &str, text: bool) {
+    if output.is_empty() {
+        return;
+    }
+
+    match (chunks.last_mut(), text) {
+        (Some(Chunk::Text(Cow::Owned(last))), true)
+        | (Some(Chunk::Escape(Cow::Owned(last))), false) => last.push_str(output),
+        (_, true) => chunks.push(Chunk::Text(Cow::Owned(output.to_string()))),
+        (_, false) => chunks.push(Chunk::Escape(Cow::Owned(output.to_string()))),
+    }
+}
+
+impl<'n> Renderer<'_, 'n> {
+    fn render_nodes(&mut self, nodes: &'n [Node]) {
+        for node in nodes {
+            if self.budget == Some(0) {
+                break;
+            }
+
+            self.stream();
+
+            match node {
+                Node::Text(text) => {
+                    self.flush();
+                    self.stream();
+                    self.push_template_text(text);
+                }
+                Node::Raw(text) => {
+                    self.flush();
+                    self.push_raw(text);
+                }
+                Node::Placeholder(name) => {
+                    if let Some(text) = self.content.get(name.as_str()) {
+                        self.flush();
+                        self.stream();
+                        self.push_template_text(text);
+                    }
+                }
+                Node::Fill(fill) => {
+                    self.flush();
+                    self.buffer.push(FILL_MARKER);
+                    self.fills.push(match *fill {
+                        FILL_MARKER => ' ',
+                        fill => fill,
+                    });
+                }
+                Node::Time(format) => {
+                    self.flush();
+                    let format = &*without_fill_markers(format);
+
+                    match self.output.shell {
+                        Shell::Zsh if !self.static_time => {
+                            self.columns += format_time(format).width();
+                            self.buffer.push_str(&format!("%D{{{}}}", format));
+                        }
+                        Shell::Bash if !self.static_time => {
+                            self.columns += format_time(format).width();
+                            self.buffer.push_str(&format!("\\D{{{}}}", format));
+                        }
+                        Shell::Ksh | Shell::Oils if !self.static_time => {
+                            self.columns += format_time(format).width();
+                            let format = single_quote(&format!("+{}", format));
+                            self.buffer.push_str(&format!("$(date {})", format));
+                        }
+                        Shell::Elvish if !self.static_time => {
+                            self.columns += format_time(format).width();
+                            let format = format!("+{}", format).replace('\'', "''");
+                            self.buffer.push_str(&format!("\"(e:date '{}')\"", format));
+                        }
+                        // Status line formats are passed through strftime by tmux itself
+                        Shell::Tmux if !self.static_time => {
+                            self.columns += format_time(format).width();
+                            self.buffer.push_str(format);
+                        }
+                        Shell::Vim if !self.static_time => {
+                            self.columns += format_time(format).width();
+                            let format = format.replace('\'', "''");
+                            self.buffer
+                                .push_str(&format!("%{{strftime('{}')}}", format));
+                        }
+                        Shell::Fish if !self.static_time => {
+                            self.columns += format_time(format).width();
+                            let format = fish_quote(&format!("+{}", format));
+                            self.buffer.push_str(&format!("'(date {})'", format));
+                        }
+                        Shell::PowerShell if !self.static_time => {
+                            self.columns += format_time(format).width();
+                            let format = format.replace('\'', "''");
+                            self.buffer
+                                .push_str(&format!("$(Get-Date -UFormat '{}')", format));
+                        }
+                        Shell::Nu if !self.static_time => {
+                            self.columns += format_time(format).width();
+                            let format = nu_quote(format);
+                            self.buffer
+                                .push_str(&format!("(date now | format date {})", format));
+                        }
+                        Shell::Clink if !self.static_time => {
+                            self.columns += format_time(format).width();
+                            let format = nu_quote(format);
+                            self.buffer
+                                .push_str(&format!("\" .. os.date({}) .. \"", format));
+                        }
+                        _ => self.push_text(&format_time(format)),
+                    }
+                }
+                Node::Link(_, label) if self.output.plain || self.output.html => {
+                    self.render_nodes(label)
+                }
+                Node::Link(url, label) => {
+                    self.flush();
+                    push_hyperlink(
+                        &mut self.buffer,
+                        &without_fill_markers(url),
+                        self.output.shell,
+                    );
+                    self.render_nodes(label);
+                    push_hyperlink(&mut self.buffer, "", self.output.shell);
+                }
+                Node::Section(section, children) => self.render_section(section, children),
+                Node::Conditional(Condition::Variable(variable), then, otherwise) => {
+                    if is_set(variable) {
+                        self.render_nodes(then);
+                    } else {
+                        self.render_nodes(otherwise);
+                    }
+                }
+                Node::Conditional(Condition::Status, then, otherwise) => {
+                    self.render_status(then, otherwise);
+                }
+                // Status lines can't set the title of the terminal
+                Node::Title(_)
+                    if self.output.plain
+                        || self.output.html
+                        || self.output.shell.is_status_line() => {}
+                Node::Title(title) => {
+                    self.flush();
+
+                    // The title is not printed on the line
+                    let columns = self.columns;
+                    push_non_printing_start(&mut self.buffer, self.output.shell);
+                    self.buffer.push_str("\x1b]0;");
+                    self.in_escape = true;
+                    self.render_title(title);
+                    self.in_escape = false;
+                    self.buffer.push('\x07');
+                    push_non_printing_end(&mut self.buffer, self.output.shell);
+                    self.columns = columns;
+                }
+            }
+        }
+    }
+
+    /// Renders the section with its own style, unless it's optional and blank. Named sections are
+    /// rendered with their content if it's given.
+    fn render_section(&mut self, section: &Section, children: &'n [Node]) {
+        if is_collapsed(section, children, self.content) {
+            return;
+        }
+
+        let children = section_children(section, children, self.content);
+
+        self.transition(OPEN_BRACE);
+        self.styles.push(section.style);
+        self.push_padding(section.padding);
+
+        let total = children.colored_chars(self.content);
+        let coloring = std::mem::replace(
+            &mut self.coloring,
+            section.coloring.map(|coloring| ColoringState {
+                coloring,
+                index: 0,
+                total,
+            }),
+        );
+
+        self.render_content(section, children);
+        self.coloring = coloring;
+        self.stale |= section.coloring.is_some();
+
+        self.push_padding(section.padding);
+        self.transition(CLOSE_BRACE);
+        self.styles.pop();
+    }
+
+    /// Renders nodes of the title without styles, fills are left out.
+    fn render_title(&mut self, nodes: &'n [Node]) {
+        for node in nodes {
+            match node {
+                Node::Text(text) => self.push_literal(text),
+                Node::Placeholder(name) => {
+                    if let Some(text) = self.content.get(name.as_str()) {
+                        self.push_literal(text);
+                    }
+                }
+                Node::Raw(text) => self.push_raw(text),
+                Node::Fill(_) | Node::Title(_) => (),
+                Node::Section(_, children) | Node::Link(_, children) => self.render_title(children),
+                Node::Conditional(Condition::Variable(variable), then, otherwise) => {
+                    self.render_title(if is_set(variable) { then } else { otherwise })
+                }
+                Node::Time(_) | Node::Conditional(Condition::Status, _, _) => {
+                    self.render_nodes(std::slice::from_ref(node))
+                }
+            }
+        }
+    }
+
+    /// Renders children of the section, truncating them if they don't fit into the maximum width
+    /// and aligning them if they are shorter than the minimum.
+    fn render_content(&mut self, section: &Section, children: Children<'n>) {
+        let content_width = children.width(self.content);
+        let truncate = section.max_width.filter(|max| content_width > *max);
+
+        if truncate.is_none() && content_width >= section.min_width {
+            return self.render_children(children);
+        }
+
+        let ellipsis = match truncate {
+            Some(_) => section.ellipsis.as_deref().unwrap_or(ELLIPSIS),
+            None => "",
+        };
+
+        let available = truncate.map_or(content_width, |max| max.saturating_sub(ellipsis.width()));
+        let limit = self
+            .budget
+            .map_or(available, |budget| budget.min(available));
+
+        self.flush();
+
+        let budget = self.budget.replace(limit);
+        let content = self.render_branch(children);
+        let used = limit - self.budget.unwrap_or(0);
+        self.budget = budget.map(|budget| budget - used);
+
+        let fill = section.min_width.saturating_sub(used + ellipsis.width());
+        let (before, after) = match section.align {
+            Align::Left => (0, fill),
+            Align::Right => (fill, 0),
+            Align::Center => (fill / 2, fill - fill / 2),
+        };
+
+        self.push_text(&" ".repeat(before));
+        self.buffer.append(content);
+        self.push_text(ellipsis);
+        self.push_text(&" ".repeat(after));
+    }
+
+    fn render_children(&mut self, children: Children<'n>) {
+        match children {
+            Children::Nodes(nodes) => self.render_nodes(nodes),
+            Children::Text(text) => {
+                self.flush();
+                self.stream();
+                self.push_template_text(text);
+            }
+        }
+    }
+
+    /// Pushes text of the template or the content, which chunks borrow if it's not escaped.
+    fn push_template_text(&mut self, text: &'n str) {
+        let start = self.buffer.len();
+        self.push_text(text);
+        self.buffer.mark_template_text(start, text);
+    }
+
+    fn push_text(&mut self, text: &str) {
+        let mut lines = text.split('\n');
+
+        self.push_line(lines.next().unwrap_or_default());
+
+        for line in lines {
+            self.break_line();
+            self.push_line(line);
+        }
+    }
+
+    /// Pushes text without line breaks, cutting it to the remaining budget and coloring it
+    /// character by character if needed.
+    fn push_line(&mut self, text: &str) {
+        if self.budget.is_none() && self.coloring.is_none() {
+            self.columns += text.width();
+            return self.push_literal(text);
+        }
+
+        for c in text.chars() {
+            let width = c.width().unwrap_or(0);
+
+            if let Some(budget) = self.budget.as_mut() {
+                if width > *budget {
+                    *budget = 0;
+                    break;
+                }
+
+                *budget -= width;
+            }
+
+            if let Some(coloring) = self.coloring.as_mut().filter(|_| is_colored(c)) {
+                let color = coloring.next_color();
+                push_escape_code(&mut self.buffer, Escape::Foreground(color), self.output);
+            }
+
+            self.columns += width;
+            self.push_literal(c.encode_utf8(&mut [0; 4]));
+        }
+    }
+
+    /// Pushes raw text, which is code of the shell in the output of shells that quote it.
+    fn push_raw(&mut self, text: &str) {
+        let text = &*without_fill_markers(text);
+
+        match self.output.shell {
+            Shell::Fish => self.buffer.push_str(&format!("'{}'", text)),
+            Shell::Elvish => self.buffer.push_str(&format!("\"{}\"", text)),
+            Shell::Clink => self.buffer.push_str(&format!("\" .. ({}) .. \"", text)),
+            _ if self.output.html => self.buffer.push_str(&escape_xml(text)),
+            _ => self.buffer.push_str(text),
+        }
+    }
+
+    /// Pushes OSC 7 report of the host and the working directory, which the shell expands itself
+    /// where it can.
+    fn push_cwd_report(&mut self) {
+        let location = match self.output.shell {
+            Shell::Tmux | Shell::Screen | Shell::Vim | Shell::Starship => return,
+            _ if self.output.plain || self.output.html => return,
+            Shell::Zsh | Shell::Tcsh => "%M%/",
+            Shell::Bash | Shell::Oils => "\\H${PWD}",
+            Shell::Ksh => "$(hostname)${PWD}",
+            Shell::Fish => "$hostname\"$PWD\"",
+            Shell::PowerShell => {
+                "$([Environment]::MachineName)/$($PWD.ProviderPath -replace '\\\\', '/')"
+            }
+            Shell::Nu => "(sys host | get hostname)($env.PWD)",
+            Shell::Elvish => "(e:hostname)$pwd",
+            Shell::Clink => {
+                "(os.getenv(\"COMPUTERNAME\") or \"\") .. \"/\" .. os.getcwd():gsub(\"\\\\\", \"/\")"
+            }
+            Shell::Xonsh | Shell::Readline | Shell::Custom(_) | Shell::None => "",
+        };
+
+        push_non_printing_start(&mut self.buffer, self.output.shell);
+        self.buffer.push_str("\x1b]7;file://");
+
+        match location {
+            "" => {
+                let cwd = env::current_dir().unwrap_or_default();
+                self.in_escape = true;
+                self.push_literal(&format!("{}{}", hostname(), cwd.display()));
+                self.in_escape = false;
+            }
+            location => self.push_raw(location),
+        }
+
+        self.buffer.push('\x07');
+        push_non_printing_end(&mut self.buffer, self.output.shell);
+    }
+
+    /// Pushes the text escaping it for the shell, unless shell escapes in the text are allowed.
+    fn push_literal(&mut self, text: &str) {
+        let text = &*without_fill_markers(text);
+        let start = self.buffer.len();
+
+        match self.output.shell {
+            _ if self.output.html => self.buffer.push_str(&escape_xml(text)),
+            Shell::Bash | Shell::Oils | Shell::Zsh if self.output.shell_escapes => {
+                self.buffer.push_str(text)
+            }
+            // Branches are single-quoted in the code, which only sees the decoding of the prompt
+            Shell::Bash | Shell::Oils if self.in_branch => {
+                self.buffer.push_str(&text.replace('\\', "\\\\"))
+            }
+            shell => push_literal(&mut self.buffer, text, shell),
+        }
+
+        if !self.in_escape {
+            self.buffer.mark_text(start);
+        }
+    }
+
+    fn push_padding(&mut self, padding: usize) {
+        if padding > 0 {
+            self.flush();
+            self.push_text(&" ".repeat(padding));
+        }
+    }
+
+    /// Styles are reset before the line break, so that the background doesn't bleed to the
+    /// terminal edge when it scrolls, and are restored on the next line.
+    fn break_line(&mut self) {
+        let style = self.styles.last().copied();
+
+        if style.is_some() {
+            push_escape_code(&mut self.buffer, Escape::Reset, self.output);
+        }
+
+        self.resolve_fills();
+        self.stream();
+
+        let start = self.buffer.len();
+        self.buffer.push('\n');
+        self.buffer.mark_text(start);
+
+        if let Some(style) = style {
+            push_escape_code(&mut self.buffer, Escape::Foreground(style.fg), self.output);
+            push_escape_code(&mut self.buffer, Escape::Background(style.bg), self.output);
+            push_attributes(
+                &mut self.buffer,
+                Attributes::default(),
+                style.attrs,
+                self.output,
+            );
+        }
+    }
+
+    /// Writes the output so far, unless something later can change it: fills are resolved at the
+    /// end of the line, branches are combined into a conditional, and some shells convert or wrap
+    /// the prompt as a whole.
+    fn stream(&mut self) {
+        if !self.streaming || self.captures > 0 || !self.fills.is_empty() {
+            return;
+        }
+
+        if self.written.is_ok() && !self.buffer.is_empty() {
+            self.written = self.sink.write(&self.buffer);
+        }
+
+        self.buffer.clear();
+    }
+
+    /// Completes the output and writes the rest of it.
+    fn finish(mut self) -> fmt::Result {
+        self.flush();
+        self.resolve_fills();
+
+        if self.report_cwd {
+            self.push_cwd_report();
+        }
+
+        if self.semantic_marks {
+            push_semantic_mark(&mut self.buffer, 'B', self.output);
+        }
+
+        let output = match self.output.shell {
+            // Status is saved first, as every command substitution changes it
+            Shell::Fish if self.status => format!(
+                "set -l bubble_status $status; printf '%s' '{}'",
+                self.buffer.as_str()
+            ),
+            Shell::Fish => format!("printf '%s' '{}'", self.buffer.as_str()),
+            Shell::Vim => vim_statusline(&self.buffer),
+            Shell::Starship => starship_format(&self.buffer),
+            Shell::Elvish => {
+                let buffer = self.buffer.replace('\x1b', "\\e").replace('\x07', "\\a");
+                format!("put \"{}\"", buffer)
+            }
+            Shell::PowerShell => {
+                let (escape, bell) = match self.legacy_escapes {
+                    true => ("$([char]27)", "$([char]7)"),
+                    false => ("`e", "`a"),
+                };
+
+                let buffer = self.buffer.replace('\x1b', escape).replace('\x07', bell);
+
+                match self.status {
+                    true => format!("$bubble_status = $?; \"{}\"", buffer),
+                    false => format!("\"{}\"", buffer),
+                }
+            }
+            Shell::Nu => {
+                let buffer = self.buffer.replace('\x1b', "\\e").replace('\x07', "\\a");
+                format!("$\"{}\"", buffer)
+            }
+            // Decimal escapes are supported by every Lua version, unlike `\x1b`
+            Shell::Clink => {
+                let buffer = self
+                    .buffer
+                    .replace('\x1b', "\\027")
+                    .replace('\x07', "\\007")
+                    .replace('\n', "\\n");
+
+                format!(
+                    "local bubble = clink.promptfilter(1)\nfunction bubble:filter()\n    return \"{}\"\nend",
+                    buffer
+                )
+            }
+            _ => {
+                self.written?;
+                return self.sink.write(&self.buffer);
+            }
+        };
+
+        // Text and escapes are converted together
+        let output = Buffer {
+            output,
+            texts: Vec::new(),
+            mixed: true,
+        };
+
+        self.written?;
+        self.sink.write(&output)
+    }
+
+    /// Replaces fills of the current line with shell-side code that computes their width, and
+    /// starts counting columns of the next line.
+    fn resolve_fills(&mut self) {
+        let columns = std::mem::take(&mut self.columns);
+        let fills = std::mem::take(&mut self.fills);
+
+        if fills.is_empty() {
+            return;
+        }
+
+        let count = fills.len();
+        let mut parts = self.buffer.split(FILL_MARKER);
+        let mut buffer = parts.next().unwrap_or_default().to_string();
+
+        for (part, fill) in parts.zip(fills) {
+            buffer.push_str(&self.fill_code(fill, columns, count));
+            buffer.push_str(part);
+        }
+
+        self.buffer = Buffer {
+            output: buffer,
+            texts: Vec::new(),
+            mixed: true,
+        };
+    }
+
+    /// Code of a fill that repeats the character to take its share of the columns left.
+    fn fill_code(&self, fill: char, columns: usize, count: usize) -> String {
+        match self.output.shell {
+            // Percents produced by the expansion are decoded afterwards, so twice as many are padded
+            Shell::Zsh if fill == '%' => format!(
+                "${{(pl:2*((COLUMNS-{})/{})::\\x25:)}}",
+                columns, count
+            ),
+            // Characters special for the prompt are written as print escapes with the `p` flag
+            Shell::Zsh if "\\$`\"(){}:".contains(fill) => format!(
+                "${{(pl:(COLUMNS-{})/{}::\\x{:02x}:)}}",
+                columns, count, fill as u32
+            ),
+            Shell::Zsh => format!("${{(l:(COLUMNS-{})/{}::{}:)}}", columns, count, fill),
+            // Screen pads the string to the display width with spaces
+            Shell::Screen | Shell::Vim if fill == ' ' => "%=".to_string(),
+            Shell::Bash | Shell::Ksh | Shell::Oils if fill == ' ' => {
+                format!("$(printf '%*s' $(((COLUMNS-{})/{})) '')", columns, count)
+            }
+            // Special characters are passed in a single-quoted variable, which is expanded literally
+            // in the replacement. Bash decodes backslashes of the prompt before running the code,
+            // and Ksh replaces `!` in the output with the history number.
+            Shell::Bash | Shell::Ksh | Shell::Oils if "\\$`\"'}&!".contains(fill) => {
+                let fill = match self.output.shell {
+                    Shell::Ksh => single_quote(&fill.to_string().replace('!', "!!")),
+                    _ => single_quote(&fill.to_string()).replace('\\', "\\\\"),
+                };
+
+                format!(
+                    "$(c={}; s=$(printf '%*s' $(((COLUMNS-{})/{})) ''); printf %s \"${{s// /\"$c\"}}\")",
+                    fill, columns, count
+                )
+            }
+            Shell::Bash | Shell::Ksh | Shell::Oils => format!(
+                "$(s=$(printf '%*s' $(((COLUMNS-{})/{})) ''); printf %s \"${{s// /{}}}\")",
+                columns, count, fill
+            ),
+            Shell::Fish => format!(
+                "'(string repeat -n (math \"max(0, floor(($COLUMNS - {}) / {}))\") {})'",
+                columns,
+                count,
+                fish_quote(&fill.to_string())
+            ),
+            Shell::PowerShell => format!(
+                "$('{}' * [math]::Max(0, [math]::Floor(($Host.UI.RawUI.WindowSize.Width - {}) / {})))",
+                fill.to_string().replace('\'', "''"),
+                columns,
+                count
+            ),
+            Shell::Nu => format!(
+                "(\"\" | fill -c {} -w ([0 (((term size).columns - {}) / {} | math floor)] | math max))",
+                nu_quote(&fill.to_string()),
+                columns,
+                count
+            ),
+            _ => {
+                let terminal = env::var("COLUMNS")
+                    .ok()
+                    .and_then(|columns| columns.parse::<usize>().ok())
+                    .unwrap_or(0);
+
+                let mut code = String::new();
+                let fill = fill.to_string().repeat(terminal.saturating_sub(columns) / count);
+                push_literal(&mut code, &fill, self.output.shell);
+                code
+            }
+        }
+    }
+
+    /// Emits both branches wrapped into a shell-side check of the last exit status. Without a
+    /// shell the status is assumed to be successful.
+    fn render_status(&mut self, then: &'n [Node], otherwise: &'n [Node]) {
+        self.flush();
+
+        let budget = self.budget;
+        let columns = self.columns;
+        let in_branch = std::mem::replace(&mut self.in_branch, true);
+        let then = self.render_branch(Children::Nodes(then)).output;
+        let then_budget = std::mem::replace(&mut self.budget, budget);
+        let then_columns = std::mem::replace(&mut self.columns, columns);
+        let otherwise = self.render_branch(Children::Nodes(otherwise)).output;
+        self.in_branch = in_branch;
+        self.budget = self.budget.min(then_budget);
+        self.columns = self.columns.max(then_columns);
+
+        match self.output.shell {
+            Shell::Zsh => {
+                let then = then.replace(')', "%)");
+                let otherwise = otherwise.replace(')', "%)");
+
+                let delimiter = ['.', '|', '/', ':', ',', ';', '!', '~']
+                    .iter()
+                    .copied()
+                    .find(|c| !then.contains(*c) && !otherwise.contains(*c))
+                    .unwrap_or('.');
+
+                self.buffer
+                    .push_str(&format!("%(?{d}{}{d}{})", then, otherwise, d = delimiter));
+            }
+            Shell::Bash | Shell::Ksh | Shell::Oils => {
+                self.buffer.push_str(&format!(
+                    "$(if [ $? = 0 ]; then printf %s {}; else printf %s {}; fi)",
+                    single_quote(&then),
+                    single_quote(&otherwise)
+                ));
+            }
+            Shell::Fish => {
+                self.status = true;
+                self.buffer.push_str(&format!(
+                    "'(if test $bubble_status = 0; printf '%s' '{}'; else; printf '%s' '{}'; end)'",
+                    then, otherwise
+                ));
+            }
+            Shell::PowerShell => {
+                self.status = true;
+                self.buffer.push_str(&format!(
+                    "$(if ($bubble_status) {{ \"{}\" }} else {{ \"{}\" }})",
+                    then, otherwise
+                ));
+            }
+            Shell::Nu => {
+                self.buffer.push_str(&format!(
+                    "(if $env.LAST_EXIT_CODE == 0 {{ $\"{}\" }} else {{ $\"{}\" }})",
+                    then, otherwise
+                ));
+            }
+            _ => self.buffer.push_str(&then),
+        }
+    }
+
+    /// Renders nodes separately from the main buffer, starting with the current style.
+    fn render_branch(&mut self, children: Children<'n>) -> Buffer<'n> {
+        let buffer = std::mem::take(&mut self.buffer);
+
+        self.captures += 1;
+        self.render_children(children);
+        self.flush();
+        self.captures -= 1;
+
+        std::mem::replace(&mut self.buffer, buffer)
+    }
+
+    /// Consecutive braces of the same kind produce a single transition, from the style that was
+    /// active before the first brace to the style after the last one.
+    fn transition(&mut self, brace: char) {
+        match self.pending {
+            Some((pending, _)) if pending == brace => (),
+            // Closing is emitted along with the opening, adjacent sections might need a divider
+            Some((CLOSE_BRACE, Some(closed))) => {
+                self.closed = Some(closed);
+                self.pending = Some((brace, self.styles.last().copied()));
+            }
+            _ => {
+                self.flush();
+                self.pending = Some((brace, self.styles.last().copied()));
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let (brace, style) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+
+        if let Some(closed) = self.closed.take() {
+            if self.push_divider(&closed) {
+                self.stale = false;
+                return;
+            }
+
+            self.columns += push_brace(
+                &mut self.buffer,
+                CLOSE_BRACE,
+                Some(&closed),
+                style.as_ref(),
+                self.caps,
+                self.direction,
+                self.output,
+            );
+        }
+
+        self.columns += push_brace(
+            &mut self.buffer,
+            brace,
+            style.as_ref(),
+            self.styles.last(),
+            self.caps,
+            self.direction,
+            self.output,
+        );
+
+        // Transition between equal styles emits nothing, so the foreground is restored here
+        if std::mem::take(&mut self.stale) && style.as_ref() == self.styles.last() {
+            if let Some(style) = self.styles.last() {
+                push_escape_code(&mut self.buffer, Escape::Foreground(style.fg), self.output);
+            }
+        }
+    }
+
+    /// Draws a thin divider in place of the caps between the closed section and the opened one
+    /// if they share the background, returns whether it was drawn.
+    fn push_divider(&mut self, closed: &Style) -> bool {
+        let next = match self.styles.last() {
+            Some(next) if next.bg == closed.bg && !closed.bg.is_default() => *next,
+            _ => return false,
+        };
+
+        let thin = match closed.separator.thin {
+            Some(thin) if next.separator.thin.is_some() => thin,
+            _ => return false,
+        };
+
+        let thin = match closed.direction.unwrap_or(self.direction) {
+            Direction::Right => thin,
+            Direction::Left => mirror_glyph(thin),
+        };
+
+        push_escape_code(&mut self.buffer, Escape::Foreground(closed.fg), self.output);
+        self.columns += push_glyph(&mut self.buffer, thin, self.output);
+
+        push_escape_code(&mut self.buffer, Escape::Foreground(next.fg), self.output);
+        push_attributes(&mut self.buffer, closed.attrs, next.attrs, self.output);
+
+        true
+    }
+}
+
+/// Parses `@name=color`, `%name=fg,bg,attrs`, `{default:fg,bg,attrs}` and `{caps:glyphs}`
+/// declarations at the start of the template, returns them with the rest of the template.
+/// Whitespace after each declaration is dropped. When errors are collected, invalid declarations
+/// are skipped, and variables and styles that fail to parse are still defined with the default
+/// colors so that their uses don't fail too.
+fn parse_header<'t>(
+    template: &'t str,
+    values: Option<&HashMap<&str, String>>,
+    mut errors: Option<&mut Vec<Error>>,
+) -> Result<(Definitions, &'t str), Error> {
+    let locate = |error: ErrorKind, part: &str| Error::from(error).at(Span::of(template, part));
+
+    let mut definitions = Definitions {
+        values: values.map(|values| {
+            values
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect()
+        }),
+        ..Definitions::default()
+    };
+    let mut rest = match parse_pragma(template) {
+        Ok((version, rest)) => {
+            definitions.version = version;
+            rest
+        }
+        Err(error) => {
+            let pragma = braced(template);
+            recover(errors.as_deref_mut(), locate(error, pragma))?;
+            template[pragma.len()..].trim_start()
+        }
+    };
+
+    loop {
+        if let Some(declaration) = rest.strip_prefix(DEFAULT_STYLE) {
+            let end = match declaration.find(CLOSE_BRACE) {
+                Some(end) => end,
+                None => return Err(locate(ErrorKind::UnbalancedBraces, braced(rest))),
+            };
+
+            match section_from_meta(&split_meta(&declaration[..end]), None, &definitions) {
+                Ok(mut section) => {
+                    section.style.separator = NO_SEPARATOR;
+                    definitions.default = Some(section);
+                }
+                Err(error) => recover(errors.as_deref_mut(), locate(error, braced(rest)))?,
+            }
+
+            rest = declaration[end + 1..].trim_start();
+            continue;
+        }
+
+        if let Some(declaration) = rest.strip_prefix(CAPS) {
+            let end = match declaration.find(CLOSE_BRACE) {
+                Some(end) => end,
+                None => return Err(locate(ErrorKind::UnbalancedBraces, braced(rest))),
+            };
+
+            match parse_separator(declaration[..end].trim()) {
+                Ok(caps) => definitions.caps = Some(caps),
+                Err(error) => recover(errors.as_deref_mut(), locate(error, braced(rest)))?,
+            }
+
+            rest = declaration[end + 1..].trim_start();
+            continue;
+        }
+
+        let kind = match rest
+            .chars()
+            .next()
+            .filter(|c| *c == VARIABLE || *c == NAMED_STYLE)
+        {
+            Some(kind) => kind,
+            None => break,
+        };
+
+        let declaration = &rest[kind.len_utf8()..];
+        let end = declaration
+            .find(char::is_whitespace)
+            .unwrap_or(declaration.len());
+
+        let (name, value) = match declaration[..end].split_once('=') {
+            Some((name, value)) if is_variable_name(name) => (name, value),
+            _ => break,
+        };
+
+        let span = &rest[..kind.len_utf8() + end];
+
+        if kind == VARIABLE {
+            let color = match parse_color(value, &definitions) {
+                Ok(color) => color,
+                Err(reason) => {
+                    let error = ErrorKind::InvalidVariable {
+                        name: name.to_string(),
+                        reason,
+                    };
+
+                    recover(errors.as_deref_mut(), locate(error, span))?;
+                    Color::Default
+                }
+            };
+
+            definitions.colors.insert(name.to_string(), color);
+        } else {
+            let section = match section_from_meta(&split_meta(value), None, &definitions) {
+                Ok(section) => section,
+                Err(error) => {
+                    recover(errors.as_deref_mut(), locate(error, span))?;
+                    Section::default()
+                }
+            };
+
+            definitions.styles.insert(name.to_string(), section);
+        }
+
+        rest = declaration[end..].trim_start();
+    }
+
+    Ok((definitions, rest))
+}
+
+/// Parses the leading `{pragma:vN}` declaration, returns the version and the rest of the template.
+fn parse_pragma(template: &str) -> Result<(Option<u32>, &str), ErrorKind> {
+    let declaration = match template.strip_prefix(PRAGMA) {
+        Some(declaration) => declaration,
+        None => return Ok((None, template)),
+    };
+
+    let end = declaration
+        .find(CLOSE_BRACE)
+        .ok_or(ErrorKind::UnbalancedBraces)?;
+    let value = declaration[..end].trim();
+
+    match value
+        .strip_prefix('v')
+        .and_then(|version| version.parse().ok())
+    {
+        Some(version) if (1..=LATEST_VERSION).contains(&version) => {
+            Ok((Some(version), declaration[end + 1..].trim_start()))
+        }
+        _ => Err(ErrorKind::UnsupportedVersion(value.to_string())),
+    }
+}
+
+/// Collects the error if errors are collected, or fails with it. Nesting that is too deep can't be
+/// recovered from, the rest of the template would be misread.
+fn recover(errors: Option<&mut Vec<Error>>, error: Error) -> Result<(), Error> {
+    match errors {
+        Some(errors) if !matches!(error.kind, ErrorKind::NestingTooDeep { .. }) => {
+            errors.push(error);
+            Ok(())
+        }
+        _ => Err(error),
+    }
+}
+
+/// Part of `rest` up to the closing brace, or just the opening one if it's not closed.
+fn braced(rest: &str) -> &str {
+    match rest.find(CLOSE_BRACE) {
+        Some(end) => &rest[..=end],
+        None => &rest[..rest.chars().next().map_or(0, char::len_utf8)],
+    }
+}
+
+fn is_variable_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Comments start with `{#` followed by whitespace, so that hex colors are not mistaken for them.
+fn is_comment(rest: &str) -> bool {
+    let mut rest = rest.chars();
+
+    rest.next() == Some(COMMENT)
+        && rest
+            .next()
+            .is_some_and(|c| c.is_whitespace() || c == CLOSE_BRACE)
+}
+
+fn skip_comment(chars: &mut Chars) -> Result<(), ErrorKind> {
+    while let Some(c) = chars.next() {
+        match c {
+            ESCAPE => {
+                chars.next();
+            }
+            CLOSE_BRACE => return Ok(()),
+            _ => (),
+        }
+    }
+
+    Err(ErrorKind::UnterminatedComment)
+}
+
+/// Parses section meta, colors that are left empty are inherited from the parent style. The last
+/// meta value can be followed by `@name` of the section.
+fn parse_section(
+    chars: &mut Chars,
+    parent: Option<&Style>,
+    definitions: &Definitions,
+) -> Result<Section, ErrorKind> {
+    let mut buffer = String::new();
+
+    let mut meta: Vec<String> = {
+        let mut quoted = false;
+
+        while let Some(c) = chars.next() {
+            match c {
+                QUOTE => {
+                    quoted = !quoted;
+                    buffer.push(c);
+                }
+                ':' if !quoted => break,
+                ESCAPE => {
+                    buffer.push(c);
+                    buffer.extend(chars.next());
+                }
+                _ => buffer.push(c),
+            }
+        }
+        split_meta(&buffer)
+    };
+
+    let name = meta.last_mut().and_then(split_section_name);
+
+    let mut section = section_from_meta(&meta, parent, definitions)?;
+    section.name = name;
+
+    Ok(section)
+}
+
+/// Cuts `@name` off the end of the meta value. A variable reference has no value before it, so
+/// the `@` should follow a name, a number or a closing parenthesis to start the section name.
+fn split_section_name(value: &mut String) -> Option<String> {
+    let index = value.rfind(VARIABLE)?;
+    let name = &value[index + 1..];
+
+    let follows_value = value[..index]
+        .chars()
+        .next_back()
+        .is_some_and(|c| c.is_ascii_alphanumeric() || c == ')');
+
+    if !follows_value || !is_variable_name(name) {
+        return None;
+    }
+
+    let name = name.to_string();
+    value.truncate(index);
+
+    Some(name)
+}
+
+fn section_from_meta(
+    meta: &[String],
+    parent: Option<&Style>,
+    definitions: &Definitions,
+) -> Result<Section, ErrorKind> {
+    let meta: Vec<&str> = meta.iter().map(String::as_str).collect();
+
+    if let [""] = meta[..] {
+        return Ok(Section {
+            style: parent.copied().unwrap_or_default(),
+            ..Section::default()
+        });
+    }
+
+    if let [name] = meta[..] {
+        if let Some(name) = name.trim().strip_prefix(NAMED_STYLE) {
+            return definitions
+                .styles
+                .get(name)
+                .cloned()
+                .ok_or_else(|| ErrorKind::UnknownStyle(name.to_string()));
+        }
+
+        if let Some((role, meta)) = ROLES.iter().find(|(role, _)| *role == name.trim()) {
+            return match definitions.styles.get(*role) {
+                Some(section) => Ok(section.clone()),
+                None => section_from_meta(&split_meta(meta), None, definitions),
+            };
+        }
+    }
+
+    if meta.len() < 2 {
+        return Err(ErrorKind::MissingMeta);
+    }
+
+    let mut coloring = None;
+    let mut auto = None;
+
+    let fg = match meta[0].trim() {
+        "" => parent.map(|parent| parent.fg).unwrap_or_default(),
+        value if value == AUTO || value.starts_with("auto(") => {
+            match parse_auto(value, definitions) {
+                Ok(colors) => {
+                    auto = Some(colors);
+                    Color::Default
+                }
+                Err(reason) => {
+                    return Err(ErrorKind::InvalidColor {
+                        field: "fg",
+                        reason,
+                    })
+                }
+            }
+        }
+        value => match parse_foreground(value, definitions) {
+            Ok((fg, fg_coloring)) => {
+                coloring = fg_coloring;
+                fg
+            }
+            Err(reason) => {
+                return Err(ErrorKind::InvalidColor {
+                    field: "fg",
+                    reason,
+                })
+            }
+        },
+    };
+
+    let bg = match meta[1].trim() {
+        "" => parent.map(|parent| parent.bg).unwrap_or_default(),
+        value => match parse_color(value, definitions) {
+            Ok(bg) => bg,
+            Err(reason) => {
+                return Err(ErrorKind::InvalidColor {
+                    field: "bg",
+                    reason,
+                })
+            }
+        },
+    };
+
+    let fg = match auto {
+        Some((light, dark)) => contrasting_color(bg, light, dark),
+        None => fg,
+    };
+
+    let mut section = Section {
+        style: Style {
+            fg,
+            bg,
+            direction: parent.and_then(|parent| parent.direction),
+            ..Style::default()
+        },
+        coloring,
+        ..Section::default()
+    };
+
+    for value in &meta[2..] {
+        match value.split_once('=') {
+            Some((key, value)) => parse_option(&mut section, key.trim(), value.trim())?,
+            None if value.trim() == "nosep" => section.style.separator = NO_SEPARATOR,
+            None if value.trim() == "rainbow" => section.coloring = Some(Coloring::Rainbow),
+            None if value.trim() == "optional" => section.optional = true,
+            None => section.style.attrs.insert(parse_attribute(value.trim())?),
+        }
+    }
+
+    Ok(section)
+}
+
+fn parse_option(section: &mut Section, key: &str, value: &str) -> Result<(), ErrorKind> {
+    match key {
+        "sep" => section.style.separator = parse_separator(value)?,
+        "dir" => section.style.direction = Some(parse_direction(value)?),
+        "pad" => section.padding = parse_number(key, value)?,
+        "max" => section.max_width = Some(parse_number(key, value)?),
+        "ellipsis" => section.ellipsis = Some(value.to_string()),
+        "min" => section.min_width = parse_number(key, value)?,
+        "width" => {
+            section.min_width = parse_number(key, value)?;
+            section.max_width = Some(section.min_width);
+        }
+        "align" => section.align = parse_align(value)?,
+        _ => return Err(ErrorKind::UnknownOption(key.to_string())),
+    }
+
+    Ok(())
+}
+
+fn parse_align(value: &str) -> Result<Align, ErrorKind> {
+    match value {
+        "left" => Ok(Align::Left),
+        "right" => Ok(Align::Right),
+        "center" => Ok(Align::Center),
+        _ => Err(ErrorKind::InvalidAlign(value.to_string())),
+    }
+}
+
+fn parse_direction(value: &str) -> Result<Direction, ErrorKind> {
+    match value {
+        "right" => Ok(Direction::Right),
+        "left" => Ok(Direction::Left),
+        _ => Err(ErrorKind::InvalidDirection(value.to_string())),
+    }
+}
+
+fn parse_number(key: &str, value: &str) -> Result<usize, ErrorKind> {
+    value
+        .parse::<usize>()
+        .map_err(|e| ErrorKind::InvalidNumber {
+            option: key.to_string(),
+            reason: e.to_string(),
+        })
+}
+
+/// Separator is either one of the named sets or a pair of glyphs for opening and closing.
+fn parse_separator(value: &str) -> Result<Separator, ErrorKind> {
+    if let Some((_, separator)) = SEPARATORS.iter().find(|(name, _)| *name == value) {
+        return Ok(*separator);
+    }
+
+    let mut glyphs = value.chars();
+
+    match (glyphs.next(), glyphs.next(), glyphs.next(), glyphs.next()) {
+        (Some(open), Some(close), None, None) => Ok(Separator::new(open, close)),
+        (Some(open), Some(close), Some(thin), None) => {
+            Ok(Separator::new(open, close).with_thin(thin))
+        }
+        _ => Err(ErrorKind::InvalidSeparator(value.to_string())),
+    }
+}
+
+fn parse_attribute(value: &str) -> Result<Attribute, ErrorKind> {
+    let name = value.to_ascii_lowercase();
+
+    ATTRIBUTES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, attr)| *attr)
+        .ok_or_else(|| ErrorKind::UnknownAttribute(value.to_string()))
+}
+
+/// Splits meta by commas, leaving escaped commas and commas inside of parentheses intact.
+fn split_meta(meta: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut depth = 0;
+    let mut quoted = false;
+
+    let mut chars = meta.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ESCAPE => part.extend(chars.next()),
+            QUOTE => quoted = !quoted,
+            _ if quoted => part.push(c),
+            '(' => {
+                depth += 1;
+                part.push(c);
+            }
+            ')' if depth > 0 => {
+                depth -= 1;
+                part.push(c);
+            }
+            ',' if depth == 0 => parts.push(std::mem::take(&mut part)),
+            _ => part.push(c),
+        }
+    }
+
+    parts.push(part);
+    parts
+}
+
+/// Parses foreground, which can also be a `from..to` gradient. In that case the first color is used
+/// for everything but the text.
+fn parse_foreground(
+    value: &str,
+    definitions: &Definitions,
+) -> Result<(Color, Option<Coloring>), String> {
+    let (from, to) = match value.split_once("..") {
+        Some(gradient) => gradient,
+        None => return Ok((parse_color(value, definitions)?, None)),
+    };
+
+    let rgb = |value: &str| -> Result<Rgb, String> {
+        parse_color(value.trim(), definitions)?
+            .to_rgb()
+            .ok_or_else(|| "gradient can't use the default color".to_string())
+    };
+
+    let (from, to) = (rgb(from)?, rgb(to)?);
+
+    Ok((
+        Color::Rgb(from.0, from.1, from.2),
+        Some(Coloring::Gradient(from, to)),
+    ))
+}
+
+fn parse_color(value: &str, definitions: &Definitions) -> Result<Color, String> {
+    if let Some(name) = value.strip_prefix(VARIABLE) {
+        return definitions
+            .colors
+            .get(name)
+            .copied()
+            .ok_or_else(|| format!("unknown variable '{}'", value));
+    }
+    if let Some(hex) = value.strip_prefix('#') {
+        return parse_hex_color(hex).ok_or_else(|| format!("malformed hex color '{}'", value));
+    }
+
+    if let Some(args) = value.strip_prefix("rgb(").and_then(|v| v.strip_suffix(')')) {
+        return parse_rgb_color(args).ok_or_else(|| format!("malformed rgb color '{}'", value));
+    }
+
+    if let Some(arg) = value
+        .strip_prefix("hash(")
+        .and_then(|v| v.strip_suffix(')'))
+    {
+        return hash_color(arg.trim(), definitions);
+    }
+
+    if let Some(args) = value.strip_prefix("mix(").and_then(|v| v.strip_suffix(')')) {
+        return parse_mix(args, definitions);
+    }
+
+    for (function, sign) in [("lighten(", 1.0), ("darken(", -1.0)] {
+        if let Some(args) = value
+            .strip_prefix(function)
+            .and_then(|v| v.strip_suffix(')'))
+        {
+            return parse_lightness_change(args, sign, definitions);
+        }
+    }
+
+    if !value.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return value
+            .parse::<u8>()
+            .map(Color::Indexed)
+            .map_err(|e| e.to_string());
+    }
+
+    let name = value.to_ascii_lowercase();
+
+    if name == "default" {
+        return Ok(Color::Default);
+    }
+
+    COLOR_NAMES
+        .iter()
+        .position(|known| *known == name)
+        .map(|index| Color::Indexed(index as u8))
+        .ok_or_else(|| format!("unknown color name '{}'", value))
+}
+
+/// Parses `auto` or `auto(light, dark)` foreground, returns colors to choose from.
+fn parse_auto(value: &str, definitions: &Definitions) -> Result<(Color, Color), String> {
+    let args = match value
+        .strip_prefix("auto(")
+        .and_then(|v| v.strip_suffix(')'))
+    {
+        Some(args) => args,
+        None if value == AUTO => return Ok(AUTO_COLORS),
+        None => return Err(format!("malformed auto color '{}'", value)),
+    };
+
+    let colors = split_meta(args)
+        .iter()
+        .map(|color| parse_color(color.trim(), definitions))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match colors[..] {
+        [light, dark] if light.to_rgb().is_some() && dark.to_rgb().is_some() => Ok((light, dark)),
+        [_, _] => Err("auto can't choose the default color".to_string()),
+        _ => Err(format!("auto should have two colors, got '{}'", args)),
+    }
+}
+
+/// Picks the color with the greater contrast ratio against the background. Nothing is known about
+/// the default background, so the default foreground is used with it.
+fn contrasting_color(bg: Color, light: Color, dark: Color) -> Color {
+    let luminance = |color: Color| color.to_rgb().map(relative_luminance);
+
+    let (bg, light_luminance, dark_luminance) =
+        match (luminance(bg), luminance(light), luminance(dark)) {
+            (Some(bg), Some(light), Some(dark)) => (bg, light, dark),
+            _ => return Color::Default,
+        };
+
+    let contrast = |fg: f64| (fg.max(bg) + 0.05) / (fg.min(bg) + 0.05);
+
+    if contrast(light_luminance) >= contrast(dark_luminance) {
+        light
+    } else {
+        dark
+    }
+}
+
+/// Relative luminance as defined by WCAG.
+fn relative_luminance((r, g, b): Rgb) -> f64 {
+    let linear = |c: u8| {
+        let c = c as f64 / 255.0;
+
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+
+    0.2126 * linear(r) + 0.7152 * linear(g) + 0.0722 * linear(b)
+}
+
+/// Maps the text to one of the palette colors, `$NAME` stands for the value given when generating
+/// or the environment variable. FNV-1a hash is used, as it's stable between builds and platforms.
+/// When the template is only parsed, an unset variable gives the default color.
+fn hash_color(text: &str, definitions: &Definitions) -> Result<Color, String> {
+    let text = match text.strip_prefix('$') {
+        Some(name) => {
+            let value = definitions
+                .values
+                .as_ref()
+                .and_then(|values| values.get(name).cloned())
+                .or_else(|| env::var(name).ok());
+
+            match (value, &definitions.values) {
+                (Some(value), _) => value,
+                (None, Some(_)) => return Err(format!("unset variable '${}'", name)),
+                (None, None) => return Ok(Color::Default),
+            }
+        }
+        None => text.to_string(),
+    };
+
+    let hash = text.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    Ok(Color::Indexed(
+        HASH_COLORS[(hash % HASH_COLORS.len() as u64) as usize],
+    ))
+}
+
+/// Template has `hash($NAME)` colors, which depend on the values of the generation.
+fn has_variable_hash(template: &str) -> bool {
+    template
+        .match_indices("hash(")
+        .any(|(index, call)| template[index + call.len()..].trim_start().starts_with('$'))
+}
+
+/// Parses `color, amount%` arguments and changes HSL lightness of the color by the amount in the
+/// direction of the `sign`.
+fn parse_lightness_change(
+    args: &str,
+    sign: f64,
+    definitions: &Definitions,
+) -> Result<Color, String> {
+    let (color, amount) = args
+        .rsplit_once(',')
+        .ok_or_else(|| format!("missing amount in '{}'", args))?;
+
+    let amount = parse_percentage(amount.trim())?;
+
+    let rgb = parse_color(color.trim(), definitions)?
+        .to_rgb()
+        .ok_or("can't change lightness of the default color")?;
+
+    let (hue, saturation, lightness) = rgb_to_hsl(rgb);
+    let lightness = (lightness + sign * amount).clamp(0.0, 1.0);
+    let (r, g, b) = hsl_to_rgb(hue, saturation, lightness);
+
+    Ok(Color::Rgb(r, g, b))
+}
+
+/// Parses `a, b` or `a, b, weight%` arguments and blends the colors, the weight is of the first
+/// color and is 50% by default.
+fn parse_mix(args: &str, definitions: &Definitions) -> Result<Color, String> {
+    let args = split_meta(args);
+
+    let (a, b, weight) = match &args[..] {
+        [a, b] => (a, b, 0.5),
+        [a, b, weight] => (a, b, parse_percentage(weight.trim())?),
+        _ => return Err("mix should have two colors and an optional weight".to_string()),
+    };
+
+    let rgb = |color: &str| -> Result<Rgb, String> {
+        parse_color(color.trim(), definitions)?
+            .to_rgb()
+            .ok_or_else(|| "can't mix the default color".to_string())
+    };
+
+    let (r, g, b) = blend(rgb(b)?, rgb(a)?, weight);
+    Ok(Color::Rgb(r, g, b))
+}
+
+/// Parses an amount like `20%` or `20` into a fraction from 0 to 1.
+fn parse_percentage(amount: &str) -> Result<f64, String> {
+    amount
+        .strip_suffix('%')
+        .unwrap_or(amount)
+        .parse::<f64>()
+        .ok()
+        .filter(|amount| (0.0..=100.0).contains(amount))
+        .map(|amount| amount / 100.0)
+        .ok_or_else(|| format!("invalid amount '{}'", amount))
+}
+
+/// Squared distance between the colors.
+fn rgb_distance((r, g, b): Rgb, (r2, g2, b2): Rgb) -> i32 {
+    let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+    d(r, r2) + d(g, g2) + d(b, b2)
+}
+
+/// Interpolates between the colors, `t` goes from 0 for the first one to 1 for the second one.
+fn blend(from: Rgb, to: Rgb, t: f64) -> Rgb {
+    let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+    (mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+}
+
+/// Converts RGB to hue in degrees, saturation and lightness in the range from 0 to 1.
+fn rgb_to_hsl((r, g, b): Rgb) -> (f64, f64, f64) {
+    let (r, g, b) = (r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0);
+
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.0;
+
+    if max == min {
+        return (0.0, 0.0, lightness);
+    }
+
+    let delta = max - min;
+    let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+
+    let hue = if max == r {
+        ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+
+    (hue * 60.0, saturation, lightness)
+}
+
+fn hsl_to_rgb(hue: f64, saturation: f64, lightness: f64) -> Rgb {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0).rem_euclid(2.0) - 1.0).abs());
+    let m = lightness - chroma / 2.0;
+
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+
+    let component = |c: f64| ((c + m) * 255.0).round() as u8;
+
+    (component(r), component(g), component(b))
+}
+
+fn parse_hex_color(hex: &str) -> Option<Color> {
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+
+    Some(Color::Rgb(component(0)?, component(2)?, component(4)?))
+}
+
+/// Emits the transition between styles, `caps` replace glyphs of sections at the edges against the
+/// terminal background. Sections pointing left are closed with the opening glyph and swapped colors.
+fn push_brace(
+    buffer: &mut Buffer,
+    brace: char,
+    style: Option<&Style>,
+    next_style: Option<&Style>,
+    caps: Option<Separator>,
+    direction: Direction,
+    output: Output,
+) -> usize {
+    let mut columns = 0;
+
+    // Having no style is the same as having the default one
+    if style.copied().unwrap_or_default() == next_style.copied().unwrap_or_default() {
+        return columns;
+    }
+
+    if brace == OPEN_BRACE {
+        if let Some(next_style) = next_style {
+            if let Some(glyph) = edge_separator(next_style, style, caps).open {
+                if needs_cap(next_style, style) {
+                    push_escape_code(buffer, Escape::Foreground(next_style.bg), output);
+                    columns += push_glyph(buffer, glyph, output);
+                }
+            }
+
+            push_escape_code(buffer, Escape::Foreground(next_style.fg), output);
+            push_escape_code(buffer, Escape::Background(next_style.bg), output);
+
+            let attrs = style.map(|style| style.attrs).unwrap_or_default();
+            push_attributes(buffer, attrs, next_style.attrs, output);
+        }
+    } else if brace == CLOSE_BRACE {
+        // Parent style is restored in full, after a reset nothing of the closed section leaks
+        push_escape_code(buffer, Escape::Reset, output);
+
+        if let Some(next_style) = next_style {
+            push_escape_code(buffer, Escape::Background(next_style.bg), output);
+            push_attributes(buffer, Attributes::default(), next_style.attrs, output);
+        }
+
+        if let Some(style) = style.filter(|style| needs_cap(style, next_style)) {
+            let separator = edge_separator(style, next_style, caps);
+
+            match style.direction.unwrap_or(direction) {
+                Direction::Right => {
+                    if let Some(glyph) = separator.close {
+                        push_escape_code(buffer, Escape::Foreground(style.bg), output);
+                        columns += push_glyph(buffer, glyph, output);
+                    }
+                }
+                Direction::Left if output.right && next_style.is_none() => (),
+                Direction::Left => {
+                    if let Some(glyph) = separator.open {
+                        columns += push_swapped_glyph(buffer, glyph, style, next_style, output);
+                    }
+                }
+            }
+        }
+
+        let escape = match next_style {
+            Some(next_style) => Escape::Foreground(next_style.fg),
+            None => Escape::Reset,
+        };
+
+        push_escape_code(buffer, escape, output);
+    }
+
+    columns
+}
+
+/// Draws the glyph in the adjacent background over the background of the section. The terminal
+/// background is not known, so it's drawn in reverse video.
+fn push_swapped_glyph(
+    buffer: &mut Buffer,
+    glyph: char,
+    style: &Style,
+    adjacent: Option<&Style>,
+    output: Output,
+) -> usize {
+    match adjacent.filter(|adjacent| !adjacent.bg.is_default()) {
+        Some(adjacent) => {
+            push_escape_code(buffer, Escape::Foreground(adjacent.bg), output);
+            push_escape_code(buffer, Escape::Background(style.bg), output);
+            let columns = push_glyph(buffer, glyph, output);
+            push_escape_code(buffer, Escape::Background(adjacent.bg), output);
+            columns
+        }
+        None => {
+            push_escape_code(buffer, Escape::Foreground(style.bg), output);
+            push_escape_code(buffer, Escape::AttributeOn(Attribute::Reverse), output);
+            let columns = push_glyph(buffer, glyph, output);
+            push_escape_code(buffer, Escape::AttributeOff(Attribute::Reverse), output);
+            columns
+        }
+    }
+}
+
+/// Pushes the glyph of a separator, returns the number of columns it takes.
+fn push_glyph(buffer: &mut Buffer, glyph: char, output: Output) -> usize {
+    let glyph = match (output.plain, output.ascii) {
+        (_, true) => ascii_glyph(glyph),
+        (true, false) => return 0,
+        (false, false) => glyph,
+    };
+
+    let start = buffer.len();
+
+    match output.html {
+        true => buffer.push_str(&escape_xml(glyph.encode_utf8(&mut [0; 4]))),
+        false => buffer.push(glyph),
+    }
+
+    buffer.mark_text(start);
+
+    glyph.width().unwrap_or(1)
+}
+
+/// ASCII replacement for Powerline glyphs, other glyphs are returned as is.
+fn ascii_glyph(glyph: char) -> char {
+    match glyph {
+        '\u{E0B0}' | '\u{E0B1}' => '>',
+        '\u{E0B2}' | '\u{E0B3}' => '<',
+        '\u{E0B4}' => ')',
+        '\u{E0B6}' => '(',
+        '\u{E0B5}' | '\u{E0B7}' => '|',
+        '\u{E0BA}' | '\u{E0BC}' | '\u{E0BD}' => '/',
+        '\u{E0BB}' => '\\',
+        _ => glyph,
+    }
+}
+
+/// Thin glyphs of the standard separators pointing the other way.
+fn mirror_glyph(glyph: char) -> char {
+    match glyph {
+        '\u{E0B1}' => '\u{E0B3}',
+        '\u{E0B5}' => '\u{E0B7}',
+        '\u{E0BD}' => '\u{E0BB}',
+        _ => glyph,
+    }
+}
+
+/// Separator of the section next to the adjacent style, which is replaced with the caps when the
+/// adjacent background is the terminal one. Sections without a separator stay without it.
+fn edge_separator(style: &Style, adjacent: Option<&Style>, caps: Option<Separator>) -> Separator {
+    let edge = adjacent.is_none_or(|adjacent| adjacent.bg.is_default());
+
+    match caps {
+        Some(caps) if edge && style.separator != NO_SEPARATOR => caps,
+        _ => style.separator,
+    }
+}
+
+/// Caps are drawn only around sections that change the background.
+fn needs_cap(style: &Style, adjacent: Option<&Style>) -> bool {
+    !style.bg.is_default() && adjacent.map(|adjacent| adjacent.bg) != Some(style.bg)
+}
+
+/// Switches active attributes from one set to another, turning off only what's not needed anymore.
+fn push_attributes(buffer: &mut String, from: Attributes, to: Attributes, output: Output) {
+    let mut active = from;
+
+    for (_, attr) in ATTRIBUTES.iter().copied() {
+        if active.contains(attr) && !to.contains(attr) {
+            push_escape_code(buffer, Escape::AttributeOff(attr), output);
+            active.0 &= !output.encoder.attributes_off(attr).0;
+        }
+    }
+
+    for (_, attr) in ATTRIBUTES.iter().copied() {
+        if to.contains(attr) && !active.contains(attr) {
+            push_escape_code(buffer, Escape::AttributeOn(attr), output);
+        }
+    }
+}
+
+fn push_escape_code(buffer: &mut String, escape: Escape, output: Output) {
+    let convert = |color: Color| {
+        let converted = match output.colors {
+            ColorMode::TrueColor => return Some(color),
+            ColorMode::Palette256 => color.to_indexed(),
+            ColorMode::Basic16 => color.to_basic(),
+            ColorMode::Basic8 => color
+                .to_basic()
+                .map(|index| output.color_table[index as usize] % 8),
+            ColorMode::None => return None,
+            ColorMode::Auto => unreachable!("color mode is detected before rendering"),
+        };
+
+        Some(converted.map_or(Color::Default, Color::Indexed))
+    };
+
+    let escape = match escape {
+        Escape::Foreground(color) => Escape::Foreground(match convert(color) {
+            Some(color) => color,
+            None => return,
+        }),
+        Escape::Background(color) => Escape::Background(match convert(color) {
+            Some(color) => color,
+            None => return,
+        }),
+        Escape::AttributeOn(Attribute::Bold) | Escape::AttributeOff(Attribute::Bold) => escape,
+        Escape::AttributeOn(_) | Escape::AttributeOff(_) if output.colors == ColorMode::Basic8 => {
+            return
+        }
+        escape => escape,
+    };
+
+    output.encoder.push_escape(buffer, escape, output.shell);
+}
+
+/// Writes changes of the style in the codes of a shell or a tool, for `ShellDialect::color_encoder`.
+/// Colors are converted to the color mode before they're given to the encoder, sections are laid
+/// out the same way for all encoders.
+pub trait ColorEncoder {
+    fn push_escape(&self, buffer: &mut String, escape: Escape, shell: Shell);
+
+    /// Attributes that are turned off by the "off" code of the attribute, the ones that are still
+    /// needed are turned on again. Bold and dim share the same code.
+    fn attributes_off(&self, attr: Attribute) -> Attributes {
+        let mut off = Attributes::default();
+        off.insert(attr);
+
+        if let Attribute::Bold | Attribute::Dim = attr {
+            off.insert(Attribute::Bold);
+            off.insert(Attribute::Dim);
+        }
+
+        off
+    }
+}
+
+/// Escape codes of the terminal, between the non-printing markers of the shell.
+struct AnsiEncoder;
+
+/// Escape codes of the 16 basic colors, for terminals that don't support the palette.
+struct BasicEncoder;
+
+/// Zsh prompt escapes like `%F{33}`, with escape codes for what Zsh has no escapes for.
+struct ZshEncoder;
+
+struct TmuxEncoder;
+
+struct ScreenEncoder;
+
+/// Leaves out all escapes, for plain output.
+struct PlainEncoder;
+
+/// Wraps text into `<span>` elements with inline styles. Spans are opened after each change of the
+/// style, a span that is still empty is replaced with the next one.
+#[derive(Default)]
+struct HtmlEncoder {
+    state: Cell<(Color, Color, Attributes)>,
+    /// Opening tag of the span that is not closed yet.
+    open: Cell<Option<String>>,
+}
+
+fn color_encoder(
+    shell: Shell,
+    colors: ColorMode,
+    native_colors: bool,
+    plain: bool,
+) -> &'static dyn ColorEncoder {
+    let ansi: &'static dyn ColorEncoder = match colors {
+        ColorMode::Basic16 | ColorMode::Basic8 => &BasicEncoder,
+        _ => &AnsiEncoder,
+    };
+
+    match shell {
+        _ if plain => &PlainEncoder,
+        Shell::Tmux => &TmuxEncoder,
+        Shell::Screen => &ScreenEncoder,
+        Shell::Zsh if native_colors => &ZshEncoder,
+        Shell::Custom(dialect) => dialect.color_encoder().unwrap_or(ansi),
+        _ => ansi,
+    }
+}
+
+/// Pushes the SGR code between the non-printing markers of the shell.
+fn push_sgr(buffer: &mut String, code: &str, shell: Shell) {
+    push_non_printing_start(buffer, shell);
+    buffer.push_str("\x1b[");
+    buffer.push_str(code);
+    buffer.push('m');
+    push_non_printing_end(buffer, shell);
+}
+
+impl ColorEncoder for AnsiEncoder {
+    fn push_escape(&self, buffer: &mut String, escape: Escape, shell: Shell) {
+        let mut code = String::new();
+
+        match escape {
+            Escape::Foreground(color) => push_color_code(&mut code, color, 38),
+            Escape::Background(color) => push_color_code(&mut code, color, 48),
+            Escape::AttributeOn(attr) => code.push_str(&attr.on_code().to_string()),
+            Escape::AttributeOff(attr) => code.push_str(&attr.off_code().to_string()),
+            Escape::Reset => code.push('0'),
+        };
+
+        push_sgr(buffer, &code, shell);
+    }
+}
+
+impl ColorEncoder for BasicEncoder {
+    fn push_escape(&self, buffer: &mut String, escape: Escape, shell: Shell) {
+        let mut code = String::new();
+
+        match escape {
+            Escape::Foreground(color) => push_basic_color_code(&mut code, color, 30),
+            Escape::Background(color) => push_basic_color_code(&mut code, color, 40),
+            escape => return AnsiEncoder.push_escape(buffer, escape, shell),
+        };
+
+        push_sgr(buffer, &code, shell);
+    }
+}
+
+impl ColorEncoder for ZshEncoder {
+    fn push_escape(&self, buffer: &mut String, escape: Escape, shell: Shell) {
+        if !push_zsh_escape(buffer, escape) {
+            AnsiEncoder.push_escape(buffer, escape, shell);
+        }
+    }
+
+    /// Zsh turns off bold by resetting all attributes and restoring the ones it knows of, which
+    /// is only underline.
+    fn attributes_off(&self, attr: Attribute) -> Attributes {
+        match attr {
+            Attribute::Bold => Attributes(!(1 << Attribute::Underline as u8)),
+            attr => AnsiEncoder.attributes_off(attr),
+        }
+    }
+}
+
+impl ColorEncoder for TmuxEncoder {
+    fn push_escape(&self, buffer: &mut String, escape: Escape, _: Shell) {
+        push_tmux_escape(buffer, escape);
+    }
+}
+
+impl ColorEncoder for ScreenEncoder {
+    fn push_escape(&self, buffer: &mut String, escape: Escape, _: Shell) {
+        push_screen_escape(buffer, escape);
+    }
+}
+
+impl ColorEncoder for PlainEncoder {
+    fn push_escape(&self, _: &mut String, _: Escape, _: Shell) {}
+}
+
+impl ColorEncoder for HtmlEncoder {
+    fn push_escape(&self, buffer: &mut String, escape: Escape, _: Shell) {
+        let (mut fg, mut bg, mut attrs) = self.state.get();
+
+        match escape {
+            Escape::Foreground(color) => fg = color,
+            Escape::Background(color) => bg = color,
+            Escape::AttributeOn(attr) => attrs.insert(attr),
+            Escape::AttributeOff(attr) => attrs.0 &= !self.attributes_off(attr).0,
+            Escape::Reset => (fg, bg, attrs) = Default::default(),
+        }
+
+        if self.state.replace((fg, bg, attrs)) == (fg, bg, attrs) {
+            return;
+        }
+
+        self.close(buffer);
+
+        let styles = css_styles(fg, bg, attrs);
+
+        if !styles.is_empty() {
+            let tag = format!("<span style=\"{}\">", styles.join(";"));
+            buffer.push_str(&tag);
+            self.open.set(Some(tag));
+        }
+    }
+}
+
+impl HtmlEncoder {
+    /// Closes the open span, or removes it if there's no text in it.
+    fn close(&self, buffer: &mut String) {
+        match self.open.take() {
+            Some(tag) if buffer.ends_with(tag.as_str()) => {
+                buffer.truncate(buffer.len() - tag.len())
+            }
+            Some(_) => buffer.push_str("</span>"),
+            None => (),
+        }
+    }
+}
+
+/// Pushes tmux style. Turning off bold turns off dim too, the same as the escape code.
+fn push_tmux_escape(buffer: &mut String, escape: Escape) {
+    let color = |color| match color {
+        Color::Default => "default".to_string(),
+        Color::Indexed(index) => format!("colour{}", index),
+        Color::Rgb(r, g, b) => format!("#{:02x}{:02x}{:02x}", r, g, b),
+    };
+
+    let style = match escape {
+        Escape::Foreground(fg) => format!("fg={}", color(fg)),
+        Escape::Background(bg) => format!("bg={}", color(bg)),
+        Escape::AttributeOn(attr) => attr.tmux_name().to_string(),
+        Escape::AttributeOff(Attribute::Bold) | Escape::AttributeOff(Attribute::Dim) => {
+            "nobold,nodim".to_string()
+        }
+        Escape::AttributeOff(attr) => format!("no{}", attr.tmux_name()),
+        Escape::Reset => "default".to_string(),
+    };
+
+    buffer.push_str(&format!("#[{}]", style));
+}
+
+/// Pushes screen color or attribute change. Colors are written as background and foreground
+/// letters, where `.` leaves the color unchanged.
+fn push_screen_escape(buffer: &mut String, escape: Escape) {
+    let color = |color: Color| match color.to_basic() {
+        Some(index) => {
+            let letter = b"krgybmcw"[index as usize % 8] as char;
+
+            match index >= 8 {
+                true => letter.to_ascii_uppercase(),
+                false => letter,
+            }
+        }
+        None => 'd',
+    };
+
+    match escape {
+        Escape::Foreground(fg) => buffer.push_str(&format!("%{{.{}}}", color(fg))),
+        Escape::Background(bg) => buffer.push_str(&format!("%{{{}.}}", color(bg))),
+        Escape::AttributeOn(attr) => {
+            if let Some(letter) = attr.screen_letter() {
+                buffer.push_str(&format!("%{{+{}}}", letter));
+            }
+        }
+        Escape::AttributeOff(Attribute::Bold) | Escape::AttributeOff(Attribute::Dim) => {
+            buffer.push_str("%{-bd}")
+        }
+        Escape::AttributeOff(attr) => {
+            if let Some(letter) = attr.screen_letter() {
+                buffer.push_str(&format!("%{{-{}}}", letter));
+            }
+        }
+        Escape::Reset => buffer.push_str("%{= dd}"),
+    }
+}
+
+/// Replaces escape codes in the buffer with starship style groups like `[text](fg:33 bg:236 bold)`,
+/// text in the default style is left outside of groups. Links are left out.
+fn starship_format(buffer: &str) -> String {
+    let mut format = String::new();
+
+    for ((fg, bg, attrs), text) in styled_runs(buffer) {
+        let mut style = Vec::new();
+
+        if let Some(fg) = fg.to_template() {
+            style.push(format!("fg:{}", fg));
+        }
+
+        if let Some(bg) = bg.to_template() {
+            style.push(format!("bg:{}", bg));
+        }
+
+        for (_, attr) in ATTRIBUTES.iter().copied() {
+            if attrs.contains(attr) {
+                style.push(attr.starship_name().to_string());
+            }
+        }
+
+        match style.is_empty() {
+            true => format.push_str(&text),
+            false => format.push_str(&format!("[{}]({})", text, style.join(" "))),
+        }
+    }
+
+    format
+}
+
+/// Replaces escape codes in the buffer with highlight groups of the styles they set, and returns
+/// `:highlight` commands for the groups followed by the assignment of the statusline. The default
+/// style is switched back to the `StatusLine` group.
+fn vim_statusline(buffer: &str) -> String {
+    let mut groups = Vec::new();
+    let mut statusline = String::new();
+
+    for (state, text) in styled_runs(buffer) {
+        match groups.iter().position(|group| *group == state) {
+            _ if state == Default::default() => statusline.push_str("%*"),
+            Some(index) => statusline.push_str(&format!("%#Bubble{}#", index + 1)),
+            None => {
+                groups.push(state);
+                statusline.push_str(&format!("%#Bubble{}#", groups.len()));
+            }
+        }
+
+        statusline.push_str(&text);
+    }
+
+    let mut script = String::new();
+
+    for (i, (fg, bg, attrs)) in groups.iter().enumerate() {
+        let cterm = |color: Color| match color {
+            Color::Indexed(index) => index.to_string(),
+            color => color
+                .to_basic()
+                .map_or("NONE".to_string(), |index| index.to_string()),
+        };
+        let gui = |color: Color| color.to_hex().unwrap_or_else(|| "NONE".to_string());
+
+        let names: Vec<_> = ATTRIBUTES
+            .iter()
+            .filter(|(_, attr)| attrs.contains(*attr))
+            .filter_map(|(_, attr)| attr.vim_name())
+            .collect();
+        let names = match names.is_empty() {
+            true => "NONE".to_string(),
+            false => names.join(","),
+        };
+
+        script.push_str(&format!(
+            "highlight Bubble{} ctermfg={} ctermbg={} guifg={} guibg={} cterm={} gui={}\n",
+            i + 1,
+            cterm(*fg),
+            cterm(*bg),
+            gui(*fg),
+            gui(*bg),
+            names,
+            names
+        ));
+    }
+
+    script.push_str(&format!(
+        "let &statusline = '{}'",
+        statusline.replace('\'', "''")
+    ));
+    script
+}
+
+/// CSS declarations of the style, reverse swaps the colors.
+fn css_styles(fg: Color, bg: Color, attrs: Attributes) -> Vec<String> {
+    let (fg, bg) = match attrs.contains(Attribute::Reverse) {
+        true => (bg, fg),
+        false => (fg, bg),
+    };
+
+    let mut styles = Vec::new();
+
+    if let Some(fg) = fg.to_hex() {
+        styles.push(format!("color:{}", fg));
+    }
+
+    if let Some(bg) = bg.to_hex() {
+        styles.push(format!("background:{}", bg));
+    }
+
+    styles.extend(css_attributes(attrs));
+    styles
+}
+
+/// CSS declarations of the attributes, except for reverse that swaps colors.
+fn css_attributes(attrs: Attributes) -> Vec<String> {
+    let mut styles: Vec<_> = ATTRIBUTES
+        .iter()
+        .filter(|(_, attr)| attrs.contains(*attr))
+        .filter_map(|(_, attr)| attr.css())
+        .map(str::to_string)
+        .collect();
+
+    let decorations: Vec<_> = [Attribute::Underline, Attribute::Strikethrough]
+        .iter()
+        .filter(|attr| attrs.contains(**attr))
+        .map(|attr| match attr {
+            Attribute::Underline => "underline",
+            _ => "line-through",
+        })
+        .collect();
+
+    if !decorations.is_empty() {
+        styles.push(format!("text-decoration:{}", decorations.join(" ")));
+    }
+
+    styles
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Lays out text of the buffer on a grid of monospace cells as SVG. Powerline glyphs are drawn as
+/// shapes, so that the image doesn't depend on the fonts of the viewer.
+fn svg_image(buffer: &str) -> String {
+    let mut elements = String::new();
+    let (mut column, mut row, mut columns) = (0, 0, 0);
+
+    for ((fg, bg, attrs), text) in styled_runs(buffer) {
+        let (fg, bg) = match attrs.contains(Attribute::Reverse) {
+            true => (bg, fg),
+            false => (fg, bg),
+        };
+        let fg = fg.to_hex().unwrap_or_else(|| SVG_FOREGROUND.to_string());
+        let style = css_attributes(attrs).join(";");
+
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                row += 1;
+                column = 0;
+            }
+
+            let start = column;
+            let mut shapes = String::new();
+            let mut segment = String::new();
+
+            for c in line.chars() {
+                let (x, y) = (column * SVG_CELL_WIDTH, row * SVG_CELL_HEIGHT);
+
+                match svg_glyph(c, x as f64, y as f64) {
+                    Some((path, thin)) => {
+                        let start = column - segment.width();
+                        push_svg_text(&mut shapes, &segment, (start, row), &fg, &style);
+                        segment.clear();
+
+                        let paint = match thin {
+                            true => format!("fill=\"none\" stroke=\"{}\"", fg),
+                            false => format!("fill=\"{}\"", fg),
+                        };
+
+                        shapes.push_str(&format!("<path d=\"{}\" {}/>\n", path, paint));
+                        column += 1;
+                    }
+                    None => {
+                        column += c.width().unwrap_or(0);
+                        segment.push(c);
+                    }
+                }
+            }
+
+            let segment_start = column - segment.width();
+            push_svg_text(&mut shapes, &segment, (segment_start, row), &fg, &style);
+
+            if let Some(bg) = bg.to_hex().filter(|_| column > start) {
+                elements.push_str(&format!(
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>\n",
+                    start * SVG_CELL_WIDTH,
+                    row * SVG_CELL_HEIGHT,
+                    (column - start) * SVG_CELL_WIDTH,
+                    SVG_CELL_HEIGHT,
+                    bg
+                ));
+            }
+
+            elements.push_str(&shapes);
+            columns = columns.max(column);
+        }
+    }
+
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+         font-family=\"monospace\" font-size=\"{}\">\n\
+         <rect width=\"{w}\" height=\"{h}\" fill=\"{}\"/>\n{}</svg>",
+        SVG_FONT_SIZE,
+        SVG_BACKGROUND,
+        elements,
+        w = columns * SVG_CELL_WIDTH,
+        h = (row + 1) * SVG_CELL_HEIGHT
+    )
+}
+
+/// Pushes the text at the cell, stretched to exactly the width of its cells.
+fn push_svg_text(
+    buffer: &mut String,
+    text: &str,
+    (column, row): (usize, usize),
+    fg: &str,
+    style: &str,
+) {
+    if text.trim().is_empty() {
+        return;
+    }
+
+    buffer.push_str(&format!(
+        "<text x=\"{}\" y=\"{}\" textLength=\"{}\" lengthAdjust=\"spacingAndGlyphs\" \
+         fill=\"{}\" style=\"{}\" xml:space=\"preserve\">{}</text>\n",
+        column * SVG_CELL_WIDTH,
+        row * SVG_CELL_HEIGHT + SVG_BASELINE,
+        text.width() * SVG_CELL_WIDTH,
+        fg,
+        style,
+        escape_xml(text)
+    ));
+}
+
+/// Path of a Powerline glyph in the cell at the point, and whether it's a thin line that should be
+/// stroked instead of filled.
+fn svg_glyph(c: char, x: f64, y: f64) -> Option<(String, bool)> {
+    let (w, h) = (SVG_CELL_WIDTH as f64, SVG_CELL_HEIGHT as f64);
+    let middle = y + h / 2.0;
+    let (right, bottom) = (x + w, y + h);
+
+    let (path, thin) = match c {
+        '\u{E0B0}' | '\u{E0B1}' => (
+            format!("M{} {}L{} {}L{} {}", x, y, right, middle, x, bottom),
+            c == '\u{E0B1}',
+        ),
+        '\u{E0B2}' | '\u{E0B3}' => (
+            format!("M{} {}L{} {}L{} {}", right, y, x, middle, right, bottom),
+            c == '\u{E0B3}',
+        ),
+        '\u{E0B4}' | '\u{E0B5}' => (
+            format!("M{} {}A{} {} 0 0 1 {} {}", x, y, w, h / 2.0, x, bottom),
+            c == '\u{E0B5}',
+        ),
+        '\u{E0B6}' | '\u{E0B7}' => (
+            format!(
+                "M{} {}A{} {} 0 0 0 {} {}",
+                right,
+                y,
+                w,
+                h / 2.0,
+                right,
+                bottom
+            ),
+            c == '\u{E0B7}',
+        ),
+        '\u{E0BA}' => (
+            format!("M{} {}L{} {}L{} {}", right, y, right, bottom, x, bottom),
+            false,
+        ),
+        '\u{E0BC}' => (
+            format!("M{} {}L{} {}L{} {}", x, y, right, y, x, bottom),
+            false,
+        ),
+        '\u{E0BD}' => (format!("M{} {}L{} {}", right, y, x, bottom), true),
+        _ => return None,
+    };
+
+    match thin {
+        true => Some((path, true)),
+        false => Some((path + "Z", false)),
+    }
+}
+
+/// Splits the buffer into runs of text with the foreground, background and attributes set by
+/// escape codes before them. Other escape sequences are dropped.
+fn styled_runs(buffer: &str) -> Vec<((Color, Color, Attributes), String)> {
+    let mut runs: Vec<((Color, Color, Attributes), String)> = Vec::new();
+    let mut state = Default::default();
+    let mut rest = buffer;
+
+    while let Some(c) = rest.chars().next() {
+        if let Some(code) = rest.strip_prefix("\x1b[") {
+            if let Some(end) = code.find('m') {
+                apply_sgr(&mut state, &code[..end]);
+                rest = &code[end + 1..];
+                continue;
+            }
+        }
+
+        // Hyperlinks are terminated with BEL
+        if let Some(link) = rest.strip_prefix("\x1b]") {
+            rest = link.find('\x07').map_or("", |end| &link[end + 1..]);
+            continue;
+        }
+
+        match runs.last_mut() {
+            Some((last, text)) if *last == state => text.push(c),
+            _ => runs.push((state, c.to_string())),
+        }
+
+        rest = &rest[c.len_utf8()..];
+    }
+
+    runs
+}
+
+/// Applies SGR parameters to the foreground, background and attributes.
+fn apply_sgr(state: &mut (Color, Color, Attributes), params: &str) {
+    let mut params = params.split(';').map(|param| param.parse().unwrap_or(0));
+
+    while let Some(param) = params.next() {
+        let mut next = || params.next().unwrap_or(0);
+
+        match param {
+            0 => *state = Default::default(),
+            38 | 48 => {
+                let color = match next() {
+                    5 => Color::Indexed(next()),
+                    2 => Color::Rgb(next(), next(), next()),
+                    _ => continue,
+                };
+
+                match param {
+                    38 => state.0 = color,
+                    _ => state.1 = color,
+                }
+            }
+            39 => state.0 = Color::Default,
+            49 => state.1 = Color::Default,
+            30..=37 => state.0 = Color::Indexed(param - 30),
+            40..=47 => state.1 = Color::Indexed(param - 40),
+            90..=97 => state.0 = Color::Indexed(param - 90 + 8),
+            100..=107 => state.1 = Color::Indexed(param - 100 + 8),
+            code => {
+                for (_, attr) in ATTRIBUTES.iter().copied() {
+                    if attr.on_code() == code {
+                        state.2.insert(attr);
+                    } else if attr.off_code() == code {
+                        state.2.remove(attr);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Pushes Zsh prompt escape for the code if there's one. The raw reset is followed by Zsh resets,
+/// so that Zsh doesn't skip colors it considers already set.
+fn push_zsh_escape(buffer: &mut String, escape: Escape) -> bool {
+    match escape {
+        Escape::Foreground(Color::Default) => buffer.push_str("%f"),
+        Escape::Background(Color::Default) => buffer.push_str("%k"),
+        Escape::Foreground(color) => push_zsh_color(buffer, 'F', color),
+        Escape::Background(color) => push_zsh_color(buffer, 'K', color),
+        Escape::AttributeOn(Attribute::Bold) => buffer.push_str("%B"),
+        Escape::AttributeOff(Attribute::Bold) => buffer.push_str("%b"),
+        Escape::AttributeOn(Attribute::Underline) => buffer.push_str("%U"),
+        Escape::AttributeOff(Attribute::Underline) => buffer.push_str("%u"),
+        Escape::Reset => buffer.push_str("%{\x1b[0m%}%f%k%b%u"),
+        _ => return false,
+    }
+
+    true
+}
+
+fn push_zsh_color(buffer: &mut String, kind: char, color: Color) {
+    match color {
+        Color::Indexed(index) => buffer.push_str(&format!("%{}{{{}}}", kind, index)),
+        Color::Rgb(r, g, b) => {
+            buffer.push_str(&format!("%{}{{#{:02x}{:02x}{:02x}}}", kind, r, g, b))
+        }
+        Color::Default => buffer.push_str(&format!("%{}", kind.to_ascii_lowercase())),
+    }
+}
+
+/// Pushes OSC 133 mark, `A` for the start of the prompt and `B` for the end of it where the command
+/// starts.
+fn push_semantic_mark(buffer: &mut String, mark: char, output: Output) {
+    if output.plain || output.html || output.shell.is_status_line() {
+        return;
+    }
+
+    push_non_printing_start(buffer, output.shell);
+    buffer.push_str("\x1b]133;");
+    buffer.push(mark);
+    buffer.push('\x07');
+    push_non_printing_end(buffer, output.shell);
+}
+
+/// Pushes OSC 8 sequence that starts a hyperlink, or ends it if the URL is empty. It's terminated
+/// with BEL, as the string terminator ends with a backslash which clashes with Bash escapes.
+fn push_hyperlink(buffer: &mut String, url: &str, shell: Shell) {
+    if shell.is_status_line() {
+        return;
+    }
+
+    push_non_printing_start(buffer, shell);
+    buffer.push_str("\x1b]8;;");
+    push_literal(buffer, url, shell);
+    buffer.push('\x07');
+    push_non_printing_end(buffer, shell);
+}
+
+/// Pushes text that is printed as is. Fish output is inside of a single-quoted string, PowerShell
+/// and Elvish output is inside of a double-quoted one and Nushell output is an interpolated string.
+fn push_literal(buffer: &mut String, text: &str, shell: Shell) {
+    match shell {
+        Shell::Zsh => buffer.push_str(&text.replace('%', "%%")),
+        Shell::Tmux => buffer.push_str(&text.replace('#', "##").replace('%', "%%")),
+        Shell::Screen | Shell::Vim => buffer.push_str(&text.replace('%', "%%")),
+        Shell::Starship => {
+            for c in text.chars() {
+                if let '\\' | '[' | ']' | '(' | ')' | '$' = c {
+                    buffer.push('\\');
+                }
+
+                buffer.push(c);
+            }
+        }
+        // Bash decodes prompt escapes first and then expands the result as if in double quotes, and
+        // OSH follows it
+        Shell::Bash | Shell::Oils => {
+            for c in text.chars() {
+                match c {
+                    '\\' => buffer.push_str("\\\\\\\\"),
+                    '$' | '`' => {
+                        buffer.push_str("\\\\");
+                        buffer.push(c);
+                    }
+                    _ => buffer.push(c),
+                }
+            }
+        }
+        Shell::Fish => {
+            for c in text.chars() {
+                if c == '\'' || c == '\\' {
+                    buffer.push('\\');
+                }
+
+                buffer.push(c);
+            }
+        }
+        Shell::PowerShell => {
+            for c in text.chars() {
+                if c == '`' || c == '"' || c == '$' {
+                    buffer.push('`');
+                }
+
+                buffer.push(c);
+            }
+        }
+        // A lone `!` is replaced with the history event number
+        Shell::Tcsh => buffer.push_str(&text.replace('!', "\\!")),
+        Shell::Ksh => buffer.push_str(&text.replace('!', "!!")),
+        Shell::Custom(dialect) => buffer.push_str(&dialect.escape_text(text)),
+        Shell::Xonsh => buffer.push_str(&text.replace('{', "{{").replace('}', "}}")),
+        Shell::Elvish | Shell::Clink => {
+            for c in text.chars() {
+                if c == '\\' || c == '"' {
+                    buffer.push('\\');
+                }
+
+                buffer.push(c);
+            }
+        }
+        Shell::Nu => {
+            for c in text.chars() {
+                if c == '\\' || c == '"' || c == '(' {
+                    buffer.push('\\');
+                }
+
+                buffer.push(c);
+            }
+        }
+        _ => buffer.push_str(text),
+    }
+}
+
+/// Markers of the start and the end of escape codes that don't move the cursor.
+fn non_printing_markers(shell: Shell) -> (&'static str, &'static str) {
+    match shell {
+        Shell::Zsh | Shell::Tcsh => ("%{", "%}"),
+        Shell::Bash | Shell::Oils => ("\\[", "\\]"),
+        Shell::Ksh => ("\x01", "\x01"),
+        Shell::Readline => ("\x01", "\x02"),
+        Shell::Custom(dialect) => (dialect.non_printing_start(), dialect.non_printing_end()),
+        _ => ("", ""),
+    }
+}
+
+fn push_non_printing_start(buffer: &mut String, shell: Shell) {
+    buffer.push_str(non_printing_markers(shell).0);
+}
+
+fn push_non_printing_end(buffer: &mut String, shell: Shell) {
+    buffer.push_str(non_printing_markers(shell).1);
+}
+
+/// Pushes color parameters for SGR, `base` is 38 for foreground and 48 for background.
+fn push_color_code(buffer: &mut String, color: Color, base: u8) {
+    match color {
+        Color::Default => buffer.push_str(&(base + 1).to_string()),
+        Color::Indexed(index) => buffer.push_str(&format!("{};5;{}", base, index)),
+        Color::Rgb(r, g, b) => buffer.push_str(&format!("{};2;{};{};{}", base, r, g, b)),
+    }
+}
+
+/// Pushes the code of a basic color, `base` is 30 for foreground and 40 for background. Bright
+/// colors have their own codes starting from 90 and 100.
+fn push_basic_color_code(buffer: &mut String, color: Color, base: u8) {
+    let code = match color {
+        Color::Indexed(index @ 0..=7) => base + index,
+        Color::Indexed(index @ 8..=15) => base + 60 + index - 8,
+        _ => base + 9,
+    };
+
+    buffer.push_str(&code.to_string());
+}
+
+fn parse_rgb_color(args: &str) -> Option<Color> {
+    let components: Vec<u8> = args
+        .split(',')
+        .map(|c| c.trim().parse::<u8>().ok())
+        .collect::<Option<_>>()?;
+
+    match components[..] {
+        [r, g, b] => Some(Color::Rgb(r, g, b)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind<T>(result: Result<T, Error>) -> Result<T, ErrorKind> {
+        result.map_err(|error| error.kind)
+    }
+
+    #[test]
+    fn one_section() {
+        assert_eq!(
+            generate("{0,1:xxx}", Shell::None),
+            Ok("\x1b[38;5;1m\x1b[38;5;0m\x1b[48;5;1mxxx\x1b[0m\x1b[38;5;1m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_zsh() {
+        assert_eq!(
+            generate("{0,1:xxx}", Shell::Zsh),
+            Ok("%{\x1b[38;5;1m%}%{\x1b[38;5;0m%}%{\x1b[48;5;1m%}xxx%{\x1b[0m%}%{\x1b[38;5;1m%}%{\x1b[0m%}".to_string())
+        );
+    }
+
+    #[test]
+    fn bash_escaping() {
+        assert_eq!(
+            generate("$HOME `id` \\w {!$PWD}", Shell::Bash),
+            Ok("\\\\$HOME \\\\`id\\\\` \\\\\\\\w $PWD".to_string())
+        );
+        assert_eq!(
+            Generator::new()
+                .shell_escapes(true)
+                .generate("$HOME \\w", Shell::Bash),
+            Ok("$HOME \\w".to_string())
+        );
+        assert_eq!(
+            generate("$HOME `id` \\w {!$PWD}", Shell::Oils),
+            generate("$HOME `id` \\w {!$PWD}", Shell::Bash)
+        );
+        assert_eq!(
+            generate("{??}a\\b{|}n{/}", Shell::Oils),
+            generate("{??}a\\b{|}n{/}", Shell::Bash)
+        );
+    }
+
+    #[test]
+    fn zsh_escaping() {
+        assert_eq!(
+            generate("100% {!%~}", Shell::Zsh),
+            Ok("100%% %~".to_string())
+        );
+        assert_eq!(
+            Generator::new()
+                .shell_escapes(true)
+                .generate("%n@%m", Shell::Zsh),
+            Ok("%n@%m".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_zsh_native() {
+        let mut generator = Generator::new();
+        generator.native_colors(true);
+
+        assert_eq!(
+            generator.generate("{0,#ff8800,bold,italic:x}", Shell::Zsh),
+            Ok("%F{#ff8800}\u{E0B6}%F{0}%K{#ff8800}%B%{\x1b[3m%}x%{\x1b[0m%}%f%k%b%u%F{#ff8800}\u{E0B4}%{\x1b[0m%}%f%k%b%u".to_string())
+        );
+        assert_eq!(
+            generator.generate("{0,1:x}", Shell::Bash),
+            generate("{0,1:x}", Shell::Bash)
+        );
+    }
+
+    #[test]
+    fn nested_bold_zsh_native() {
+        let mut generator = Generator::new();
+        generator.native_colors(true);
+
+        // Italic is turned on again after `%b`, which resets all attributes
+        assert_eq!(
+            generator.generate("{0,1,bold,italic,nosep:a{0,1,italic,nosep:b}}", Shell::Zsh),
+            Ok("%F{0}%K{1}%B%{\x1b[3m%}a%F{0}%K{1}%b%{\x1b[3m%}b%{\x1b[0m%}%f%k%b%u%{\x1b[0m%}%f%k%b%u".to_string())
+        );
+        assert_eq!(
+            generator.generate("{0,1,nosep:a{0,1,bold,nosep:b}c}", Shell::Zsh),
+            Ok("%F{0}%K{1}a%F{0}%K{1}%Bb%{\x1b[0m%}%f%k%b%u%K{1}%F{0}c%{\x1b[0m%}%f%k%b%u%{\x1b[0m%}%f%k%b%u".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_bash() {
+        assert_eq!(
+            generate("{0,1:xxx}", Shell::Bash),
+            Ok("\\[\x1b[38;5;1m\\]\\[\x1b[38;5;0m\\]\\[\x1b[48;5;1m\\]xxx\\[\x1b[0m\\]\\[\x1b[38;5;1m\\]\\[\x1b[0m\\]".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_fish() {
+        assert_eq!(
+            generate("{0,1:it's \\w}", Shell::Fish),
+            Ok("printf '%s' '\x1b[38;5;1m\x1b[38;5;0m\x1b[48;5;1mit\\'s \\\\w\x1b[0m\x1b[38;5;1m\x1b[0m'".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_powershell() {
+        let mut generator = Generator::new();
+
+        assert_eq!(
+            generator.generate("{0,1:\"$x\" `}", Shell::PowerShell),
+            Ok("\"`e[38;5;1m\u{E0B6}`e[38;5;0m`e[48;5;1m`\"`$x`\" ```e[0m`e[38;5;1m\u{E0B4}`e[0m\"".to_string())
+        );
+        assert_eq!(
+            generator
+                .legacy_escapes(true)
+                .generate("{0,1:x}", Shell::PowerShell),
+            Ok("\"$([char]27)[38;5;1m\u{E0B6}$([char]27)[38;5;0m$([char]27)[48;5;1mx$([char]27)[0m$([char]27)[38;5;1m\u{E0B4}$([char]27)[0m\"".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_nu() {
+        assert_eq!(
+            generate("{0,1:\"(x)\" \\w}", Shell::Nu),
+            Ok("$\"\\e[38;5;1m\u{E0B6}\\e[38;5;0m\\e[48;5;1m\\\"\\(x)\\\" \\\\w\\e[0m\\e[38;5;1m\u{E0B4}\\e[0m\"".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_tcsh() {
+        assert_eq!(
+            generate("{0,1:x!}{??}ok{|}no{/}", Shell::Tcsh),
+            Ok("%{\x1b[38;5;1m%}\u{E0B6}%{\x1b[38;5;0m%}%{\x1b[48;5;1m%}x\\!%{\x1b[0m%}%{\x1b[38;5;1m%}\u{E0B4}%{\x1b[0m%}ok".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_ksh() {
+        assert_eq!(
+            generate("{0,1:x!} {time:%H}", Shell::Ksh),
+            Ok("\x01\r\x01\x1b[38;5;1m\x01\u{E0B6}\x01\x1b[38;5;0m\x01\x01\x1b[48;5;1m\x01x!!\x01\x1b[0m\x01\x01\x1b[38;5;1m\x01\u{E0B4}\x01\x1b[0m\x01 $(date '+%H')".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_elvish() {
+        assert_eq!(
+            generate("{0,1:\"x\" {!(tilde-abbr $pwd)} {time:%H}}", Shell::Elvish),
+            Ok("put \"\\e[38;5;1m\u{E0B6}\\e[38;5;0m\\e[48;5;1m\\\"x\\\" \"(tilde-abbr $pwd)\" \"(e:date '+%H')\"\\e[0m\\e[38;5;1m\u{E0B4}\\e[0m\"".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_clink() {
+        assert_eq!(
+            generate("{0,1:\"x\" {!os.getcwd()} {time:%H}}", Shell::Clink),
+            Ok("local bubble = clink.promptfilter(1)\nfunction bubble:filter()\n    return \"\\027[38;5;1m\u{E0B6}\\027[38;5;0m\\027[48;5;1m\\\"x\\\" \" .. (os.getcwd()) .. \" \" .. os.date(\"%H\") .. \"\\027[0m\\027[38;5;1m\u{E0B4}\\027[0m\"\nend".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_readline() {
+        assert_eq!(
+            generate("{0,1:$x\\\\}", Shell::Readline),
+            Ok("\x01\x1b[38;5;1m\x02\u{E0B6}\x01\x1b[38;5;0m\x02\x01\x1b[48;5;1m\x02$x\\\x01\x1b[0m\x02\x01\x1b[38;5;1m\x02\u{E0B4}\x01\x1b[0m\x02".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_starship() {
+        assert_eq!(
+            generate("{33,#303030,bold:[x] {!$directory}} $", Shell::Starship),
+            Ok("[\u{E0B6}](fg:#303030)[\\[x\\] $directory](fg:33 bg:#303030 bold)[\u{E0B4}](fg:#303030) \\$".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_xonsh() {
+        assert_eq!(
+            generate("{0,1:\\{x\\} {!{cwd}}}{??}ok{|}no{/}", Shell::Xonsh),
+            Ok("\x1b[38;5;1m\u{E0B6}\x1b[38;5;0m\x1b[48;5;1m{{x}} {cwd}\x1b[0m\x1b[38;5;1m\u{E0B4}\x1b[0mok".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_oils() {
+        assert_eq!(
+            generate("{0,1:x} {time:%H}", Shell::Oils),
+            Ok("\\[\x1b[38;5;1m\\]\u{E0B6}\\[\x1b[38;5;0m\\]\\[\x1b[48;5;1m\\]x\\[\x1b[0m\\]\\[\x1b[38;5;1m\\]\u{E0B4}\\[\x1b[0m\\] $(date '+%H')".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_tmux() {
+        assert_eq!(
+            generate("{0,1,bold:#1 100% {time:%H}} {link:x|y}", Shell::Tmux),
+            Ok("#[fg=colour1]\u{E0B6}#[fg=colour0]#[bg=colour1]#[bold]##1 100%% %H#[default]#[fg=colour1]\u{E0B4}#[default] y".to_string())
+        );
+        assert_eq!(
+            generate("{#ff8800,1,bold,dim:x{,,dim:y}}", Shell::Tmux),
+            Ok("#[fg=colour1]\u{E0B6}#[fg=#ff8800]#[bg=colour1]#[bold]#[dim]x#[fg=#ff8800]#[bg=colour1]#[nobold,nodim]#[dim]y#[default]#[fg=colour1]\u{E0B4}#[default]".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_screen() {
+        assert_eq!(
+            generate("{15,#ff8800,bold,italic:100%{fill}}", Shell::Screen),
+            Ok("%{.Y}\u{E0B6}%{.W}%{Y.}%{+b}100%%%=%{= dd}%{.Y}\u{E0B4}%{= dd}".to_string())
+        );
+    }
+
+    #[test]
+    fn one_section_vim() {
+        assert_eq!(
+            generate("{15,#ff8800,bold,dim:100%{fill}it's} {0,1:x} {time:%H}", Shell::Vim),
+            Ok("highlight Bubble1 ctermfg=11 ctermbg=NONE guifg=#ff8800 guibg=NONE cterm=NONE gui=NONE\n\
+                highlight Bubble2 ctermfg=15 ctermbg=11 guifg=#ffffff guibg=#ff8800 cterm=bold gui=bold\n\
+                highlight Bubble3 ctermfg=1 ctermbg=NONE guifg=#800000 guibg=NONE cterm=NONE gui=NONE\n\
+                highlight Bubble4 ctermfg=0 ctermbg=1 guifg=#000000 guibg=#800000 cterm=NONE gui=NONE\n\
+                let &statusline = '%#Bubble1#\u{E0B6}%#Bubble2#100%%%=it''s%#Bubble1#\u{E0B4}%* %#Bubble3#\u{E0B6}%#Bubble4#x%#Bubble3#\u{E0B4}%* %{strftime(''%H'')}'".to_string())
+        );
+    }
+
+    #[test]
+    fn html() {
+        assert_eq!(
+            render_html("{15,#ff8800,bold,underline:<a&b>}\n{,4,reverse:x} {link:u|y}"),
+            Ok("<span style=\"color:#ff8800\">\u{E0B6}</span><span style=\"color:#ffffff;background:#ff8800;font-weight:bold;text-decoration:underline\">&lt;a&amp;b&gt;</span><span style=\"color:#ff8800\">\u{E0B4}</span>\n<span style=\"color:#000080\">\u{E0B6}</span><span style=\"color:#000080\">x</span><span style=\"color:#000080\">\u{E0B4}</span> y".to_string())
+        );
+        assert_eq!(
+            render_html("{title:t}{1,2,sep=<>:a}{!<b>}"),
+            Ok("<span style=\"color:#008000\">&lt;</span><span style=\"color:#800000;background:#008000\">a</span><span style=\"color:#008000\">&gt;</span>&lt;b&gt;".to_string())
+        );
+    }
+
+    #[test]
+    fn svg() {
+        assert_eq!(
+            render_svg("{15,1,bold:a<b}\n{0,2,sep=arrow:xy}"),
+            Ok("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"45\" height=\"36\" font-family=\"monospace\" font-size=\"15\">\n\
+                <rect width=\"45\" height=\"36\" fill=\"#1c1c1c\"/>\n\
+                <path d=\"M9 0A9 9 0 0 0 9 18Z\" fill=\"#800000\"/>\n\
+                <rect x=\"9\" y=\"0\" width=\"27\" height=\"18\" fill=\"#800000\"/>\n\
+                <text x=\"9\" y=\"14\" textLength=\"27\" lengthAdjust=\"spacingAndGlyphs\" fill=\"#ffffff\" style=\"font-weight:bold\" xml:space=\"preserve\">a&lt;b</text>\n\
+                <path d=\"M36 0A9 9 0 0 1 36 18Z\" fill=\"#800000\"/>\n\
+                <path d=\"M9 18L0 27L9 36Z\" fill=\"#008000\"/>\n\
+                <rect x=\"9\" y=\"18\" width=\"18\" height=\"18\" fill=\"#008000\"/>\n\
+                <text x=\"9\" y=\"32\" textLength=\"18\" lengthAdjust=\"spacingAndGlyphs\" fill=\"#000000\" style=\"\" xml:space=\"preserve\">xy</text>\n\
+                <path d=\"M27 18L36 27L27 36Z\" fill=\"#008000\"/>\n\
+                </svg>".to_string())
+        );
+    }
+
+    #[test]
+    fn plain() {
+        let mut generator = Generator::new();
+        generator.plain(true);
+
+        assert_eq!(
+            generator.generate(
+                "{0,1,bold:a {2,3:b}} {link:u|c} {15,4,sep=arrow:d}",
+                Shell::Bash
+            ),
+            Ok("a b c d".to_string())
+        );
+        assert_eq!(
+            generator
+                .ascii(true)
+                .generate("{0,1:a {2,3:b}} {15,4,sep=arrow:d{15,4:e}}", Shell::Bash),
+            Ok("(a (b) <de)".to_string())
+        );
+    }
+
+    #[test]
+    fn color_modes() {
+        let options = Options::new(Shell::None);
+
+        assert_eq!(
+            generate("{#ff8800,#d0d0d0,bold:x}", options.color_mode(ColorMode::Palette256)),
+            Ok("\x1b[38;5;252m\u{E0B6}\x1b[38;5;208m\x1b[48;5;252m\x1b[1mx\x1b[0m\x1b[38;5;252m\u{E0B4}\x1b[0m".to_string())
+        );
+        assert_eq!(
+            generate(
+                "{#ff8800,#d0d0d0,bold:x}",
+                options.color_mode(ColorMode::None)
+            ),
+            Ok("\u{E0B6}\x1b[1mx\x1b[0m\u{E0B4}\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn color_mode_detection() {
+        assert!(
+            detect_color_mode(Some("xterm"), Some("truecolor"), Some(8)) == ColorMode::TrueColor
+        );
+        assert!(detect_color_mode(Some("xterm"), None, Some(256)) == ColorMode::Palette256);
+        assert!(detect_color_mode(Some("linux"), None, Some(8)) == ColorMode::Basic8);
+        assert!(detect_color_mode(Some("xterm-256color"), None, None) == ColorMode::Palette256);
+        assert!(detect_color_mode(Some("dumb"), None, Some(8)) == ColorMode::None);
+        assert!(detect_color_mode(None, None, None) == ColorMode::None);
+
+        let mut legacy = vec![0x1a, 0x01, 3, 0, 1, 0, 14, 0, 0, 0, 0, 0, b'a', b'b', 0, 1];
+        legacy.extend((0..14).flat_map(|i| [i, 0]));
+        legacy[16 + 26] = 8;
+        assert_eq!(parse_terminfo_colors(&legacy), Some(8));
+
+        let mut extended = vec![0x1e, 0x02, 2, 0, 0, 0, 14, 0, 0, 0, 0, 0, b'a', 0];
+        extended.extend((0..14).flat_map(|_| [0xff, 0xff, 0xff, 0xff]));
+        extended[14 + 52..14 + 56].copy_from_slice(&256i32.to_le_bytes());
+        assert_eq!(parse_terminfo_colors(&extended), Some(256));
+        assert_eq!(parse_terminfo_colors(&extended[..20]), None);
+    }
+
+    #[test]
+    fn color_environment() {
+        assert!(env_color_mode(ColorMode::TrueColor, true, false) == ColorMode::None);
+        assert!(env_color_mode(ColorMode::TrueColor, true, true) == ColorMode::TrueColor);
+        assert!(env_color_mode(ColorMode::None, false, true) == ColorMode::Basic16);
+        assert!(env_color_mode(ColorMode::Palette256, false, false) == ColorMode::Palette256);
+    }
+
+    #[test]
+    fn custom_shell() {
+        struct Dialect;
+
+        impl ShellDialect for Dialect {
+            fn non_printing_start(&self) -> &str {
+                "<"
+            }
+
+            fn non_printing_end(&self) -> &str {
+                ">"
+            }
+
+            fn escape_text(&self, text: &str) -> String {
+                text.replace('$', "$$")
+            }
+        }
+
+        let mut generator = Generator::new();
+        generator.ascii(true);
+
+        assert_eq!(
+            generator.generate("{1,2:$x}{??}!{|}?{/}", Shell::Custom(&Dialect)),
+            Ok(
+                "<\x1b[38;5;2m>(<\x1b[38;5;1m><\x1b[48;5;2m>$$x<\x1b[0m><\x1b[38;5;2m>)<\x1b[0m>!"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn custom_encoder() {
+        struct Encoder;
+
+        impl ColorEncoder for Encoder {
+            fn push_escape(&self, buffer: &mut String, escape: Escape, _: Shell) {
+                let code = match escape {
+                    Escape::Foreground(Color::Indexed(index)) => format!("f{}", index),
+                    Escape::Background(Color::Indexed(index)) => format!("b{}", index),
+                    Escape::Reset => "r".to_string(),
+                    escape => format!("{:?}", escape),
+                };
+
+                buffer.push_str(&format!("[{}]", code));
+            }
+        }
+
+        struct Dialect;
+
+        impl ShellDialect for Dialect {
+            fn color_encoder(&self) -> Option<&dyn ColorEncoder> {
+                Some(&Encoder)
+            }
+        }
+
+        let mut generator = Generator::new();
+        generator.ascii(true);
+
+        assert_eq!(
+            generator.generate("{1,2,bold:x}", Shell::Custom(&Dialect)),
+            Ok("[f2]([f1][b2][AttributeOn(Bold)]x[r][f2])[r]".to_string())
+        );
+
+        generator.plain(true);
+        assert_eq!(
+            generator.generate("{1,2,bold:x}", Shell::Custom(&Dialect)),
+            Ok("(x)".to_string())
+        );
+    }
+
+    #[test]
+    fn shell_names() {
+        assert!(matches!(shell_by_name("-zsh"), Some(Shell::Zsh)));
+        assert!(matches!(shell_by_name("mksh"), Some(Shell::Ksh)));
+        assert!(matches!(shell_by_name("pwsh.exe"), Some(Shell::PowerShell)));
+        assert!(matches!(shell_by_name("ysh"), Some(Shell::Oils)));
+        assert!(shell_by_name("cargo").is_none());
+
+        assert!(matches!("PowerShell".parse(), Ok(Shell::PowerShell)));
+        assert!(matches!("any".parse(), Ok(Shell::None)));
+        assert!(matches!("mksh".parse(), Ok(Shell::Ksh)));
+        assert_eq!(
+            kind("cmd".parse::<Shell>()).err(),
+            Some(ErrorKind::UnknownShell("cmd".to_string()))
+        );
+
+        for name in &[
+            "none",
+            "zsh",
+            "bash",
+            "fish",
+            "powershell",
+            "nu",
+            "tcsh",
+            "ksh",
+        ] {
+            assert_eq!(&name.parse::<Shell>().unwrap().to_string(), name);
+        }
+        for name in &["elvish", "xonsh", "oils", "tmux", "screen", "vim"] {
+            assert_eq!(&name.parse::<Shell>().unwrap().to_string(), name);
+        }
+        for name in &["clink", "starship", "readline"] {
+            assert_eq!(&name.parse::<Shell>().unwrap().to_string(), name);
+        }
+
+        struct Dialect;
+
+        impl ShellDialect for Dialect {
+            fn name(&self) -> &str {
+                "mysh"
+            }
+        }
+
+        // Custom dialects are displayed by name, but don't parse back
+        assert_eq!(Shell::Custom(&Dialect).to_string(), "mysh");
+        assert_eq!(
+            kind("mysh".parse::<Shell>()).err(),
+            Some(ErrorKind::UnknownShell("mysh".to_string()))
+        );
+    }
+
+    #[test]
+    fn continuation_prompt() {
+        let generator = Generator::new();
+
+        assert_eq!(
+            generator.generate_continuation("{0,1:a} {2,3,bold:b {4,5:c}} ", Shell::None),
+            generate("{2,3,bold:…} ", Shell::None)
+        );
+        assert_eq!(
+            generator.generate_continuation("{default:15,4}$ ", Shell::None),
+            generate("{default:15,4}… ", Shell::None)
+        );
+        assert_eq!(
+            Generator::new()
+                .ascii(true)
+                .generate_continuation("{1,2:x}", Shell::Bash),
+            Ok("\\[\x1b[38;5;2m\\](\\[\x1b[38;5;1m\\]\\[\x1b[48;5;2m\\]...\\[\x1b[0m\\]\\[\x1b[38;5;2m\\])\\[\x1b[0m\\]".to_string())
+        );
+    }
+
+    #[test]
+    fn right_prompt() {
+        let generator = Generator::new();
+
+        assert_eq!(
+            generator.generate_right(" {1,2:a}{3,4:b}  ", Shell::Zsh),
+            Ok(" %{\x1b[38;5;2m%}\u{E0B6}%{\x1b[38;5;1m%}%{\x1b[48;5;2m%}a%{\x1b[0m%}%{\x1b[0m%}%{\x1b[38;5;4m%}\u{E0B6}%{\x1b[38;5;3m%}%{\x1b[48;5;4m%}b%{\x1b[0m%}%{\x1b[0m%}".to_string())
+        );
+        assert_eq!(
+            kind(generator.generate_right("{1,2:a{fill}}", Shell::Zsh)),
+            Err(ErrorKind::MultilineRightPrompt)
+        );
+        assert_eq!(
+            kind(generator.generate_right("a\\nb", Shell::Zsh)),
+            Err(ErrorKind::MultilineRightPrompt)
+        );
+    }
+
+    #[test]
+    fn semantic_marks() {
+        let mut generator = Generator::new();
+        generator.semantic_marks(true);
+
+        assert_eq!(
+            generator.generate("$ ", Shell::Bash),
+            Ok("\\[\x1b]133;A\x07\\]\\\\$ \\[\x1b]133;B\x07\\]".to_string())
+        );
+        assert_eq!(
+            generator.generate("> ", Shell::Fish),
+            Ok("printf '%s' '\x1b]133;A\x07> \x1b]133;B\x07'".to_string())
+        );
+        assert_eq!(generator.generate("#", Shell::Tmux), Ok("##".to_string()));
+        assert_eq!(
+            generator.generate_right("{1,2:a}", Shell::Zsh),
+            Generator::new().generate_right("{1,2:a}", Shell::Zsh)
+        );
+        assert_eq!(
+            generator.generate_continuation("{1,2:a} ", Shell::Zsh),
+            Generator::new().generate_continuation("{1,2:a} ", Shell::Zsh)
+        );
+    }
+
+    #[test]
+    fn cwd_report() {
+        let mut generator = Generator::new();
+        generator.report_cwd(true);
+
+        assert_eq!(
+            generator.generate("$ ", Shell::Zsh),
+            Ok("$ %{\x1b]7;file://%M%/\x07%}".to_string())
+        );
+        assert_eq!(
+            generator.generate("> ", Shell::Fish),
+            Ok("printf '%s' '> \x1b]7;file://'$hostname\"$PWD\"'\x07'".to_string())
+        );
+        assert_eq!(
+            generator.generate("> ", Shell::Clink),
+            Ok("local bubble = clink.promptfilter(1)\nfunction bubble:filter()\n    return \"> \\027]7;file://\" .. ((os.getenv(\"COMPUTERNAME\") or \"\") .. \"/\" .. os.getcwd():gsub(\"\\\\\", \"/\")) .. \"\\007\"\nend".to_string())
+        );
+
+        let cwd = env::current_dir().unwrap();
+        assert_eq!(
+            generator.generate("", Shell::None),
+            Ok(format!("\x1b]7;file://{}{}\x07", hostname(), cwd.display()))
+        );
+        assert_eq!(
+            generator.generate_right("{1,2:a}", Shell::Zsh),
+            Generator::new().generate_right("{1,2:a}", Shell::Zsh)
+        );
+        assert_eq!(
+            generator.generate_continuation("{1,2:a} ", Shell::Zsh),
+            Generator::new().generate_continuation("{1,2:a} ", Shell::Zsh)
+        );
+    }
+
+    #[test]
+    fn title() {
+        assert_eq!(
+            generate("{title:{!\\w} - {1,2:bash}}{3,4:x}{fill}", Shell::Bash),
+            generate("{!\\[\x1b]0;\\w - bash\x07\\]}{3,4:x}{fill}", Shell::Bash)
+        );
+        assert_eq!(generate("{title:x}", Shell::Tmux), Ok(String::new()));
+        assert_eq!(
+            generate("{title:$}", Shell::Zsh),
+            Ok("%{\x1b]0;$\x07%}".to_string())
+        );
+    }
+
+    #[test]
+    fn basic_colors() {
+        let generator = Generator::new();
+        let options = Options::new(Shell::None).color_mode(ColorMode::Basic16);
+
+        assert_eq!(
+            generator.generate("{#ff8800,default:x{196,12:y}}", options),
+            Ok(
+                "\x1b[93m\x1b[49mx\x1b[94m\u{E0B6}\x1b[91m\x1b[104my\x1b[0m\x1b[94m\u{E0B4}\x1b[0m"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            render_svg("{9,1:x}"),
+            Ok(svg_image(&generate("{9,1:x}", options).unwrap()))
+        );
+    }
+
+    #[test]
+    fn eight_colors() {
+        let mut generator = Generator::new();
+        let options = Options::new(Shell::None).color_mode(ColorMode::Basic8);
+
+        assert_eq!(
+            generator.generate("{#ff8800,12,bold,italic,underline:x}", options),
+            Ok(
+                "\x1b[34m\u{E0B6}\x1b[33m\x1b[44m\x1b[1mx\x1b[0m\x1b[34m\u{E0B4}\x1b[0m"
+                    .to_string()
+            )
+        );
+
+        let mut table = EIGHT_COLOR_TABLE;
+        table[12] = 6;
+        generator.color_table(table);
+
+        assert_eq!(
+            generator.generate("{11,12:x}", options),
+            Ok("\x1b[36m\u{E0B6}\x1b[33m\x1b[46mx\x1b[0m\x1b[36m\u{E0B4}\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn sequential_sections() {
+        assert_eq!(
+            generate("{0, 1:xxx} {100,200:yyy}", Shell::None),
+            Ok("\x1b[38;5;1m\x1b[38;5;0m\x1b[48;5;1mxxx\x1b[0m\x1b[38;5;1m\x1b[0m \x1b[38;5;200m\x1b[38;5;100m\x1b[48;5;200myyy\x1b[0m\x1b[38;5;200m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn overlap_left() {
+        assert_eq!(
+            generate("{0,1:xxx {100,200:yyy}}", Shell::None),
+            Ok("\x1b[38;5;1m\x1b[38;5;0m\x1b[48;5;1mxxx \x1b[38;5;200m\x1b[38;5;100m\x1b[48;5;200myyy\x1b[0m\x1b[38;5;200m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn overlap_right() {
+        assert_eq!(
+            generate("{0,1 :{100,200:yyy} xxx}", Shell::None),
+            Ok("\x1b[38;5;200m\x1b[38;5;100m\x1b[48;5;200myyy\x1b[0m\x1b[48;5;1m\x1b[38;5;200m\x1b[38;5;0m xxx\x1b[0m\x1b[38;5;1m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn bad_fg() {
+        assert_eq!(
+            kind(generate("{999,1:xxx}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "fg",
+                reason: "number too large to fit in target type".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn bad_bg() {
+        assert_eq!(
+            kind(generate("{1,-9:xxx}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "bg",
+                reason: "invalid digit found in string".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn named_colors() {
+        assert_eq!(
+            generate("{white,Bright-Blue:xxx}", Shell::None),
+            generate("{7,12:xxx}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn unknown_color_name() {
+        assert_eq!(
+            kind(generate("{purple,1:xxx}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "fg",
+                reason: "unknown color name 'purple'".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn hex_colors() {
+        assert_eq!(
+            generate("{#FF8800,#202020:xxx}", Shell::None),
+            Ok("\x1b[38;2;32;32;32m\x1b[38;2;255;136;0m\x1b[48;2;32;32;32mxxx\x1b[0m\x1b[38;2;32;32;32m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn bad_hex_color() {
+        assert_eq!(
+            kind(generate("{#ff88,0:xxx}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "fg",
+                reason: "malformed hex color '#ff88'".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn rgb_colors() {
+        assert_eq!(
+            generate("{rgb(255, 136, 0),rgb(32,32,32):xxx}", Shell::None),
+            generate("{#ff8800,#202020:xxx}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn bad_rgb_color() {
+        assert_eq!(
+            kind(generate("{0,rgb(1,2):xxx}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "bg",
+                reason: "malformed rgb color 'rgb(1,2)'".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn default_colors() {
+        assert_eq!(
+            generate("{2,default:xxx}", Shell::None),
+            Ok("\x1b[38;5;2m\x1b[49mxxx\x1b[0m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn default_foreground() {
+        assert_eq!(
+            generate("{default,1:xxx}", Shell::None),
+            Ok("\x1b[38;5;1m\x1b[39m\x1b[48;5;1mxxx\x1b[0m\x1b[38;5;1m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn foreground_only() {
+        assert_eq!(
+            generate("{0,1:x{2,:y}z}", Shell::None),
+            Ok("\x1b[38;5;1m\x1b[38;5;0m\x1b[48;5;1mx\x1b[38;5;2m\x1b[48;5;1my\x1b[0m\x1b[48;5;1m\x1b[38;5;0mz\x1b[0m\x1b[38;5;1m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn background_only() {
+        assert_eq!(
+            generate("{0,1:x{,2:y}z}", Shell::None),
+            generate("{0,1:x{0,2:y}z}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn empty_meta() {
+        assert_eq!(
+            generate("{0,1,bold:x{:y}z}", Shell::None),
+            generate("{0,1,bold:xyz}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn empty_meta_top_level() {
+        assert_eq!(generate("x{:y}z", Shell::None), Ok("xyz".to_string()));
+    }
+
+    #[test]
+    fn escaped_braces() {
+        assert_eq!(
+            generate("\\{x\\} \\\\ \\w", Shell::None),
+            Ok("{x} \\ \\w".to_string())
+        );
+    }
+
+    #[test]
+    fn escaped_meta() {
+        assert_eq!(
+            kind(generate("{0\\,1:xxx}", Shell::None)),
+            Err(ErrorKind::MissingMeta)
+        );
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(
+            generate("{# a comment }{0,1:x{#}y}{# another \\} one }", Shell::None),
+            generate("{0,1:xy}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn unterminated_comment() {
+        assert_eq!(
+            kind(generate("x{# oops", Shell::None)),
+            Err(ErrorKind::UnterminatedComment)
+        );
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(
+            generate(
+                "@fg=15 @bg=#202020  @alt=@bg {@fg,@bg:x{@bg,@fg:y}}",
+                Shell::None
+            ),
+            generate("{15,#202020:x{#202020,15:y}}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn not_a_declaration() {
+        assert_eq!(generate("@home", Shell::None), Ok("@home".to_string()));
+    }
+
+    #[test]
+    fn unknown_variable() {
+        assert_eq!(
+            kind(generate("@fg=15 {@fg,@bg:x}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "bg",
+                reason: "unknown variable '@bg'".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn bad_variable() {
+        assert_eq!(
+            kind(generate("@fg=999 {@fg,0:x}", Shell::None)),
+            Err(ErrorKind::InvalidVariable {
+                name: "fg".to_string(),
+                reason: "number too large to fit in target type".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn named_styles() {
+        assert_eq!(
+            generate("@red=1 %error=15,@red,bold\n{%error:x}", Shell::None),
+            generate("{15,1,bold:x}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn unknown_style() {
+        assert_eq!(
+            kind(generate("{%error:x}", Shell::None)),
+            Err(ErrorKind::UnknownStyle("error".to_string()))
+        );
+    }
+
+    #[test]
+    fn includes() {
+        let mut generator = Generator::new();
+        generator
+            .partial("palette", "@fg=15 @bg=4")
+            .partial("user", "{@fg,@bg:%n{>host}}")
+            .partial("host", "@%m");
+
+        assert_eq!(
+            generator.generate("{>palette} {>user} \\{>user\\}{# {>nope\\} }", Shell::None),
+            generate("{15,4:%n@%m} \\{>user\\}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn unknown_partial() {
+        assert_eq!(
+            kind(generate("{>user}", Shell::None)),
+            Err(ErrorKind::UnknownPartial("user".to_string()))
+        );
+    }
+
+    #[test]
+    fn recursive_include() {
+        let mut generator = Generator::new();
+        generator.partial("a", "{>b}").partial("b", "x{>a}");
+
+        assert_eq!(
+            kind(generator.generate("{>a}", Shell::None)),
+            Err(ErrorKind::RecursiveInclude("a".to_string()))
+        );
+    }
+
+    #[test]
+    fn conditionals() {
+        env::set_var("BUBBLEPROMPT_TEST_SET", "1");
+        env::set_var("BUBBLEPROMPT_TEST_EMPTY", "");
+
+        assert_eq!(
+            generate(
+                "{?BUBBLEPROMPT_TEST_SET}{0,1:x{?BUBBLEPROMPT_TEST_EMPTY}y{/}}{/}{?BUBBLEPROMPT_TEST_UNSET}z{/}",
+                Shell::None
+            ),
+            generate("{0,1:x}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn unterminated_conditional() {
+        assert_eq!(
+            kind(generate("{?HOME}{0,1:x}", Shell::None)),
+            Err(ErrorKind::UnterminatedConditional)
+        );
+    }
+
+    #[test]
+    fn unexpected_end_of_conditional() {
+        assert_eq!(
+            kind(generate("{?HOME}{0,1:x{/}}", Shell::None)),
+            Err(ErrorKind::Unexpected("{/}".to_string()))
+        );
+    }
+
+    #[test]
+    fn unbalanced_braces() {
+        assert_eq!(
+            kind(generate("{0,1:x}}", Shell::None)),
+            Err(ErrorKind::UnbalancedBraces)
+        );
+        assert_eq!(
+            kind(generate("{0,1:x", Shell::None)),
+            Err(ErrorKind::UnbalancedBraces)
+        );
+    }
+
+    #[test]
+    fn error_spans() {
+        let span = |template| generate(template, Shell::None).unwrap_err().span();
+
+        assert_eq!(
+            span("a\n{0,1:x}}"),
+            Some(Span {
+                start: 9,
+                end: 10,
+                line: 2,
+                column: 8,
+            })
+        );
+        assert_eq!(
+            span("{0,1:\u{E0B0} {1,x:a}}"),
+            Some(Span {
+                start: 9,
+                end: 14,
+                line: 1,
+                column: 8,
+            })
+        );
+        assert_eq!(span("@fg=zz {@fg,0:a}").map(|span| span.end), Some(6));
+        assert_eq!(span("{0,1:{/}").map(|span| span.end - span.start), Some(3));
+
+        let mut generator = Generator::new();
+        generator.partial("x", "{1,x:a}");
+        assert_eq!(
+            generator.generate("{>x}", Shell::None).unwrap_err().span(),
+            None
+        );
+    }
+
+    #[test]
+    fn check_errors() {
+        let kinds = |template| -> Vec<ErrorKind> {
+            check(template)
+                .unwrap_err()
+                .into_iter()
+                .map(|error| error.kind)
+                .collect()
+        };
+
+        assert_eq!(check("@fg=1 {@fg,0:a}"), Ok(()));
+        assert_eq!(
+            kinds("@fg=zz {@fg,0:a} {1,x:b {hr:ab}} c} {?a ? b}"),
+            vec![
+                ErrorKind::InvalidVariable {
+                    name: "fg".to_string(),
+                    reason: "unknown color name 'zz'".to_string(),
+                },
+                ErrorKind::InvalidColor {
+                    field: "bg",
+                    reason: "unknown color name 'x'".to_string(),
+                },
+                ErrorKind::InvalidHr,
+                ErrorKind::UnbalancedBraces,
+                ErrorKind::MissingColon,
+            ]
+        );
+        assert_eq!(kinds("{0,1:{0,1:x}"), vec![ErrorKind::UnbalancedBraces]);
+        assert_eq!(
+            kinds("{>nope} {1,x:a}"),
+            vec![ErrorKind::UnknownPartial("nope".to_string())]
+        );
+    }
+
+    #[test]
+    fn parse_ast() {
+        let ast = parse("{pragma:v2}{33,#ff8800:a {,1@x:b}} {?X}y{/}").unwrap();
+        assert_eq!(ast.version(), 2);
+        assert!(ast.default_section().is_none());
+
+        let (section, children) = match &ast.nodes[..] {
+            [Node::Section(section, children), Node::Text(space), Node::Conditional(Condition::Variable(name), then, otherwise)] =>
+            {
+                assert_eq!(space, " ");
+                assert_eq!(name, "X");
+                assert!(matches!(&then[..], [Node::Text(text)] if text == "y"));
+                assert!(otherwise.is_empty());
+                (section, children)
+            }
+            nodes => panic!("unexpected nodes {:?}", nodes),
+        };
+
+        assert_eq!(section.style().fg, Color::Indexed(33));
+        assert_eq!(section.style().bg, Color::Rgb(255, 136, 0));
+        assert_eq!(section.name(), None);
+
+        match &children[..] {
+            [Node::Text(text), Node::Section(nested, _)] => {
+                assert_eq!(text, "a ");
+                assert_eq!(nested.name(), Some("x"));
+                assert_eq!(nested.style().fg, Color::Indexed(33));
+                assert!(!nested.style().attrs.contains(Attribute::Bold));
+            }
+            nodes => panic!("unexpected nodes {:?}", nodes),
+        }
+
+        assert_eq!(
+            kind(parse("{0,1:x").map(|_| ())),
+            Err(ErrorKind::UnbalancedBraces)
+        );
+    }
+
+    #[test]
+    fn render_ast() {
+        let mut ast = parse("{default:7,0}{33,236:a}").unwrap();
+        let style = Style::new().fg(1).bg(2);
+
+        ast.nodes.push(Node::Text(" ".to_string()));
+        ast.nodes.push(Node::Section(
+            Section::new(style),
+            vec![Node::Text("b".to_string())],
+        ));
+
+        assert_eq!(
+            render(&ast, Shell::Bash),
+            generate("{default:7,0}{33,236:a} {1,2:b}", Shell::Bash)
+        );
+
+        let ast = Ast::new(vec![Node::Section(
+            Section::new(style),
+            vec![Node::Text("c".to_string())],
+        )]);
+        assert_eq!(render(&ast, Shell::Zsh), generate("{1,2:c}", Shell::Zsh));
+    }
+
+    #[test]
+    fn prompt_chunks() {
+        let template = compile("{1,2:a} {3,4:b}c").unwrap();
+        let content = HashMap::new();
+
+        for shell in &[
+            Shell::None,
+            Shell::Bash,
+            Shell::Zsh,
+            Shell::Ksh,
+            Shell::Readline,
+        ] {
+            let chunks: Vec<_> = template.chunks(*shell, &content).unwrap().collect();
+
+            assert_eq!(
+                chunks.iter().map(Chunk::as_str).collect::<String>(),
+                template.render(*shell, &content).unwrap()
+            );
+
+            let text: Vec<_> = chunks
+                .iter()
+                .filter_map(|chunk| match chunk {
+                    Chunk::Text(text) => Some(text.as_ref()),
+                    _ => None,
+                })
+                .collect();
+
+            assert_eq!(
+                text,
+                ["\u{e0b6}", "a", "\u{e0b4}", " ", "\u{e0b6}", "b", "\u{e0b4}", "c"]
+            );
+            assert!(chunks
+                .iter()
+                .any(|chunk| matches!(chunk, Chunk::Text(Cow::Borrowed("a")))));
+            assert!(!chunks
+                .windows(2)
+                .any(|pair| matches!(pair, [Chunk::Escape(_), Chunk::Escape(_)])));
+        }
+
+        // Placeholders are borrowed from the content, escaped text and titles are copied
+        let template = compile("{{name}} 100%{title:{{name}}}").unwrap();
+        let mut content = HashMap::new();
+        content.insert("name", "me".to_string());
+
+        let chunks: Vec<_> = template.chunks(Shell::Zsh, &content).unwrap().collect();
+        assert!(matches!(chunks[0], Chunk::Text(Cow::Borrowed("me"))));
+        assert_eq!(
+            chunks[1..],
+            [
+                Chunk::Text(" 100%%".into()),
+                Chunk::Escape("%{\x1b]0;me\x07%}".into())
+            ]
+        );
+
+        // Lines with fills and prompts that are quoted as a whole are mixed
+        let template = compile("a{fill}b\nc").unwrap();
+        let chunks: Vec<_> = template.chunks(Shell::Zsh, &content).unwrap().collect();
+
+        assert!(matches!(
+            &chunks[..],
+            [Chunk::Text(a), Chunk::Mixed(line), Chunk::Text(c)]
+                if a == "a" && line.ends_with('b') && c == "\nc"
+        ));
+        assert_eq!(
+            chunks.iter().map(Chunk::as_str).collect::<String>(),
+            template.render(Shell::Zsh, &content).unwrap()
+        );
+        assert_eq!(
+            template
+                .chunks(Shell::Fish, &content)
+                .unwrap()
+                .collect::<Vec<_>>(),
+            [Chunk::Mixed(
+                template.render(Shell::Fish, &content).unwrap().into()
+            )]
+        );
+
+        // Templates that are parsed again on each render are chunked the same way
+        let template = compile("{hash($NAME),0:x}").unwrap();
+        content.insert("NAME", "a".to_string());
+
+        assert_eq!(
+            template
+                .chunks(Shell::Bash, &content)
+                .unwrap()
+                .map(|chunk| chunk.as_str().to_string())
+                .collect::<String>(),
+            template.render(Shell::Bash, &content).unwrap()
+        );
+    }
+
+    #[test]
+    fn generate_to_writer() {
+        struct Failing;
+
+        impl fmt::Write for Failing {
+            fn write_str(&mut self, _: &str) -> fmt::Result {
+                Err(fmt::Error)
+            }
+        }
+
+        let mut buffer = "$ ".to_string();
+        assert_eq!(generate_to("{1,2:a} ", Shell::Bash, &mut buffer), Ok(()));
+        assert_eq!(
+            buffer,
+            format!("$ {}", generate("{1,2:a} ", Shell::Bash).unwrap())
+        );
+
+        let mut written = buffer.clone();
+        assert_eq!(
+            kind(generate_to("{1,2:a", Shell::Bash, &mut written)),
+            Err(ErrorKind::UnbalancedBraces)
+        );
+        assert_eq!(written, buffer);
+        assert_eq!(
+            kind(generate_to("a", Shell::Bash, &mut Failing)),
+            Err(ErrorKind::Write)
+        );
+    }
+
+    #[test]
+    fn generate_to_streams() {
+        #[derive(Default)]
+        struct Parts(Vec<String>);
+
+        impl fmt::Write for Parts {
+            fn write_str(&mut self, part: &str) -> fmt::Result {
+                self.0.push(part.to_string());
+                Ok(())
+            }
+        }
+
+        let written = |template: &str, shell: Shell| {
+            let mut parts = Parts::default();
+            generate_to(template, shell, &mut parts).unwrap();
+            assert_eq!(parts.0.concat(), generate(template, shell).unwrap());
+            parts.0
+        };
+
+        let template = "{1,2:a}{3,4:b} c";
+        assert!(written(template, Shell::Bash).len() > 3);
+        assert!(written(template, Shell::Ksh).len() > 3);
+        assert_eq!(written(template, Shell::Fish).len(), 1);
+        assert_eq!(written(template, Shell::Vim).len(), 1);
+
+        // The rest of the line with the fill is written at once, after its width is known
+        let parts = written("{1,2:a}{hr:-}b\n{3,4:c}", Shell::Bash);
+        assert!(parts
+            .iter()
+            .any(|part| part.contains("COLUMNS") && part.contains('b')));
+        assert!(written("{1,2:a}{hr:-}b\n{3,4:c}", Shell::Zsh).len() > 1);
+        assert!(written("{1,2:a}{??}{3,4:b}{|}c{/}", Shell::Bash)
+            .iter()
+            .any(|part| part.contains("$(if")));
+    }
+
+    #[test]
+    fn compile_template() {
+        let template = "{default:7,0}{33,236@cwd:~} {1,0:{{branch}}}{?X}x{/}";
+        let compiled = Generator::new()
+            .static_time(true)
+            .compile(template)
+            .unwrap();
+
+        assert!(matches!(
+            &compiled.ast().nodes[..],
+            [Node::Section(..), Node::Text(_), Node::Section(_, children), Node::Conditional(..)]
+                if matches!(&children[..], [Node::Placeholder(name)] if name == "branch")
+        ));
+
+        for (cwd, branch) in &[("/tmp", "main"), ("/usr", "dev")] {
+            let mut content = HashMap::new();
+            content.insert("cwd", cwd.to_string());
+            content.insert("branch", branch.to_string());
+
+            assert_eq!(
+                compiled.render(Shell::Bash, &content),
+                Generator::new().generate_with(template, Shell::Bash, &content)
+            );
+        }
+
+        assert_eq!(
+            compiled.render(Shell::Zsh, &HashMap::new()),
+            generate("{default:7,0}{33,236:~} {1,0:}", Shell::Zsh)
+        );
+
+        // Content is filled while rendering, into optional, truncated and aligned sections
+        let template = "{0,1,optional: {{a}} }{2,3,max=4:{{a}}{{b}}}{4,5,width=6,align=right@c:x}";
+        let compiled = compile(template).unwrap();
+
+        for (a, b, c) in &[("", "", "c"), ("abc", "de f", "日本")] {
+            let mut content = HashMap::new();
+            content.insert("a", a.to_string());
+            content.insert("b", b.to_string());
+            content.insert("c", c.to_string());
+
+            assert_eq!(
+                compiled.render(Shell::Zsh, &content),
+                Generator::new().generate_with(template, Shell::Zsh, &content)
+            );
+        }
+
+        assert_eq!(
+            kind(compile("{0,1:x").map(|_| ())),
+            Err(ErrorKind::UnbalancedBraces)
+        );
+    }
+
+    #[test]
+    fn style_builder() {
+        let style = Style::new().fg(33).bg((255, 136, 0)).bold().underline();
+        assert_eq!(style.fg, Color::Indexed(33));
+        assert_eq!(style.bg, Color::Rgb(255, 136, 0));
+        assert!(style.attrs.contains(Attribute::Bold));
+        assert!(style.attrs.contains(Attribute::Underline));
+        assert!(!style.attrs.contains(Attribute::Italic));
+
+        let ast = Ast::new(vec![Node::Section(
+            Section::new(style),
+            vec![Node::Text("a".to_string())],
+        )]);
+        assert_eq!(
+            render(&ast, Shell::Bash),
+            generate("{33,#ff8800,bold,underline:a}", Shell::Bash)
+        );
+        assert_eq!(Style::new(), Style::default());
+    }
+
+    #[test]
+    fn prompt_builder() {
+        let mut builder = PromptBuilder::new();
+        builder
+            .segment(Style::new().fg(33).bg(236), "~/src {x}")
+            .segment(Style::new().fg(0).bg(2).bold(), "main")
+            .text(" $ ");
+
+        assert_eq!(
+            Ok(builder.render(Shell::Bash)),
+            generate("{33,236:~/src \\{x\\}}{0,2,bold:main} $ ", Shell::Bash)
+        );
+        assert_eq!(
+            Generator::new()
+                .plain(true)
+                .render(&builder.ast(), Shell::Zsh),
+            Ok("~/src {x}main $ ".to_string())
+        );
+        assert_eq!(PromptBuilder::new().render(Shell::None), "");
+    }
+
+    #[test]
+    fn map_styles() {
+        let mut ast =
+            parse("{default:7,0}{1,2,bold:a {3,4,bold,italic:b}} {?X}{5,6:c}{/}").unwrap();
+
+        ast.map_styles(|style| {
+            if let Color::Indexed(index) = style.fg {
+                style.fg = Color::Indexed(index + 10);
+            }
+
+            style.attrs.remove(Attribute::Bold);
+        });
+
+        assert_eq!(
+            render(&ast, Shell::None),
+            generate(
+                "{default:17,0}{11,2:a {13,4,italic:b}} {?X}{15,6:c}{/}",
+                Shell::None
+            )
+        );
+
+        let mut texts = Vec::new();
+        ast.walk(|node| {
+            if let Node::Text(text) = node {
+                texts.push(text.clone());
+            }
+        });
+        assert_eq!(texts, vec!["a ", "b", " ", "c"]);
+
+        ast.walk_mut(|node| {
+            if let Node::Text(text) = node {
+                *text = text.to_uppercase();
+            }
+        });
+        assert_eq!(
+            render(&ast, Shell::None),
+            generate(
+                "{default:17,0}{11,2:A {13,4,italic:B}} {?X}{15,6:C}{/}",
+                Shell::None
+            )
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_types() {
+        fn serializable<T: serde::Serialize + serde::de::DeserializeOwned>() {}
+
+        serializable::<Ast>();
+        serializable::<Node>();
+        serializable::<Style>();
+        serializable::<Color>();
+        serializable::<TemplateInfo>();
+
+        let template = "{default:7,0}@x=#ff8800 {@x,236,bold,italic,pad=1@cwd:~} {?X}y{/}{fill}";
+        let ast = parse(template).unwrap();
+        let json = serde_json::to_string(&ast).unwrap();
+        let ast: Ast = serde_json::from_str(&json).unwrap();
+
+        assert!(json.contains(r#""attrs":["bold","italic"]"#));
+        assert_eq!(
+            render(&ast, Shell::Zsh).unwrap(),
+            generate(template, Shell::Zsh).unwrap()
+        );
+    }
+
+    #[test]
+    fn template_tokens() {
+        let template = "@fg=1 {@fg,0:a\\{ {?X ? y : z}}\n{# c}{!r{1}}{title:t}{0,1:";
+        let kinds: Vec<Token> = tokens(template).map(|(token, _)| token).collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                Token::Header("@fg=1 "),
+                Token::OpenSection("@fg,0"),
+                Token::Text("a"),
+                Token::Escape("\\{"),
+                Token::Text(" "),
+                Token::OpenBlock("{?X ?"),
+                Token::Text(" y : z"),
+                Token::CloseBlock,
+                Token::CloseSection,
+                Token::Text("\n"),
+                Token::Comment("{# c}"),
+                Token::Tag("{!r{1}}"),
+                Token::OpenBlock("{title:"),
+                Token::Text("t"),
+                Token::CloseBlock,
+                Token::OpenSection("0,1"),
+            ]
+        );
+
+        let spans: Vec<Span> = tokens(template).map(|(_, span)| span).collect();
+        assert_eq!(
+            spans[10],
+            Span {
+                start: 31,
+                end: 36,
+                line: 2,
+                column: 1,
+            }
+        );
+        assert_eq!(spans.last().map(|span| span.end), Some(template.len()));
+    }
+
+    #[test]
+    fn template_info() {
+        assert_eq!(
+            validate("{pragma:v2}{default:7,0}{33,236:a {?X}{#ff8800,:b}{/}} {33,:{1,2:c}}"),
+            Ok(TemplateInfo {
+                version: 2,
+                sections: 4,
+                colors: vec![
+                    "7".to_string(),
+                    "0".to_string(),
+                    "33".to_string(),
+                    "236".to_string(),
+                    "#ff8800".to_string(),
+                    "1".to_string(),
+                    "2".to_string(),
+                ],
+                depth: 2,
+            })
+        );
+        assert_eq!(validate("text").map(|info| info.depth), Ok(0));
+        assert_eq!(validate("{1,x:a} {0,1:b").map_err(|e| e.len()), Err(2));
+    }
+
+    #[test]
+    fn lenient_generation() {
+        let generator = Generator::new();
+
+        let (output, warnings) = generator.generate_lenient("{1,x:a} {hr:ab} b}", Shell::None);
+        assert_eq!(output, "a {hr:ab} b}");
+        assert_eq!(
+            warnings.iter().map(|e| e.kind.clone()).collect::<Vec<_>>(),
+            vec![
+                ErrorKind::InvalidColor {
+                    field: "bg",
+                    reason: "unknown color name 'x'".to_string(),
+                },
+                ErrorKind::InvalidHr,
+                ErrorKind::UnbalancedBraces,
+            ]
+        );
+
+        let (output, warnings) = generator.generate_lenient("{>x} {0,1:a}", Shell::Bash);
+        assert_eq!(output, "{>x} {0,1:a}");
+        assert_eq!(warnings.len(), 1);
+
+        assert_eq!(
+            generator.generate_lenient("{0,1:a}", Shell::None),
+            (
+                generator.generate("{0,1:a}", Shell::None).unwrap(),
+                Vec::new()
+            )
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let template = "{0,1:ok}\n{0,wat:x}";
+        let error = generate(template, Shell::None).unwrap_err();
+
+        assert_eq!(
+            error.diagnostic(template),
+            "Error: invalid bg, unknown color name 'wat'.\n --> 2:1\n  |\n2 | {0,wat:x}\n  | ^^^^^^^"
+        );
+        assert_eq!(
+            Error::from(ErrorKind::InvalidHr).diagnostic(template),
+            "Error: invalid hr, should be a single character."
+        );
+    }
+
+    #[test]
+    fn error_messages() {
+        assert_eq!(
+            ErrorKind::UnbalancedBraces.to_string(),
+            "Error: unbalanced braces."
+        );
+        assert_eq!(
+            generate("{1,x:a}", Shell::None).map_err(|e| e.to_string()),
+            Err("Error: invalid bg, unknown color name 'x'.".to_string())
+        );
+        assert_eq!(
+            generate("{?a b}a{/}", Shell::None).map_err(|e| e.to_string()),
+            Err(
+                "Error: invalid conditional, should be '{?NAME}' or '{?NAME ? then : else}'."
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn else_branch() {
+        assert_eq!(
+            generate("{?BUBBLEPROMPT_TEST_UNSET}x{|}y{/}", Shell::None),
+            Ok("y".to_string())
+        );
+    }
+
+    #[test]
+    fn status_zsh() {
+        assert_eq!(
+            generate("{0,1:{??}{2,:ok.}{|}(x){/}}", Shell::Zsh),
+            Ok("%{\x1b[38;5;1m%}%{\x1b[38;5;0m%}%{\x1b[48;5;1m%}%(?|%{\x1b[38;5;2m%}%{\x1b[48;5;1m%}ok.%{\x1b[0m%}%{\x1b[48;5;1m%}%{\x1b[38;5;0m%}|(x%))%{\x1b[0m%}%{\x1b[38;5;1m%}%{\x1b[0m%}".to_string())
+        );
+    }
+
+    #[test]
+    fn status_bash() {
+        assert_eq!(
+            generate("{??}ok{|}it's bad{/}", Shell::Bash),
+            Ok(
+                "$(if [ $? = 0 ]; then printf %s 'ok'; else printf %s 'it'\\''s bad'; fi)"
+                    .to_string()
+            )
+        );
+
+        // Text in the branches is only decoded as the prompt, not expanded
+        assert_eq!(
+            generate("{??}a$b\\\\c`x`{|}n{/} a$b\\\\c`x`", Shell::Bash),
+            Ok(
+                "$(if [ $? = 0 ]; then printf %s 'a$b\\\\c`x`'; else printf %s 'n'; fi) a\\\\$b\\\\\\\\c\\\\`x\\\\`"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn status_fish() {
+        assert_eq!(
+            generate("{??}ok{|}it's bad{/} {!(prompt_pwd)}", Shell::Fish),
+            Ok("set -l bubble_status $status; printf '%s' ''(if test $bubble_status = 0; printf '%s' 'ok'; else; printf '%s' 'it\\'s bad'; end)' '(prompt_pwd)''".to_string())
+        );
+    }
+
+    #[test]
+    fn status_powershell() {
+        assert_eq!(
+            generate("{??}ok{|}$no{/} {!$PWD} {time:%H}", Shell::PowerShell),
+            Ok("$bubble_status = $?; \"$(if ($bubble_status) { \"ok\" } else { \"`$no\" }) $PWD $(Get-Date -UFormat '%H')\"".to_string())
+        );
+    }
+
+    #[test]
+    fn status_nu() {
+        assert_eq!(
+            generate("{??}ok{|}no{/} {!($env.PWD)} {time:%H}", Shell::Nu),
+            Ok("$\"(if $env.LAST_EXIT_CODE == 0 { $\"ok\" } else { $\"no\" }) ($env.PWD) (date now | format date \"%H\")\"".to_string())
+        );
+    }
+
+    #[test]
+    fn status_without_shell() {
+        assert_eq!(
+            generate("{??}ok{|}bad{/}", Shell::None),
+            Ok("ok".to_string())
+        );
+    }
+
+    #[test]
+    fn unexpected_else() {
+        assert_eq!(
+            kind(generate("x{|}y", Shell::None)),
+            Err(ErrorKind::Unexpected("{|}".to_string()))
+        );
+    }
+
+    #[test]
+    fn ternary() {
+        env::set_var("BUBBLEPROMPT_TEST_TERNARY", "1");
+
+        assert_eq!(
+            generate(
+                "{?BUBBLEPROMPT_TEST_TERNARY ? {1,0:a:b}\\: : c}{?BUBBLEPROMPT_TEST_UNSET ?  x :  y }",
+                Shell::None
+            ),
+            generate("{1,0:a:b}:y", Shell::None)
+        );
+    }
+
+    #[test]
+    fn ternary_status() {
+        assert_eq!(
+            generate("{?? ? ok : bad}", Shell::Zsh),
+            Ok("%(?.ok.bad)".to_string())
+        );
+    }
+
+    #[test]
+    fn ternary_missing_else() {
+        assert_eq!(
+            kind(generate("{?HOME ? x}", Shell::None)),
+            Err(ErrorKind::MissingColon)
+        );
+    }
+
+    #[test]
+    fn raw_text() {
+        assert_eq!(
+            generate("{!\\w ${PWD}}{!}", Shell::Bash),
+            Ok("\\w ${PWD}".to_string())
+        );
+    }
+
+    #[test]
+    fn unterminated_raw_text() {
+        assert_eq!(
+            kind(generate("{!${PWD}", Shell::Bash)),
+            Err(ErrorKind::UnterminatedRaw)
+        );
+    }
+
+    #[test]
+    fn unicode_escapes() {
+        assert_eq!(
+            Generator::new()
+                .shell_escapes(true)
+                .generate("\\u{E0B0} \\u{1f680} \\u@\\h", Shell::Bash),
+            Ok("\u{E0B0} \u{1f680} \\u@\\h".to_string())
+        );
+    }
+
+    #[test]
+    fn malformed_unicode_escape() {
+        assert_eq!(
+            kind(generate("@fg=1 {@fg,0:ab\\u{D800}}", Shell::None)),
+            Err(ErrorKind::MalformedUnicodeEscape(16))
+        );
+        assert_eq!(
+            kind(generate("\\u{1234567}", Shell::None)),
+            Err(ErrorKind::MalformedUnicodeEscape(1))
+        );
+        assert_eq!(
+            kind(generate("\\u{E0B0", Shell::None)),
+            Err(ErrorKind::MalformedUnicodeEscape(1))
+        );
+    }
+
+    #[test]
+    fn separators() {
+        assert_eq!(
+            generate("{0,1,sep=arrow:x}{0,2,sep=none:y}{0,3,sep=<>:z}", Shell::None),
+            Ok("\x1b[38;5;1m\u{E0B2}\x1b[38;5;0m\x1b[48;5;1mx\x1b[0m\x1b[38;5;1m\u{E0B0}\x1b[0m\x1b[38;5;0m\x1b[48;5;2my\x1b[0m\x1b[0m\x1b[38;5;3m<\x1b[38;5;0m\x1b[48;5;3mz\x1b[0m\x1b[38;5;3m>\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn no_separator_flag() {
+        assert_eq!(
+            generate("{0,1,bold,nosep:x}", Shell::None),
+            generate("{0,1,bold,sep=none:x}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!(
+            generate("{0,1,pad=1:x{2,3,pad=2:y}}", Shell::None).unwrap(),
+            generate("{0,1: x{2,3:  y  } }", Shell::None).unwrap()
+        );
+    }
+
+    #[test]
+    fn max_width() {
+        assert_eq!(
+            generate("{0,1,max=5:abc{2,3:def}ghi}{0,1,max=3:abc}", Shell::None),
+            generate("{0,1:abc{2,3:d}\u{2026}}{0,1:abc}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn max_width_with_ellipsis() {
+        assert_eq!(
+            generate("{0,1,max=6,ellipsis=...:~/projects}", Shell::None),
+            generate("{0,1:~/p...}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn max_width_wide_characters() {
+        assert_eq!(
+            generate("{0,1,max=4,ellipsis=:日本語}", Shell::None),
+            generate("{0,1:日本}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn min_width() {
+        assert_eq!(
+            generate(
+                "{0,1,min=4:ab}{0,1,min=4,align=right:ab}{0,1,min=5,align=center:ab}",
+                Shell::None
+            ),
+            generate("{0,1:ab  }{0,1:  ab}{0,1: ab  }", Shell::None)
+        );
+    }
+
+    #[test]
+    fn fixed_width() {
+        assert_eq!(
+            generate("{0,1,width=4:abcdef}{0,1,width=4:a{2,3:b}}", Shell::None),
+            generate("{0,1:abc\u{2026}}{0,1:a{2,3:b}  }", Shell::None)
+        );
+    }
+
+    #[test]
+    fn nested_width() {
+        assert_eq!(
+            generate("{0,1,min=6:x{2,3,width=2:abc}}", Shell::None),
+            generate("{0,1:x{2,3:a\u{2026}}   }", Shell::None)
+        );
+    }
+
+    #[test]
+    fn bad_align() {
+        assert_eq!(
+            kind(generate("{0,1,min=4,align=top:ab}", Shell::None)),
+            Err(ErrorKind::InvalidAlign("top".to_string()))
+        );
+    }
+
+    #[test]
+    fn fill_zsh() {
+        assert_eq!(
+            generate("{0,1:ab{fill}}{fill}x", Shell::Zsh),
+            Ok("%{\x1b[38;5;1m%}%{\x1b[38;5;0m%}%{\x1b[48;5;1m%}ab${(l:(COLUMNS-5)/2:: :)}%{\x1b[0m%}%{\x1b[38;5;1m%}%{\x1b[0m%}${(l:(COLUMNS-5)/2:: :)}x".to_string())
+        );
+    }
+
+    #[test]
+    fn fill_bash() {
+        assert_eq!(
+            generate("{fill}{!\\w}", Shell::Bash),
+            Ok("$(printf '%*s' $(((COLUMNS-0)/1)) '')\\w".to_string())
+        );
+    }
+
+    #[test]
+    fn fill_fish() {
+        assert_eq!(
+            generate("{hr:'}x {time:%H}", Shell::Fish),
+            Ok("printf '%s' ''(string repeat -n (math \"max(0, floor(($COLUMNS - 4) / 1))\") '\\'')'x '(date '+%H')''".to_string())
+        );
+    }
+
+    #[test]
+    fn fill_powershell() {
+        assert_eq!(
+            generate("x{fill}", Shell::PowerShell),
+            Ok("\"x$(' ' * [math]::Max(0, [math]::Floor(($Host.UI.RawUI.WindowSize.Width - 1) / 1)))\"".to_string())
+        );
+    }
+
+    #[test]
+    fn fill_nu() {
+        assert_eq!(
+            generate("x{hr:\"}", Shell::Nu),
+            Ok("$\"x(\"\" | fill -c \"\\\"\" -w ([0 (((term size).columns - 1) / 1 | math floor)] | math max))\"".to_string())
+        );
+    }
+
+    #[test]
+    fn bad_padding() {
+        assert_eq!(
+            kind(generate("{0,1,pad=-1:x}", Shell::None)),
+            Err(ErrorKind::InvalidNumber {
+                option: "pad".to_string(),
+                reason: "invalid digit found in string".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn bad_separator() {
+        assert_eq!(
+            kind(generate("{0,1,sep=wavy:x}", Shell::None)),
+            Err(ErrorKind::InvalidSeparator("wavy".to_string()))
+        );
+        assert_eq!(
+            kind(generate("{0,1,gap=1:x}", Shell::None)),
+            Err(ErrorKind::UnknownOption("gap".to_string()))
+        );
+    }
+
+    #[test]
+    fn gradient() {
+        assert_eq!(
+            generate("{#ff0000..#0000ff,0:a b{1,0:x}c}", Shell::None),
+            Ok("\x1b[38;5;0m\x1b[38;2;255;0;0m\x1b[48;5;0m\x1b[38;2;255;0;0ma \x1b[38;2;128;0;128mb\x1b[38;5;1m\x1b[48;5;0mx\x1b[0m\x1b[48;5;0m\x1b[38;2;255;0;0m\x1b[38;2;0;0;255mc\x1b[0m\x1b[38;5;0m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn gradient_from_indexed_colors() {
+        assert_eq!(
+            generate("{9..21,0:ab}", Shell::None),
+            generate("{#ff0000..#0000ff,0:ab}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn rainbow() {
+        assert_eq!(
+            generate("{1,0,rainbow,nosep:a e\u{301}iou}", Shell::None),
+            Ok("\x1b[38;5;1m\x1b[48;5;0m\x1b[38;5;196ma \x1b[38;5;208me\u{301}\x1b[38;5;226mi\x1b[38;5;46mo\x1b[38;5;21mu\x1b[0m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn icons() {
+        assert_eq!(
+            generate("{icon:arch} {icon: folder }{icon:git}", Shell::None),
+            Ok("\u{F303} \u{F07B}\u{E702}".to_string())
+        );
+        assert_eq!(
+            kind(generate("{icon:nope}", Shell::None)),
+            Err(ErrorKind::UnknownIcon("nope".to_string()))
+        );
+    }
+
+    #[test]
+    fn icons_are_sorted() {
+        assert!(ICONS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn emoji() {
+        let mut generator = Generator::new();
+        generator.emoji(true);
+
+        assert_eq!(
+            generator.generate("{1,0::rocket:} 10:30 :nope: :+1::x:", Shell::None),
+            Ok("\x1b[38;5;0m\x1b[38;5;1m\x1b[48;5;0m🚀\x1b[0m\x1b[38;5;0m\x1b[0m 10:30 :nope: 👍❌".to_string())
+        );
+        assert_eq!(
+            generate(":rocket:", Shell::None),
+            Ok(":rocket:".to_string())
+        );
+    }
+
+    #[test]
+    fn emoji_are_sorted() {
+        assert!(EMOJI.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            generate("{link:https://example.com|site} {link:https://example.com}", Shell::None),
+            Ok("\x1b]8;;https://example.com\x07site\x1b]8;;\x07 \x1b]8;;https://example.com\x07https://example.com\x1b]8;;\x07".to_string())
+        );
+        assert_eq!(
+            generate("{link:a|b}", Shell::Zsh),
+            Ok("%{\x1b]8;;a\x07%}b%{\x1b]8;;\x07%}".to_string())
+        );
+        assert_eq!(
+            generate("{link:a|b}", Shell::Bash),
+            Ok("\\[\x1b]8;;a\x07\\]b\\[\x1b]8;;\x07\\]".to_string())
+        );
+    }
+
+    #[test]
+    fn link_with_section() {
+        assert_eq!(
+            generate("{1,0,nosep:{link:a|x{2,0,nosep:y}}}", Shell::None),
+            Ok("\x1b[38;5;1m\x1b[48;5;0m\x1b]8;;a\x07x\x1b[38;5;2m\x1b[48;5;0my\x1b]8;;\x07\x1b[0m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn close_restores_full_style() {
+        assert_eq!(
+            generate("{1,0,bold,nosep:a{2,0,underline,nosep:b}c}", Shell::None),
+            Ok("\x1b[38;5;1m\x1b[48;5;0m\x1b[1ma\x1b[38;5;2m\x1b[48;5;0m\x1b[22m\x1b[4mb\x1b[0m\x1b[48;5;0m\x1b[1m\x1b[38;5;1mc\x1b[0m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn close_restores_foreground_after_coloring() {
+        assert_eq!(
+            generate("{1,0,nosep:a{1,0,rainbow,nosep:bc}d}", Shell::None),
+            Ok("\x1b[38;5;1m\x1b[48;5;0ma\x1b[38;5;196mb\x1b[38;5;208mc\x1b[38;5;1md\x1b[0m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn lighten_and_darken() {
+        assert_eq!(
+            generate("{lighten(#336699, 20%),darken(rgb(51, 102, 153),20):x}", Shell::None),
+            Ok("\x1b[38;2;26;51;77m\x1b[38;2;102;153;204m\x1b[48;2;26;51;77mx\x1b[0m\x1b[38;2;26;51;77m\x1b[0m".to_string())
+        );
+        assert_eq!(
+            generate(
+                "@base=#336699\n{darken(lighten(@base, 20%), 20%),0:x}",
+                Shell::None
+            ),
+            generate("{#336699,0:x}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn mix() {
+        assert_eq!(
+            generate(
+                "{mix(#ff0000, #0000ff),mix(red, rgb(0, 0, 255), 75%),nosep:x}",
+                Shell::None
+            ),
+            generate("{#800080,#600040,nosep:x}", Shell::None)
+        );
+        assert_eq!(
+            kind(generate("{mix(#ff0000, default),0:x}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "fg",
+                reason: "can't mix the default color".to_string(),
+            })
+        );
+        assert_eq!(
+            kind(generate("{mix(#ff0000),0:x}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "fg",
+                reason: "mix should have two colors and an optional weight".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn bad_lightness_change() {
+        assert_eq!(
+            kind(generate("{lighten(#336699),0:x}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "fg",
+                reason: "missing amount in '#336699'".to_string(),
+            })
+        );
+        assert_eq!(
+            kind(generate("{lighten(#336699, 120%),0:x}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "fg",
+                reason: "invalid amount '120%'".to_string(),
+            })
+        );
+        assert_eq!(
+            kind(generate("{,darken(default, 10%):x}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "bg",
+                reason: "can't change lightness of the default color".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn auto_foreground() {
+        assert_eq!(
+            generate("{auto,#ffff00,nosep:x}{auto,#000080,nosep:y}", Shell::None),
+            generate("{0,#ffff00,nosep:x}{15,#000080,nosep:y}", Shell::None)
+        );
+        assert_eq!(
+            generate(
+                "{auto(#eeeeee, rgb(0, 0, 64)),11,nosep:x}{auto,,nosep:y}",
+                Shell::None
+            ),
+            generate("{rgb(0, 0, 64),11,nosep:x}{default,,nosep:y}", Shell::None)
+        );
+    }
+
+    #[test]
+    fn bad_auto_foreground() {
+        assert_eq!(
+            kind(generate("{auto(1),0:x}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "fg",
+                reason: "auto should have two colors, got '1'".to_string(),
+            })
+        );
+        assert_eq!(
+            kind(generate("{auto(1, default),0:x}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "fg",
+                reason: "auto can't choose the default color".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn hash_colors() {
+        env::set_var("BUBBLEPROMPT_TEST_HASH", "example.com");
 
-#[derive(Copy, Clone)]
-pub enum Shell {
-    None,
-    Zsh,
-    Bash,
-}
+        assert_eq!(
+            generate(
+                "{hash($BUBBLEPROMPT_TEST_HASH),hash(user),nosep:x}",
+                Shell::None
+            ),
+            generate("{hash(example.com),hash(user),nosep:x}", Shell::None)
+        );
+        assert_eq!(
+            generate("{hash(example.com),hash(user),nosep:x}", Shell::None),
+            Ok("\x1b[38;5;106m\x1b[48;5;39mx\x1b[0m\x1b[0m".to_string())
+        );
 
-const OPEN_BRACE: char = '{';
-const CLOSE_BRACE: char = '}';
+        // Given values are looked up before the environment
+        let template = "{hash($BUBBLEPROMPT_TEST_HASH),0,nosep:x}";
+        let mut content = HashMap::new();
+        content.insert("BUBBLEPROMPT_TEST_HASH", "user".to_string());
 
-pub fn generate(template: &str, shell: Shell) -> Result<String, String> {
-    let mut buffer = String::new();
+        assert_eq!(
+            Generator::new().generate_with(template, Shell::None, &content),
+            generate("{hash(user),0,nosep:x}", Shell::None)
+        );
 
-    let mut styles: Vec<Style> = Vec::new();
-    let mut active_style: Option<Style> = None;
-    let mut last_brace: Option<char> = None;
-
-    let mut chars = template.chars();
-
-    while let Some(next) = chars.next() {
-        match last_brace {
-            Some(brace) if brace != next => {
-                push_brace(
-                    &mut buffer,
-                    brace,
-                    active_style.as_ref(),
-                    styles.last(),
-                    shell,
-                );
-                active_style = styles.last().copied();
-            }
-            _ => (),
-        }
+        let compiled = compile(template).unwrap();
+        assert_eq!(
+            compiled.render(Shell::None, &content),
+            generate("{hash(user),0,nosep:x}", Shell::None)
+        );
+        assert_eq!(
+            compiled.render(Shell::None, &HashMap::new()),
+            generate("{hash(example.com),0,nosep:x}", Shell::None)
+        );
 
-        last_brace = match next {
-            OPEN_BRACE => {
-                styles.push(parse_style(&mut chars)?);
-                Some(OPEN_BRACE)
-            }
-            CLOSE_BRACE => {
-                styles.pop();
-                Some(CLOSE_BRACE)
-            }
-            _ => {
-                buffer.push(next);
-                None
-            }
-        };
+        let unset = Err(ErrorKind::InvalidColor {
+            field: "fg",
+            reason: "unset variable '$BUBBLEPROMPT_TEST_UNSET'".to_string(),
+        });
+        let template = "{hash($BUBBLEPROMPT_TEST_UNSET),0:x}";
+
+        assert_eq!(kind(generate(template, Shell::None)), unset);
+        assert_eq!(
+            kind(
+                compile(template)
+                    .unwrap()
+                    .render(Shell::None, &HashMap::new())
+            ),
+            unset
+        );
+        assert_eq!(check(template), Ok(()));
     }
 
-    if !styles.is_empty() {
-        return Err("Error: unbalanced braces.".to_string());
+    #[test]
+    fn multiple_lines() {
+        assert_eq!(
+            generate("{1,2,bold,nosep:a\\nb}\n$", Shell::None),
+            Ok("\x1b[38;5;1m\x1b[48;5;2m\x1b[1ma\x1b[0m\n\x1b[38;5;1m\x1b[48;5;2m\x1b[1mb\x1b[0m\x1b[0m\n$".to_string())
+        );
     }
 
-    if let Some(last_brace) = last_brace {
-        push_brace(
-            &mut buffer,
-            last_brace,
-            active_style.as_ref(),
-            styles.last(),
-            shell,
+    #[test]
+    fn fill_on_each_line() {
+        assert_eq!(
+            generate("a{fill}b\\nccc{fill}", Shell::Zsh),
+            Ok("a${(l:(COLUMNS-2)/1:: :)}b\nccc${(l:(COLUMNS-3)/1:: :)}".to_string())
         );
     }
 
-    Ok(buffer)
-}
+    #[test]
+    fn horizontal_rule() {
+        assert_eq!(
+            generate("{hr:\u{2500}}\\n{1,0,nosep:{hr:=}}", Shell::Zsh),
+            Ok("${(l:(COLUMNS-0)/1::\u{2500}:)}\n%{\x1b[38;5;1m%}%{\x1b[48;5;0m%}${(l:(COLUMNS-0)/1::=:)}%{\x1b[0m%}%{\x1b[0m%}".to_string())
+        );
+        assert_eq!(
+            generate("{hr:\\:}", Shell::Zsh),
+            Ok("${(pl:(COLUMNS-0)/1::\\x3a:)}".to_string())
+        );
+        assert_eq!(
+            generate("{hr:$}{hr:%}", Shell::Zsh),
+            Ok("${(pl:(COLUMNS-0)/2::\\x24:)}${(pl:2*((COLUMNS-0)/2)::\\x25:)}".to_string())
+        );
+        assert_eq!(
+            generate("a{hr:-}", Shell::Bash),
+            Ok("a$(s=$(printf '%*s' $(((COLUMNS-1)/1)) ''); printf %s \"${s// /-}\")".to_string())
+        );
+        assert_eq!(
+            generate("{hr:\\}}{hr:\\\\}", Shell::Bash),
+            Ok(
+                "$(c='}'; s=$(printf '%*s' $(((COLUMNS-0)/2)) ''); printf %s \"${s// /\"$c\"}\")\
+                $(c='\\\\'; s=$(printf '%*s' $(((COLUMNS-0)/2)) ''); printf %s \"${s// /\"$c\"}\")"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            generate("{hr:!}", Shell::Ksh),
+            Ok("\x01\r$(c='!!'; s=$(printf '%*s' $(((COLUMNS-0)/1)) ''); printf %s \"${s// /\"$c\"}\")".to_string())
+        );
+    }
 
-fn parse_style(chars: &mut Chars) -> Result<Style, String> {
-    let mut buffer = String::new();
+    #[test]
+    fn bad_horizontal_rule() {
+        assert_eq!(
+            kind(generate("{hr:ab}", Shell::None)),
+            Err(ErrorKind::InvalidHr)
+        );
+        assert_eq!(
+            kind(generate("{hr:}", Shell::None)),
+            Err(ErrorKind::InvalidHr)
+        );
+        assert_eq!(
+            kind(generate("{hr:\\u{0}}", Shell::None)),
+            Err(ErrorKind::InvalidHr)
+        );
+    }
 
-    let meta: Vec<&str> = {
-        for c in chars.take_while(|c| *c != ':') {
-            buffer.push(c);
-        }
-        buffer.split(',').collect()
-    };
+    #[test]
+    fn nul_is_not_a_fill() {
+        let mut content = HashMap::new();
+        content.insert("x", "c\0d".to_string());
 
-    if meta.len() != 2 {
-        return Err("Error: invalid style, should be 'fg,bg'.".to_string());
+        assert_eq!(
+            Generator::new().generate_with("a\\x00b{fill}{0,1@x:}", Shell::Zsh, &content),
+            generate("ab{fill}{0,1:cd}", Shell::Zsh)
+        );
     }
 
-    let fg: u8 = match meta[0].trim().parse::<u8>() {
-        Ok(fg) => fg,
-        Err(e) => return Err(format!("Error: invalid fg, {}.", e.to_string())),
-    };
+    #[test]
+    fn macros() {
+        assert_eq!(
+            generate("{def x}{1,0,nosep:a}{/def}{use x} {use x }", Shell::None),
+            Ok(
+                "\x1b[38;5;1m\x1b[48;5;0ma\x1b[0m\x1b[0m \x1b[38;5;1m\x1b[48;5;0ma\x1b[0m\x1b[0m"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            generate(
+                "{def a}x{/def}{def b}{use a}{use a}{/def}{use b}",
+                Shell::None
+            ),
+            Ok("xx".to_string())
+        );
 
-    let bg: u8 = match meta[1].trim().parse::<u8>() {
-        Ok(bg) => bg,
-        Err(e) => return Err(format!("Error: invalid bg, {}.", e.to_string())),
-    };
+        let mut generator = Generator::new();
+        generator.partial("defs", "{def x}y{/def}");
 
-    Ok(Style { fg, bg })
-}
+        assert_eq!(
+            generator.generate("{>defs}{use x}", Shell::None),
+            Ok("y".to_string())
+        );
+    }
 
-fn push_brace(
-    buffer: &mut String,
-    brace: char,
-    style: Option<&Style>,
-    next_style: Option<&Style>,
-    shell: Shell,
-) {
-    if brace == OPEN_BRACE {
-        if let Some(next_style) = next_style {
-            push_escape_code(buffer, Escape::Foreground(next_style.bg), shell);
-            buffer.push('');
-            push_escape_code(buffer, Escape::Foreground(next_style.fg), shell);
-            push_escape_code(buffer, Escape::Background(next_style.bg), shell);
-        }
-    } else if brace == CLOSE_BRACE {
-        let escape = match next_style {
-            Some(next_style) => Escape::Background(next_style.bg),
-            None => Escape::Reset,
-        };
+    #[test]
+    fn bad_macros() {
+        assert_eq!(
+            kind(generate("{use x}", Shell::None)),
+            Err(ErrorKind::UnknownMacro("x".to_string()))
+        );
+        assert_eq!(
+            kind(generate("{def x}y", Shell::None)),
+            Err(ErrorKind::UnterminatedMacro("x".to_string()))
+        );
+        assert_eq!(
+            kind(generate("{def x}{use x}{/def}{use x}", Shell::None)),
+            Err(ErrorKind::RecursiveMacro("x".to_string()))
+        );
+    }
 
-        push_escape_code(buffer, escape, shell);
+    #[test]
+    fn default_style() {
+        assert_eq!(
+            generate("{default:250,default}\n$ {1,0:x} {3,:y}", Shell::None),
+            Ok("\x1b[38;5;250m\x1b[49m$ \x1b[38;5;0m\x1b[38;5;1m\x1b[48;5;0mx\x1b[0m\x1b[49m\x1b[38;5;0m\x1b[38;5;250m \x1b[38;5;3m\x1b[49my\x1b[0m\x1b[0m".to_string())
+        );
+    }
 
-        if let Some(style) = style {
-            push_escape_code(buffer, Escape::Foreground(style.bg), shell);
-            buffer.push('');
-        }
+    #[test]
+    fn named_sections() {
+        let template = "{1,0,nosep@cwd:~}{2,0,bold@git:}{%s@host:h}";
+        let template = format!("%s=3,0,nosep {}", template);
 
-        let escape = match next_style {
-            Some(next_style) => Escape::Foreground(next_style.fg),
-            None => Escape::Reset,
-        };
+        let mut content = HashMap::new();
+        content.insert("cwd", "/tmp".to_string());
+        content.insert("host", "box".to_string());
 
-        push_escape_code(buffer, escape, shell);
+        assert_eq!(
+            Generator::new().generate_with(&template, Shell::None, &content),
+            generate(
+                "%s=3,0,nosep {1,0,nosep:/tmp}{2,0,bold:}{%s:box}",
+                Shell::None
+            )
+        );
+        assert_eq!(
+            generate(&template, Shell::None),
+            generate("%s=3,0,nosep {1,0,nosep:~}{2,0,bold:}{%s:h}", Shell::None)
+        );
     }
-}
 
-fn push_escape_code(buffer: &mut String, escape: Escape, shell: Shell) {
-    match shell {
-        Shell::Zsh => buffer.push_str("%{"),
-        Shell::Bash => buffer.push_str("\\["),
-        _ => (),
+    #[test]
+    fn placeholders() {
+        let mut content = HashMap::new();
+        content.insert("branch", "main".to_string());
+
+        assert_eq!(
+            Generator::new().generate_with(
+                "{1,0,nosep: {{branch}} {{ nope }}}",
+                Shell::None,
+                &content
+            ),
+            generate("{1,0,nosep: main }", Shell::None)
+        );
+        assert_eq!(
+            kind(generate("{{branch}", Shell::None)),
+            Err(ErrorKind::UnbalancedBraces)
+        );
     }
 
-    buffer.push_str("\x1b[");
+    #[test]
+    fn time() {
+        assert_eq!(
+            generate("{time:%H:%M}", Shell::Zsh),
+            Ok("%D{%H:%M}".to_string())
+        );
+        assert_eq!(
+            generate("{time:%H:%M}", Shell::Bash),
+            Ok("\\D{%H:%M}".to_string())
+        );
 
-    match escape {
-        Escape::Foreground(color) => {
-            buffer.push_str("38;5;");
-            buffer.push_str(&color.to_string())
-        }
-        Escape::Background(color) => {
-            buffer.push_str("48;5;");
-            buffer.push_str(&color.to_string())
-        }
-        Escape::Reset => buffer.push('0'),
-    };
+        let time = generate("{time:%H:%M}", Shell::None).unwrap();
+        assert!(time.len() == 5 && time.chars().nth(2) == Some(':'));
+    }
 
-    buffer.push('m');
+    #[test]
+    fn static_time() {
+        let mut generator = Generator::new();
+        generator.static_time(true);
 
-    match shell {
-        Shell::Zsh => buffer.push_str("%}"),
-        Shell::Bash => buffer.push_str("\\]"),
-        _ => (),
+        assert_eq!(
+            generator.generate("{time:at 100%%}", Shell::Zsh),
+            Ok("at 100%%".to_string())
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn optional_sections() {
+        let mut content = HashMap::new();
+        content.insert("branch", "main".to_string());
+
+        let template = "{1,0,optional: {{branch}} }{2,0,optional: {{dirty}} }{3,0,optional@cwd:}";
+
+        assert_eq!(
+            Generator::new().generate_with(template, Shell::None, &content),
+            generate("{1,0: main }", Shell::None)
+        );
+        assert_eq!(
+            generate("a{fill}{1,0,optional:{2,0: }}", Shell::Zsh),
+            Ok("a${(l:(COLUMNS-1)/1:: :)}".to_string())
+        );
+    }
 
     #[test]
-    fn one_section() {
+    fn variables_are_not_section_names() {
         assert_eq!(
-            generate("{0,1:xxx}", Shell::None),
-            Ok("\x1b[38;5;1m\x1b[38;5;0m\x1b[48;5;1mxxx\x1b[0m\x1b[38;5;1m\x1b[0m".to_string())
+            generate("@a=1 @b=2 {@a..@b,@b:x}", Shell::None),
+            generate("{1..2,2:x}", Shell::None)
         );
     }
 
     #[test]
-    fn one_section_zsh() {
+    fn roles() {
         assert_eq!(
-            generate("{0,1:xxx}", Shell::Zsh),
-            Ok("%{\x1b[38;5;1m%}%{\x1b[38;5;0m%}%{\x1b[48;5;1m%}xxx%{\x1b[0m%}%{\x1b[38;5;1m%}%{\x1b[0m%}".to_string())
+            generate("{error:x}{ok:y}", Shell::None),
+            generate("{15,1:x}{0,2:y}", Shell::None)
+        );
+        assert_eq!(
+            generate("%error=0,9,bold {error:x}", Shell::None),
+            generate("{0,9,bold:x}", Shell::None)
         );
     }
 
     #[test]
-    fn one_section_bash() {
+    fn quoted_meta() {
         assert_eq!(
-            generate("{0,1:xxx}", Shell::Bash),
-            Ok("\\[\x1b[38;5;1m\\]\\[\x1b[38;5;0m\\]\\[\x1b[48;5;1m\\]xxx\\[\x1b[0m\\]\\[\x1b[38;5;1m\\]\\[\x1b[0m\\]".to_string())
+            generate(
+                "{\"#ff0000\",\"rgb(0,0,0)\",ellipsis=\":,\",max=2:abc}",
+                Shell::None
+            ),
+            generate(
+                "{#ff0000,rgb(0\\,0\\,0),ellipsis=\\:\\,,max=2:abc}",
+                Shell::None
+            )
         );
     }
 
     #[test]
-    fn sequential_sections() {
+    fn control_escapes() {
         assert_eq!(
-            generate("{0, 1:xxx} {100,200:yyy}", Shell::None),
-            Ok("\x1b[38;5;1m\x1b[38;5;0m\x1b[48;5;1mxxx\x1b[0m\x1b[38;5;1m\x1b[0m \x1b[38;5;200m\x1b[38;5;100m\x1b[48;5;200myyy\x1b[0m\x1b[38;5;200m\x1b[0m".to_string())
+            generate("\\e[5m\\a\\x1b\\x09\\t", Shell::None),
+            Ok("\x1b[5m\x07\x1b\t\\t".to_string())
+        );
+        assert_eq!(
+            kind(generate("ab\\x1", Shell::None)),
+            Err(ErrorKind::MalformedHexEscape(3))
+        );
+        assert_eq!(
+            kind(generate("a\\x7f\\x9b", Shell::None)),
+            Err(ErrorKind::MalformedHexEscape(6))
+        );
+        assert_eq!(
+            kind(generate("\\xff", Shell::None)),
+            Err(ErrorKind::MalformedHexEscape(1))
         );
     }
 
     #[test]
-    fn overlap_left() {
+    fn caps() {
         assert_eq!(
-            generate("{0,1:xxx {100,200:yyy}}", Shell::None),
-            Ok("\x1b[38;5;1m\x1b[38;5;0m\x1b[48;5;1mxxx \x1b[38;5;200m\x1b[38;5;100m\x1b[48;5;200myyy\x1b[0m\x1b[38;5;200m\x1b[0m".to_string())
+            generate("{caps:round}{1,2,sep=arrow:a{3,4,sep=arrow:b}c}{5,6,nosep:d}", Shell::None),
+            Ok("\x1b[38;5;2m\x1b[38;5;1m\x1b[48;5;2ma\x1b[38;5;4m\u{E0B2}\x1b[38;5;3m\x1b[48;5;4mb\x1b[0m\x1b[48;5;2m\x1b[38;5;4m\u{E0B0}\x1b[38;5;1mc\x1b[0m\x1b[38;5;2m\x1b[0m\x1b[38;5;5m\x1b[48;5;6md\x1b[0m\x1b[0m".to_string())
+        );
+
+        let template = "{1,2,sep=arrow:a{3,4,sep=arrow:b}c}{5,6,nosep:d}";
+        let mut generator = Generator::new();
+        generator.caps("round").unwrap();
+
+        assert_eq!(
+            generator.generate(template, Shell::None),
+            generate(&format!("{{caps:round}}{}", template), Shell::None)
+        );
+        assert_eq!(
+            generator.generate(&format!("{{caps:<>}}{}", template), Shell::None),
+            generate(&format!("{{caps:<>}}{}", template), Shell::None)
+        );
+        assert_eq!(
+            generator.caps("x").err().map(|error| error.kind),
+            Some(ErrorKind::InvalidSeparator("x".to_string()))
         );
     }
 
     #[test]
-    fn overlap_right() {
+    fn generation_options() {
+        let template = "{1,2,sep=arrow:a{3,4,sep=arrow:b}c}$";
+        let mut generator = Generator::new();
+        generator.caps("round").unwrap().shell_escapes(true);
+
+        // Caps and escaping of the options win over the generator, caps of the template over both
+        let options = Options::new(Shell::Bash)
+            .caps("<>")
+            .unwrap()
+            .escaping(Escaping::default());
+
         assert_eq!(
-            generate("{0,1 :{100,200:yyy} xxx}", Shell::None),
-            Ok("\x1b[38;5;200m\x1b[38;5;100m\x1b[48;5;200myyy\x1b[48;5;1m\x1b[38;5;200m\x1b[38;5;0m xxx\x1b[0m\x1b[38;5;1m\x1b[0m".to_string())
+            generator.generate(template, options),
+            generate(&format!("{{caps:<>}}{}", template), Shell::Bash)
+        );
+        assert_eq!(
+            generator.generate(&format!("{{caps:()}}{}", template), options),
+            generate(&format!("{{caps:()}}{}", template), Shell::Bash)
+        );
+        assert_eq!(
+            generator.generate(template, Shell::Bash),
+            Generator::new()
+                .shell_escapes(true)
+                .generate(&format!("{{caps:round}}{}", template), Shell::Bash)
+        );
+        assert_eq!(
+            Generator::new().generate(
+                "{1,2:a}",
+                Options::new(Shell::Zsh).escaping(Escaping {
+                    native_colors: true,
+                    ..Escaping::default()
+                })
+            ),
+            Generator::new()
+                .native_colors(true)
+                .generate("{1,2:a}", Shell::Zsh)
+        );
+        assert_eq!(
+            Options::new(Shell::Zsh)
+                .caps("x")
+                .err()
+                .map(|error| error.kind),
+            Some(ErrorKind::InvalidSeparator("x".to_string()))
         );
     }
 
     #[test]
-    fn bad_fg() {
+    fn thin_divider() {
+        assert_eq!(
+            generate("{1,4,sep=arrow:a}{2,4,sep=arrow:b}", Shell::None),
+            Ok("\x1b[38;5;4m\u{E0B2}\x1b[38;5;1m\x1b[48;5;4ma\x1b[38;5;1m\u{E0B1}\x1b[38;5;2mb\x1b[0m\x1b[38;5;4m\u{E0B0}\x1b[0m".to_string())
+        );
         assert_eq!(
-            generate("{999,1:xxx}", Shell::None),
-            Err("Error: invalid fg, number too large to fit in target type.".to_string())
+            generate("{0,4,nosep:{1,4,sep=<>|:a}{2,4:b}}", Shell::None),
+            Ok("\x1b[38;5;4m<\x1b[38;5;1m\x1b[48;5;4ma\x1b[38;5;1m|\x1b[38;5;2mb\x1b[0m\x1b[38;5;4m\x1b[0m".to_string())
         );
     }
 
     #[test]
-    fn bad_bg() {
+    fn no_divider_between_backgrounds() {
+        assert_eq!(
+            generate("{1,4,sep=arrow:a}{2,5,sep=arrow:b}", Shell::None),
+            Ok("\x1b[38;5;4m\u{E0B2}\x1b[38;5;1m\x1b[48;5;4ma\x1b[0m\x1b[38;5;4m\u{E0B0}\x1b[0m\x1b[38;5;5m\u{E0B2}\x1b[38;5;2m\x1b[48;5;5mb\x1b[0m\x1b[38;5;5m\u{E0B0}\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn left_direction() {
+        assert_eq!(
+            generate("{1,4,sep=arrow,dir=left:a{2,5,sep=arrow:b}}", Shell::None),
+            Ok("\x1b[38;5;4m\u{E0B2}\x1b[38;5;1m\x1b[48;5;4ma\x1b[38;5;5m\u{E0B2}\x1b[38;5;2m\x1b[48;5;5mb\x1b[0m\x1b[38;5;5m\x1b[7m\u{E0B2}\x1b[27m\x1b[0m".to_string())
+        );
+
+        assert_eq!(
+            generate("{1,4,sep=arrow,dir=left:a{2,5:b}c}", Shell::None),
+            Ok("\x1b[38;5;4m\u{E0B2}\x1b[38;5;1m\x1b[48;5;4ma\x1b[38;5;5m\u{E0B6}\x1b[38;5;2m\x1b[48;5;5mb\x1b[0m\x1b[48;5;4m\x1b[38;5;4m\x1b[48;5;5m\u{E0B6}\x1b[48;5;4m\x1b[38;5;1mc\x1b[0m\x1b[38;5;4m\x1b[7m\u{E0B2}\x1b[27m\x1b[0m".to_string())
+        );
+
+        let mut generator = Generator::new();
+        generator.direction(Direction::Left);
+
+        assert_eq!(
+            generator.generate("{1,4,sep=arrow:a}{2,4,sep=arrow:b}", Shell::None),
+            Ok("\x1b[38;5;4m\u{E0B2}\x1b[38;5;1m\x1b[48;5;4ma\x1b[38;5;1m\u{E0B3}\x1b[38;5;2mb\x1b[0m\x1b[38;5;4m\x1b[7m\u{E0B2}\x1b[27m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn pragma() {
+        assert_eq!(template_version("{0,1:x}"), Ok(1));
+        assert_eq!(template_version("{pragma:v2} {0,1:x}"), Ok(2));
+        assert_eq!(
+            generate("{pragma:v2} @c=1 {0,@c:x}", Shell::None),
+            generate("@c=1 {0,@c:x}", Shell::None)
+        );
+        assert_eq!(
+            kind(generate("{pragma:v3}x", Shell::None)),
+            Err(ErrorKind::UnsupportedVersion("v3".to_string()))
+        );
+        assert_eq!(
+            kind(generate("{pragma:2}x", Shell::None)),
+            Err(ErrorKind::UnsupportedVersion("2".to_string()))
+        );
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let mut generator = Generator::new();
+        generator.max_depth(2);
+
+        assert!(generator.generate("{1,0:{2,0:x}}", Shell::None).is_ok());
+        assert_eq!(
+            kind(generator.generate("{1,0:{2,0:{3,0:x}}}", Shell::None)),
+            Err(ErrorKind::NestingTooDeep {
+                limit: 2,
+                position: 16,
+            })
+        );
+        assert_eq!(
+            kind(generate(&"{1,0:".repeat(100), Shell::None)),
+            Err(ErrorKind::NestingTooDeep {
+                limit: 64,
+                position: 326,
+            })
+        );
+    }
+
+    #[test]
+    fn include_nesting_limit() {
+        let mut generator = Generator::new();
+        generator
+            .max_depth(2)
+            .partial("a", "{>b}")
+            .partial("b", "{>c}")
+            .partial("c", "x");
+
+        assert_eq!(
+            kind(generator.generate("{>a}", Shell::None)),
+            Err(ErrorKind::IncludesTooDeep(2))
+        );
+        assert_eq!(generator.generate("{>b}", Shell::None), Ok("x".to_string()));
+    }
+
+    #[test]
+    fn bad_gradient() {
+        assert_eq!(
+            kind(generate("{default..1,0:ab}", Shell::None)),
+            Err(ErrorKind::InvalidColor {
+                field: "fg",
+                reason: "gradient can't use the default color".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(
+            generate("{0,1,bold,underline:xxx}", Shell::None),
+            Ok(
+                "\x1b[38;5;1m\x1b[38;5;0m\x1b[48;5;1m\x1b[1m\x1b[4mxxx\x1b[0m\x1b[38;5;1m\x1b[0m"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn nested_attributes() {
+        assert_eq!(
+            generate("{0,1,bold,dim:x{2,3,dim,italic:y}z}", Shell::None),
+            Ok("\x1b[38;5;1m\x1b[38;5;0m\x1b[48;5;1m\x1b[1m\x1b[2mx\x1b[38;5;3m\x1b[38;5;2m\x1b[48;5;3m\x1b[22m\x1b[2m\x1b[3my\x1b[0m\x1b[48;5;1m\x1b[1m\x1b[2m\x1b[38;5;3m\x1b[38;5;0mz\x1b[0m\x1b[38;5;1m\x1b[0m".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_attribute() {
         assert_eq!(
-            generate("{1,-9:xxx}", Shell::None),
-            Err("Error: invalid bg, invalid digit found in string.".to_string())
+            kind(generate("{0,1,blod:xxx}", Shell::None)),
+            Err(ErrorKind::UnknownAttribute("blod".to_string()))
         );
     }
 
     #[test]
     fn incomplete_meta() {
         assert_eq!(
-            generate("{1:xxx}", Shell::None),
-            Err("Error: invalid style, should be 'fg,bg'.".to_string())
+            kind(generate("{1:xxx}", Shell::None)),
+            Err(ErrorKind::MissingMeta)
         );
     }
 }
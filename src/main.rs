@@ -1,6 +1,8 @@
 use std::process;
 
-use bubbleprompt::Shell;
+use bubbleprompt::{
+    ColorMode, Direction, Error, Escaping, Generator, Options, Shell, EIGHT_COLOR_TABLE,
+};
 use clap::{App, Arg, ArgMatches};
 
 const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
@@ -21,33 +23,287 @@ fn parse_command_line<'a>() -> ArgMatches<'a> {
                 .value_name("NAME")
                 .help("If specified will wrap escape codes into non-printing characters specific for a shell")
                 .takes_value(true)
-                .possible_values(&["zsh", "bash"])
+                .possible_values(&[
+                    "auto",
+                    "zsh",
+                    "bash",
+                    "fish",
+                    "powershell",
+                    "nu",
+                    "tcsh",
+                    "ksh",
+                    "elvish",
+                    "xonsh",
+                    "oils",
+                    "tmux",
+                    "screen",
+                    "vim",
+                    "clink",
+                    "readline",
+                    "starship",
+                ])
+                .case_insensitive(true),
+        )
+        .arg(
+            Arg::with_name("partial")
+                .short("p")
+                .long("partial")
+                .value_name("NAME=FILE")
+                .help("Registers a partial template read from a file, can be included with {>NAME}")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("emoji")
+                .short("e")
+                .long("emoji")
+                .help("Expands :shortcode: emoji in the template text"),
+        )
+        .arg(
+            Arg::with_name("static-time")
+                .short("t")
+                .long("static-time")
+                .help("Formats {time:...} during generation instead of leaving it to the shell"),
+        )
+        .arg(
+            Arg::with_name("html")
+                .long("html")
+                .help("Renders the prompt as HTML for previews instead of generating it")
+                .conflicts_with("shell"),
+        )
+        .arg(
+            Arg::with_name("continuation")
+                .long("continuation")
+                .help("Generates a continuation prompt matching the template, like PS2")
+                .conflicts_with_all(&["html", "svg"]),
+        )
+        .arg(
+            Arg::with_name("right")
+                .long("right")
+                .help("Generates a prompt for the right side of the terminal, like RPROMPT")
+                .conflicts_with_all(&["html", "svg", "continuation"]),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Reports all errors in the template instead of generating the prompt")
+                .conflicts_with_all(&["html", "svg", "continuation", "right"]),
+        )
+        .arg(
+            Arg::with_name("lenient")
+                .long("lenient")
+                .help("Generates the prompt despite errors, which are printed as warnings")
+                .conflicts_with_all(&["html", "svg", "continuation", "right", "check"]),
+        )
+        .arg(
+            Arg::with_name("svg")
+                .long("svg")
+                .help("Renders the prompt as SVG image instead of generating it")
+                .conflicts_with_all(&["shell", "html"]),
+        )
+        .arg(
+            Arg::with_name("colors")
+                .long("colors")
+                .value_name("MODE")
+                .help("Colors supported by the terminal, colors are converted to the closest ones")
+                .takes_value(true)
+                .possible_values(&["truecolor", "256", "16", "8", "none", "auto"]),
+        )
+        .arg(
+            Arg::with_name("semantic-marks")
+                .long("semantic-marks")
+                .help("Wraps the prompt into OSC 133 marks for terminals that jump between prompts"),
+        )
+        .arg(
+            Arg::with_name("report-cwd")
+                .long("report-cwd")
+                .help("Reports the working directory to the terminal with OSC 7"),
+        )
+        .arg(
+            Arg::with_name("color-env")
+                .long("color-env")
+                .help("Honors NO_COLOR and CLICOLOR_FORCE environment variables"),
+        )
+        .arg(
+            Arg::with_name("color-table")
+                .long("color-table")
+                .value_name("LIST")
+                .help("Comma-separated 8 colors which the 16 basic colors are mapped to with '--colors 8'")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("plain")
+                .long("plain")
+                .help("Leaves out colors and separators, for dumb terminals and serial consoles"),
+        )
+        .arg(
+            Arg::with_name("ascii")
+                .long("ascii")
+                .help("Replaces Powerline glyphs of separators with ASCII characters"),
+        )
+        .arg(
+            Arg::with_name("shell-escapes")
+                .long("shell-escapes")
+                .help("Passes the text to the shell as is, so that escapes like \\w or %~ work in it"),
+        )
+        .arg(
+            Arg::with_name("native-colors")
+                .long("native-colors")
+                .help("Writes colors in Zsh output as %F{..} and %K{..} instead of escape codes"),
+        )
+        .arg(
+            Arg::with_name("legacy-escapes")
+                .long("legacy-escapes")
+                .help("Writes escapes in PowerShell output as $([char]27), for Windows PowerShell 5.1"),
+        )
+        .arg(
+            Arg::with_name("direction")
+                .short("d")
+                .long("direction")
+                .value_name("DIR")
+                .help("Direction the caps point to, 'left' suits prompts on the right side")
+                .takes_value(true)
+                .possible_values(&["right", "left"])
                 .case_insensitive(true),
         )
         .get_matches()
 }
 
 fn main() {
+    // Output that goes to a file or a pipe doesn't need it
+    #[cfg(all(windows, feature = "windows-vt"))]
+    let _ = bubbleprompt::enable_virtual_terminal();
+
     let matches = parse_command_line();
     let template = matches.value_of("template").unwrap();
 
-    let shell = matches
-        .value_of("shell")
-        .map(str::to_lowercase)
-        .map(|shell| match shell.as_ref() {
-            "zsh" => Shell::Zsh,
-            "bash" => Shell::Bash,
-            _ => Shell::None,
-        })
-        .unwrap_or(Shell::None);
-
-    match bubbleprompt::generate(&template, shell) {
+    let shell = match matches.value_of("shell") {
+        Some(shell) if shell.eq_ignore_ascii_case("auto") => Shell::detect(),
+        Some(shell) => shell
+            .parse()
+            .unwrap_or_else(|e: Error| exit_with_error(&e.to_string())),
+        None => Shell::None,
+    };
+
+    let color_mode = match matches.value_of("colors") {
+        Some("256") => ColorMode::Palette256,
+        Some("16") => ColorMode::Basic16,
+        Some("8") => ColorMode::Basic8,
+        Some("none") => ColorMode::None,
+        Some("auto") => ColorMode::Auto,
+        _ => ColorMode::TrueColor,
+    };
+
+    let mut generator = Generator::new();
+    generator
+        .emoji(matches.is_present("emoji"))
+        .static_time(matches.is_present("static-time"))
+        .color_table(color_table(matches.value_of("color-table")))
+        .semantic_marks(matches.is_present("semantic-marks"))
+        .report_cwd(matches.is_present("report-cwd"))
+        .plain(matches.is_present("plain"))
+        .ascii(matches.is_present("ascii"));
+
+    if let Some(direction) = matches.value_of("direction") {
+        generator.direction(match direction.to_lowercase().as_ref() {
+            "left" => Direction::Left,
+            _ => Direction::Right,
+        });
+    }
+
+    for partial in matches.values_of("partial").into_iter().flatten() {
+        let (name, path) = match partial.split_once('=') {
+            Some(partial) => partial,
+            None => exit_with_error(&format!(
+                "Error: invalid partial '{}', should be NAME=FILE.",
+                partial
+            )),
+        };
+
+        if let Err(e) = generator.partial_file(name, path) {
+            exit_with_error(&format!("Error: can't read partial '{}', {}.", name, e));
+        }
+    }
+
+    if matches.is_present("check") {
+        if let Err(errors) = generator.check(template) {
+            let diagnostics: Vec<String> = errors
+                .iter()
+                .map(|error| error.diagnostic(template))
+                .collect();
+
+            exit_with_error(&diagnostics.join("\n\n"));
+        }
+
+        return;
+    }
+
+    let options = Options::new(shell)
+        .color_mode(color_mode)
+        .color_env(matches.is_present("color-env"))
+        .escaping(Escaping {
+            shell_escapes: matches.is_present("shell-escapes"),
+            legacy_escapes: matches.is_present("legacy-escapes"),
+            native_colors: matches.is_present("native-colors"),
+        });
+
+    if matches.is_present("lenient") {
+        let (output, warnings) = generator.generate_lenient(template, options);
+
+        for warning in warnings {
+            eprintln!("{}\n", warning.diagnostic(template));
+        }
+
+        println!("{}", output);
+        return;
+    }
+
+    let result = if matches.is_present("html") {
+        generator.render_html(template)
+    } else if matches.is_present("svg") {
+        generator.render_svg(template)
+    } else if matches.is_present("right") {
+        generator.generate_right(template, options)
+    } else if matches.is_present("continuation") {
+        generator.generate_continuation(template, options)
+    } else {
+        generator.generate(template, options)
+    };
+
+    match result {
         Ok(result) => {
             println!("{}", result);
         }
-        Err(e) => {
-            eprintln!("{}", e);
-            process::exit(1);
-        }
+        Err(e) => exit_with_error(&e.diagnostic(template)),
     }
 }
+
+fn color_table(list: Option<&str>) -> [u8; 16] {
+    let list = match list {
+        Some(list) => list,
+        None => return EIGHT_COLOR_TABLE,
+    };
+
+    let colors: Vec<u8> = list
+        .split(',')
+        .filter_map(|color| color.trim().parse().ok())
+        .filter(|color| *color < 8)
+        .collect();
+
+    let mut table = EIGHT_COLOR_TABLE;
+    if colors.len() != table.len() || list.split(',').count() != table.len() {
+        exit_with_error(&format!(
+            "Error: invalid color table '{}', should be 16 comma-separated colors from 0 to 7.",
+            list
+        ));
+    }
+
+    table.copy_from_slice(&colors);
+    table
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}